@@ -0,0 +1,254 @@
+//! Resolving extents back to the trees and files using them, by walking the
+//! backrefs of the extent tree upwards like the kernel's backref code.
+//!
+//! Data refs either name the subvolume and inode (indirect refs) or the leaf
+//! holding the file extent items (shared refs). Either way the leaf can be
+//! shared by snapshots, so the trees using it are found by following the
+//! tree block refs of the leaf and of every node above it up to tree roots.
+
+use crate::ctree::{block_header, item_data, leaf_item, node_keyptr, TreeRoot};
+use crate::extent::{Extent, ExtentRef};
+use crate::file::parse_file_extent;
+use crate::fs::Fs;
+use crate::inode::InodeId;
+use crate::structs::*;
+use crate::tree_block_cache::TreeBlock;
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+
+/// A file using a data extent, and the file offset of the resolved address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileOffset {
+    pub id: InodeId,
+    pub offset: u64,
+}
+
+impl Fs {
+    /// Root of tree `id`, subvolume or not
+    pub fn tree_root(&self, id: u64) -> Result<TreeRoot> {
+        match id {
+            BTRFS_ROOT_TREE_OBJECTID => Ok(self.root_tree()),
+            BTRFS_CHUNK_TREE_OBJECTID => Ok(TreeRoot {
+                bytenr: self.superblock.chunk_root,
+                generation: self.superblock.chunk_root_generation,
+            }),
+            _ => match self.read_root_item(id)? {
+                Some(root_item) => Ok(TreeRoot::from(&root_item)),
+                None => bail!("Tree {} not found", id),
+            },
+        }
+    }
+
+    /// Read a tree block known only by its address, its extent item records
+    /// the generation it was written in
+    fn read_tree_block_at(&self, bytenr: u64) -> Result<(Extent, TreeBlock)> {
+        let extent = match self.lookup_tree_block(bytenr)? {
+            Some(extent) => extent,
+            None => bail!("No extent item for tree block {}", bytenr),
+        };
+        let block = self.read_tree_block(bytenr, extent.generation)?;
+        Ok((extent, block))
+    }
+
+    /// Every tree whose root reaches the tree block at `bytenr`
+    pub fn tree_block_roots(&self, bytenr: u64) -> Result<BTreeSet<u64>> {
+        self.cached_tree_block_roots(bytenr, &mut HashMap::new())
+    }
+
+    /// `tree_block_roots` remembering the roots of every block it goes
+    /// through in `cache`, keyed by block address. Blocks near the top of a
+    /// tree are above most others, sharing the cache between lookups saves
+    /// resolving them again.
+    pub fn cached_tree_block_roots(
+        &self,
+        bytenr: u64,
+        cache: &mut HashMap<u64, BTreeSet<u64>>,
+    ) -> Result<BTreeSet<u64>> {
+        if let Some(roots) = cache.get(&bytenr) {
+            return Ok(roots.clone());
+        }
+        // Stops loops through corrupted refs
+        cache.insert(bytenr, BTreeSet::new());
+
+        let mut roots = BTreeSet::new();
+        let (extent, block) = self.read_tree_block_at(bytenr)?;
+        let header = block_header(&block);
+        let level = header.level;
+        let first_key = match (header.nritems, level) {
+            (0, _) => BtrfsKey::new(0, 0, 0),
+            (_, 0) => leaf_item(&block, 0).key,
+            _ => node_keyptr(&block, 0).key,
+        };
+
+        for extent_ref in extent.all_refs() {
+            match *extent_ref {
+                // Indirect: find the node of the tree pointing at the block
+                ExtentRef::TreeBlock { root } => {
+                    let path = self.search_path(self.tree_root(root)?, &first_key, level)?;
+                    match path[..] {
+                        [.., parent, last] if last == bytenr => {
+                            roots.extend(self.cached_tree_block_roots(parent, cache)?)
+                        }
+                        [last] if last == bytenr => {
+                            roots.insert(root);
+                        }
+                        // The tree doesn't use the block anymore
+                        _ => {}
+                    }
+                }
+                ExtentRef::SharedBlock { parent } => {
+                    roots.extend(self.cached_tree_block_roots(parent, cache)?)
+                }
+                _ => {}
+            }
+        }
+        cache.insert(bytenr, roots.clone());
+        Ok(roots)
+    }
+
+    /// Every tree using an extent, through any of its refs. Data extents are
+    /// used by the trees reaching the leaves whose file extent items point
+    /// to them. Roots of tree blocks are kept in `cache`, as for
+    /// `cached_tree_block_roots`.
+    pub fn extent_roots(
+        &self,
+        extent: &Extent,
+        cache: &mut HashMap<u64, BTreeSet<u64>>,
+    ) -> Result<BTreeSet<u64>> {
+        if extent.is_tree_block() {
+            return self.cached_tree_block_roots(extent.bytenr, cache);
+        }
+
+        let mut roots = BTreeSet::new();
+        for extent_ref in extent.all_refs() {
+            let leaves = match *extent_ref {
+                ExtentRef::Data {
+                    root,
+                    objectid,
+                    offset,
+                    count,
+                } => self.data_ref_leaves(extent, root, objectid, offset, count)?,
+                ExtentRef::SharedData { parent, .. } => vec![parent],
+                _ => continue,
+            };
+            for leaf in leaves {
+                roots.extend(self.cached_tree_block_roots(leaf, cache)?);
+            }
+        }
+        Ok(roots)
+    }
+
+    /// Leaves of tree `root` holding the file extent items behind an
+    /// indirect data ref
+    fn data_ref_leaves(
+        &self,
+        extent: &Extent,
+        root: u64,
+        objectid: u64,
+        offset: u64,
+        count: u32,
+    ) -> Result<Vec<u64>> {
+        let tree = self.tree_root(root)?;
+        let mut keys = vec![];
+
+        // `offset` is the file offset the extent would start at, before the
+        // start of the file for items using only its end
+        let start = if offset >= i64::MAX as u64 { 0 } else { offset };
+        self.walk_tree(
+            tree,
+            &BtrfsKey::new(objectid, BTRFS_EXTENT_DATA_KEY, start),
+            &BtrfsKey::new(objectid, BTRFS_EXTENT_DATA_KEY, u64::MAX),
+            |key, data| {
+                let file_extent = parse_file_extent(key.offset, data)?;
+                if file_extent.disk_bytenr == extent.bytenr
+                    && key.offset.wrapping_sub(file_extent.offset) == offset
+                {
+                    keys.push(*key);
+                }
+                Ok(keys.len() < count as usize)
+            },
+        )?;
+
+        let mut leaves = vec![];
+        for key in keys {
+            if let Some(leaf) = self.search_path(tree, &key, 0)?.last() {
+                leaves.push(*leaf);
+            }
+        }
+        Ok(leaves)
+    }
+
+    /// Every file of every subvolume and snapshot using the data at logical
+    /// address `logical`, like `btrfs inspect-internal logical-resolve`.
+    /// Compressed extents can't be mapped byte for byte, their files are
+    /// reported at the start of the range using the extent.
+    pub fn logical_resolve(&self, logical: u64) -> Result<Vec<FileOffset>> {
+        let extent = match self.lookup_extent(logical)? {
+            Some(extent) => extent,
+            None => bail!("No extent holds logical address {}", logical),
+        };
+        if !extent.is_data() {
+            bail!(
+                "Logical address {} is in tree block {}, not in data",
+                logical,
+                extent.bytenr
+            );
+        }
+
+        let mut leaves = BTreeSet::new();
+        for extent_ref in extent.all_refs() {
+            match *extent_ref {
+                ExtentRef::Data {
+                    root,
+                    objectid,
+                    offset,
+                    count,
+                } => leaves.extend(self.data_ref_leaves(&extent, root, objectid, offset, count)?),
+                ExtentRef::SharedData { parent, .. } => {
+                    leaves.insert(parent);
+                }
+                _ => {}
+            }
+        }
+
+        let extent_pos = logical - extent.bytenr;
+        let mut found = BTreeSet::new();
+        let mut block_roots = HashMap::new();
+        for leaf in leaves {
+            let roots = self.cached_tree_block_roots(leaf, &mut block_roots)?;
+            let (_, block) = self.read_tree_block_at(leaf)?;
+
+            for slot in 0..block_header(&block).nritems as usize {
+                let item = leaf_item(&block, slot);
+                let key = item.key;
+                if key.ty != BTRFS_EXTENT_DATA_KEY {
+                    continue;
+                }
+                let file_extent = parse_file_extent(key.offset, item_data(&block, item)?)?;
+                if file_extent.ty == BTRFS_FILE_EXTENT_INLINE
+                    || file_extent.disk_bytenr != extent.bytenr
+                {
+                    continue;
+                }
+
+                let mut offset = key.offset;
+                if file_extent.compression == BTRFS_COMPRESS_NONE {
+                    // Files using another part of the extent don't see it
+                    if extent_pos < file_extent.offset
+                        || extent_pos - file_extent.offset >= file_extent.num_bytes
+                    {
+                        continue;
+                    }
+                    offset += extent_pos - file_extent.offset;
+                }
+                for root in &roots {
+                    found.insert(FileOffset {
+                        id: InodeId::new(*root, key.objectid),
+                        offset,
+                    });
+                }
+            }
+        }
+        Ok(found.into_iter().collect())
+    }
+}
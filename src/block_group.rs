@@ -0,0 +1,232 @@
+//! Block groups and the space accounting built on them.
+//!
+//! Every chunk has a `BLOCK_GROUP_ITEM` recording how much of it is used,
+//! kept in the extent tree or, with the block group tree feature, in a tree
+//! of their own. Combined with the chunk stripes and the device items they
+//! give what `btrfs filesystem df` and `btrfs filesystem usage` report.
+
+use crate::ctree::struct_at;
+use crate::fs::Fs;
+use crate::structs::*;
+use crate::volumes::{Chunk, Device};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone)]
+pub struct BlockGroup {
+    pub start: u64,
+    pub length: u64,
+    pub used: u64,
+    /// `BTRFS_BLOCK_GROUP_*` type and profile
+    pub flags: u64,
+}
+
+pub fn parse_block_group_item(key: &BtrfsKey, data: &[u8]) -> Result<BlockGroup> {
+    let item = struct_at::<BtrfsBlockGroupItem>(data, 0)?;
+
+    Ok(BlockGroup {
+        start: key.objectid,
+        length: key.offset,
+        used: item.used,
+        flags: item.flags,
+    })
+}
+
+/// The block groups of one type and profile, a line of `btrfs fi df`
+#[derive(Debug, Clone, Default)]
+pub struct SpaceInfo {
+    /// `BTRFS_BLOCK_GROUP_*` type and profile
+    pub flags: u64,
+    /// Logical size of the block groups
+    pub total: u64,
+    pub used: u64,
+    /// Device bytes allocated to their chunks, by devid
+    pub raw: BTreeMap<u64, u64>,
+}
+
+impl SpaceInfo {
+    pub fn raw_total(&self) -> u64 {
+        self.raw.values().sum()
+    }
+
+    /// Device bytes taken by every logical byte
+    pub fn ratio(&self) -> f64 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        self.raw_total() as f64 / self.total as f64
+    }
+}
+
+/// Allocation of the devices to block groups and how full those are
+#[derive(Debug, Clone)]
+pub struct SpaceUsage {
+    pub devices: Vec<Device>,
+    pub space_infos: Vec<SpaceInfo>,
+}
+
+impl SpaceUsage {
+    pub fn device_size(&self) -> u64 {
+        self.devices.iter().map(|device| device.total_bytes).sum()
+    }
+
+    /// Bytes of device `devid` allocated to chunks
+    pub fn allocated(&self, devid: u64) -> u64 {
+        self.space_infos
+            .iter()
+            .filter_map(|info| info.raw.get(&devid))
+            .sum()
+    }
+
+    pub fn device_allocated(&self) -> u64 {
+        self.space_infos.iter().map(SpaceInfo::raw_total).sum()
+    }
+
+    pub fn unallocated(&self, devid: u64) -> u64 {
+        let total = self
+            .devices
+            .iter()
+            .find(|device| device.devid == devid)
+            .map_or(0, |device| device.total_bytes);
+        total.saturating_sub(self.allocated(devid))
+    }
+
+    pub fn device_unallocated(&self) -> u64 {
+        self.devices
+            .iter()
+            .map(|device| self.unallocated(device.devid))
+            .sum()
+    }
+
+    /// Device bytes holding used data and metadata, copies included
+    pub fn used(&self) -> u64 {
+        self.space_infos
+            .iter()
+            .map(|info| (info.used as f64 * info.ratio()) as u64)
+            .sum()
+    }
+
+    /// Ratio of the block groups holding `flag`, 0 without any
+    fn ratio_of(&self, flag: u64) -> f64 {
+        let (raw, total) = self
+            .space_infos
+            .iter()
+            .filter(|info| info.flags & flag != 0)
+            .fold((0, 0), |(raw, total), info| {
+                (raw + info.raw_total(), total + info.total)
+            });
+        if total == 0 {
+            return 0.0;
+        }
+        raw as f64 / total as f64
+    }
+
+    pub fn data_ratio(&self) -> f64 {
+        self.ratio_of(BTRFS_BLOCK_GROUP_DATA)
+    }
+
+    pub fn metadata_ratio(&self) -> f64 {
+        self.ratio_of(BTRFS_BLOCK_GROUP_METADATA)
+    }
+
+    /// Space left for file data: what data block groups have free, plus the
+    /// unallocated space under the data profile. The minimum assumes the
+    /// unallocated space goes to the most redundant profile in use.
+    pub fn free_estimated(&self) -> (u64, u64) {
+        let data_free: u64 = self
+            .space_infos
+            .iter()
+            .filter(|info| info.flags & BTRFS_BLOCK_GROUP_DATA != 0)
+            .map(|info| info.total.saturating_sub(info.used))
+            .sum();
+        let unallocated = self.device_unallocated() as f64;
+        let data_ratio = self.data_ratio().max(1.0);
+        let max_ratio = self
+            .space_infos
+            .iter()
+            .map(SpaceInfo::ratio)
+            .fold(data_ratio, f64::max);
+
+        (
+            data_free + (unallocated / data_ratio) as u64,
+            data_free + (unallocated / max_ratio) as u64,
+        )
+    }
+}
+
+impl Fs {
+    /// Every block group, in logical address order
+    pub fn block_groups(&self) -> Result<Vec<BlockGroup>> {
+        let mut block_groups = vec![];
+
+        // The block group tree holds nothing else, walk all of it
+        if self.superblock.compat_ro_flags & BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE != 0 {
+            self.walk_tree(
+                self.tree_root(BTRFS_BLOCK_GROUP_TREE_OBJECTID)?,
+                &BtrfsKey::new(0, BTRFS_BLOCK_GROUP_ITEM_KEY, 0),
+                &BtrfsKey::new(u64::MAX, BTRFS_BLOCK_GROUP_ITEM_KEY, u64::MAX),
+                |key, data| {
+                    if key.ty == BTRFS_BLOCK_GROUP_ITEM_KEY {
+                        block_groups.push(parse_block_group_item(key, data)?);
+                    }
+                    Ok(true)
+                },
+            )?;
+            return Ok(block_groups);
+        }
+
+        // In the extent tree they are mixed with every extent item, look up
+        // the one of each chunk like the kernel does at mount
+        let tree = self.extent_tree()?;
+        for chunk in self.chunks()? {
+            let key = BtrfsKey::new(chunk.logical, BTRFS_BLOCK_GROUP_ITEM_KEY, chunk.length);
+            match self.search_tree(tree, &key)? {
+                Some(data) => block_groups.push(parse_block_group_item(&key, &data)?),
+                None => bail!(
+                    "Chunk {} of {} bytes has no block group item",
+                    chunk.logical,
+                    chunk.length
+                ),
+            }
+        }
+        Ok(block_groups)
+    }
+
+    /// Block groups summed up by type and profile, with the device space
+    /// their chunks take
+    pub fn space_usage(&self) -> Result<SpaceUsage> {
+        let chunks: HashMap<u64, Chunk> = self
+            .chunks()?
+            .into_iter()
+            .map(|chunk| (chunk.logical, chunk))
+            .collect();
+        let mut space_infos: BTreeMap<u64, SpaceInfo> = BTreeMap::new();
+
+        for block_group in self.block_groups()? {
+            let chunk = match chunks.get(&block_group.start) {
+                Some(chunk) if chunk.length == block_group.length => chunk,
+                _ => bail!(
+                    "Block group {} has no chunk of {} bytes",
+                    block_group.start,
+                    block_group.length
+                ),
+            };
+            let info = space_infos
+                .entry(block_group.flags)
+                .or_insert_with(|| SpaceInfo {
+                    flags: block_group.flags,
+                    ..Default::default()
+                });
+            info.total += block_group.length;
+            info.used += block_group.used;
+            for (devid, _) in &chunk.stripes {
+                *info.raw.entry(*devid).or_default() += chunk.stripe_length();
+            }
+        }
+
+        Ok(SpaceUsage {
+            devices: self.devices()?,
+            space_infos: space_infos.into_values().collect(),
+        })
+    }
+}
@@ -1,4 +1,5 @@
 use core::fmt;
+use std::collections::HashMap;
 use std::vec::Vec;
 
 #[derive(Default, Clone, Copy)]
@@ -8,16 +9,28 @@ pub struct ChunkTreeKey {
 }
 pub struct ChunkTree {
     vec: Vec<(ChunkTreeKey, u64)>,
+    /// Physical offsets of the extra copies of DUP and RAID1 chunks on this
+    /// device, by chunk start
+    mirrors: HashMap<u64, Vec<u64>>,
 }
 enum Overlap {
-    YES,
-    NO,
-    DUP,
+    Yes,
+    No,
+    Dup,
+}
+
+impl Default for ChunkTree {
+    fn default() -> Self {
+        ChunkTree::new()
+    }
 }
 
 impl ChunkTree {
     pub fn new() -> ChunkTree {
-        ChunkTree { vec: vec![] }
+        ChunkTree {
+            vec: vec![],
+            mirrors: HashMap::new(),
+        }
     }
 
     fn check_for_overlap(&self, key: &ChunkTreeKey) -> Overlap {
@@ -26,13 +39,13 @@ impl ChunkTree {
             if key.start > k.start && key.start < (k.start + k.size)
                 || key_range_end > k.start && key_range_end < (k.start + k.size)
             {
-                return Overlap::YES;
+                return Overlap::Yes;
             }
             if k.start == key.start {
-                return Overlap::DUP;
+                return Overlap::Dup;
             }
         }
-        return Overlap::NO;
+        Overlap::No
     }
 
     pub fn find_logical(&self, logical: u64) -> Option<(ChunkTreeKey, u64)> {
@@ -52,17 +65,38 @@ impl ChunkTree {
     }
     pub fn insert(&mut self, key: ChunkTreeKey, offset: u64) -> Result<i32, i32> {
         match self.check_for_overlap(&key) {
-            Overlap::NO => {
+            Overlap::No => {
                 self.vec.push((key, offset));
             }
-            Overlap::YES => {
+            Overlap::Yes => {
                 println!("Overlapping chunks");
                 return Err(1);
             }
-            Overlap::DUP => {}
+            Overlap::Dup => {}
         }
         Ok(0)
     }
+
+    /// Record another copy of the chunk starting at `start`
+    pub fn insert_mirror(&mut self, start: u64, offset: u64) {
+        let mirrors = self.mirrors.entry(start).or_default();
+        if !mirrors.contains(&offset) {
+            mirrors.push(offset);
+        }
+    }
+
+    /// Physical offsets of every copy of `logical`, the first stripe first
+    pub fn mirror_offsets(&self, logical: u64) -> Vec<u64> {
+        let (k, v) = match self.find_logical(logical) {
+            Some(found) => found,
+            None => return vec![],
+        };
+        let mut offsets = vec![v + (logical - k.start)];
+        if let Some(mirrors) = self.mirrors.get(&k.start) {
+            offsets.extend(mirrors.iter().map(|m| m + (logical - k.start)));
+        }
+        offsets
+    }
 }
 
 impl fmt::Display for ChunkTree {
@@ -72,9 +106,9 @@ impl fmt::Display for ChunkTree {
         // operation succeeded or failed. Note that `write!` uses syntax which
         // is very similar to `println!`.
         for (chunk, off) in &self.vec {
-            write!(
+            writeln!(
                 f,
-                "Logical start {}, Logical size {}, Physical off: {}\n",
+                "Logical start {}, Logical size {}, Physical off: {}",
                 chunk.start, chunk.size, off
             )?;
         }
@@ -148,4 +182,21 @@ mod tests {
         );
         assert_eq!(insert2.err(), None);
     }
+
+    #[test]
+    fn mirror_offsets() {
+        let mut chunk: ChunkTree = ChunkTree::new();
+
+        let _insert = chunk.insert(
+            super::ChunkTreeKey {
+                start: 200,
+                size: 100,
+            },
+            1000,
+        );
+        chunk.insert_mirror(200, 5000);
+        chunk.insert_mirror(200, 5000);
+        assert_eq!(chunk.mirror_offsets(250), vec![1050, 5050]);
+        assert_eq!(chunk.mirror_offsets(300), Vec::<u64>::new());
+    }
 }
@@ -0,0 +1,106 @@
+use crate::lzo::decompress_framed;
+use crate::structs::*;
+use anyhow::{bail, Result};
+use flate2::read::ZlibDecoder;
+use std::io::Read;
+
+/// Largest window btrfs compresses zstd with, which bounds the decoder memory
+const ZSTD_BTRFS_MAX_WINDOWLOG: u32 = 17;
+
+/// Decompress the data of an extent into exactly `ram_bytes` bytes.
+///
+/// Output is capped at `ram_bytes` so a corrupt stream can't make us allocate
+/// more than the extent claims, and like the kernel a stream that ends early
+/// leaves zeros behind it. LZO segments each hold one sector of data.
+pub fn decompress(
+    compression: u8,
+    data: &[u8],
+    ram_bytes: u64,
+    sector_size: u32,
+) -> Result<Vec<u8>> {
+    // btrfs never compresses more than this into one extent, a bigger size
+    // is corrupt and must not size our buffers
+    if ram_bytes > BTRFS_MAX_COMPRESSED {
+        bail!(
+            "Decompressed size {} is more than {}",
+            ram_bytes,
+            BTRFS_MAX_COMPRESSED
+        );
+    }
+    let mut out = match compression {
+        BTRFS_COMPRESS_NONE => data[..data.len().min(ram_bytes as usize)].to_vec(),
+        BTRFS_COMPRESS_ZLIB => inflate(data, ram_bytes)?,
+        BTRFS_COMPRESS_LZO => decompress_framed(data, sector_size as usize, ram_bytes as usize)?,
+        BTRFS_COMPRESS_ZSTD => unzstd(data, ram_bytes)?,
+        _ => bail!("Unsupported compression type {}", compression),
+    };
+    out.resize(ram_bytes as usize, 0);
+    Ok(out)
+}
+
+/// zlib streams are stored with their header and adler32 trailer
+fn inflate(data: &[u8], ram_bytes: u64) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(ram_bytes as usize);
+
+    if let Err(err) = ZlibDecoder::new(data).take(ram_bytes).read_to_end(&mut out) {
+        bail!("Corrupt zlib stream: {}", err);
+    }
+    Ok(out)
+}
+
+/// Extents hold a single zstd frame, padded with zeros up to the sector size
+fn unzstd(data: &[u8], ram_bytes: u64) -> Result<Vec<u8>> {
+    if ram_bytes > BTRFS_MAX_COMPRESSED {
+        bail!(
+            "zstd output size {} is more than {}",
+            ram_bytes,
+            BTRFS_MAX_COMPRESSED
+        );
+    }
+    let mut out = Vec::with_capacity(ram_bytes as usize);
+    let mut decoder = zstd::stream::read::Decoder::with_buffer(data)?.single_frame();
+    decoder.window_log_max(ZSTD_BTRFS_MAX_WINDOWLOG)?;
+
+    if let Err(err) = decoder.take(ram_bytes).read_to_end(&mut out) {
+        bail!("Invalid zstd stream: {}", err);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn inflates_zlib() {
+        let data = b"btrfs ".repeat(1000);
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(
+            decompress(BTRFS_COMPRESS_ZLIB, &compressed, 6000, 4096).unwrap(),
+            data
+        );
+        assert!(decompress(BTRFS_COMPRESS_ZLIB, &compressed[..20], 6000, 4096).is_err());
+        assert!(decompress(BTRFS_COMPRESS_ZLIB, &compressed, 1 << 40, 4096).is_err());
+    }
+
+    #[test]
+    fn decodes_padded_zstd_frames() {
+        let data = b"btrfs ".repeat(1000);
+        let mut compressed = zstd::bulk::compress(&data, 3).unwrap();
+        compressed.resize(4096, 0);
+
+        assert_eq!(
+            decompress(BTRFS_COMPRESS_ZSTD, &compressed, 6000, 4096).unwrap(),
+            data
+        );
+        assert!(decompress(BTRFS_COMPRESS_ZSTD, &compressed[..10], 6000, 4096).is_err());
+        assert!(unzstd(&compressed, 1 << 40).is_err());
+        assert!(decompress(BTRFS_COMPRESS_ZSTD, &compressed, 1 << 40, 4096).is_err());
+    }
+}
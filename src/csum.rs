@@ -0,0 +1,190 @@
+//! Data checksums: the csum tree and verification of file data against it.
+//!
+//! Every data sector written with checksums enabled has one in an
+//! `EXTENT_CSUM` item. Items are keyed by the logical address of their first
+//! sector and hold the checksums of consecutive sectors back to back.
+
+use crate::ctree::TreeRoot;
+use crate::fs::Fs;
+use crate::structs::*;
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+
+/// Size of one checksum of `csum_type`
+pub fn csum_size(csum_type: u16) -> Result<usize> {
+    match csum_type {
+        BTRFS_CSUM_TYPE_CRC32 => Ok(4),
+        BTRFS_CSUM_TYPE_XXHASH => Ok(8),
+        BTRFS_CSUM_TYPE_SHA256 | BTRFS_CSUM_TYPE_BLAKE2 => Ok(32),
+        _ => bail!("Unknown checksum type {}", csum_type),
+    }
+}
+
+/// Checksum of a data sector, as stored in the csum tree
+pub fn compute_csum(csum_type: u16, data: &[u8]) -> Result<Vec<u8>> {
+    match csum_type {
+        BTRFS_CSUM_TYPE_CRC32 => Ok(crc32c::crc32c(data).to_le_bytes().to_vec()),
+        BTRFS_CSUM_TYPE_XXHASH => Ok(xxhash_rust::xxh64::xxh64(data, 0).to_le_bytes().to_vec()),
+        BTRFS_CSUM_TYPE_SHA256 => Ok(Sha256::digest(data).to_vec()),
+        // BLAKE2b cut down to the 32 bytes of a checksum
+        BTRFS_CSUM_TYPE_BLAKE2 => Ok(blake2b_simd::Params::new()
+            .hash_length(32)
+            .hash(data)
+            .as_bytes()
+            .to_vec()),
+        _ => bail!("Unknown checksum type {}", csum_type),
+    }
+}
+
+impl Fs {
+    pub fn csum_tree(&self) -> Result<TreeRoot> {
+        match self.read_root_item(BTRFS_CSUM_TREE_OBJECTID)? {
+            Some(root_item) => Ok(TreeRoot::from(&root_item)),
+            None => bail!("The filesystem has no csum tree"),
+        }
+    }
+
+    /// Expected checksum of every sector of `[logical, logical + len)`, or
+    /// `None` for sectors without one. `logical` must be sector aligned.
+    pub fn lookup_csums(&self, logical: u64, len: u64) -> Result<Vec<Option<Vec<u8>>>> {
+        let sector_size = self.superblock.sector_size as u64;
+        let size = csum_size(self.superblock.csum_type)?;
+
+        if !logical.is_multiple_of(sector_size) {
+            bail!("Logical address {} isn't sector aligned", logical);
+        }
+        let count = len.div_ceil(sector_size);
+        let mut csums = vec![None; count as usize];
+        if count == 0 {
+            return Ok(csums);
+        }
+        let end = logical + count * sector_size;
+
+        // An item can't hold more checksums than fit in a leaf, so the one
+        // covering `logical` starts at most that many sectors before it
+        let max_span = self.node_size() as u64 / size as u64 * sector_size;
+        self.walk_tree(
+            self.csum_tree()?,
+            &BtrfsKey::new(
+                BTRFS_EXTENT_CSUM_OBJECTID,
+                BTRFS_EXTENT_CSUM_KEY,
+                logical.saturating_sub(max_span),
+            ),
+            &BtrfsKey::new(BTRFS_EXTENT_CSUM_OBJECTID, BTRFS_EXTENT_CSUM_KEY, end - 1),
+            |key, data| {
+                for (i, csum) in data.chunks_exact(size).enumerate() {
+                    let at = key.offset + i as u64 * sector_size;
+                    if at >= logical && at < end {
+                        csums[((at - logical) / sector_size) as usize] = Some(csum.to_vec());
+                    }
+                }
+                Ok(true)
+            },
+        )?;
+        Ok(csums)
+    }
+
+    /// Expected checksum of the data sector holding `logical`
+    pub fn data_csum(&self, logical: u64) -> Result<Option<Vec<u8>>> {
+        let sector_size = self.superblock.sector_size as u64;
+        let start = logical / sector_size * sector_size;
+
+        Ok(self.lookup_csums(start, sector_size)?.pop().flatten())
+    }
+
+    /// Like `read_logical`, checking every sector the read touches against
+    /// the csum tree. A sector that doesn't match is read again from the
+    /// other copies of its chunk, the read fails when none of them matches or
+    /// when the sector has no checksum.
+    pub fn read_logical_verified(&self, logical: u64, buf: &mut [u8]) -> Result<()> {
+        let sector_size = self.superblock.sector_size as u64;
+        let start = logical / sector_size * sector_size;
+        let end = (logical + buf.len() as u64).div_ceil(sector_size) * sector_size;
+
+        let csums = self.lookup_csums(start, end - start)?;
+        let mut data = vec![0; (end - start) as usize];
+        self.read_logical(start, &mut data)?;
+
+        for (i, (sector, expected)) in data
+            .chunks_exact_mut(sector_size as usize)
+            .zip(&csums)
+            .enumerate()
+        {
+            let at = start + i as u64 * sector_size;
+            match expected {
+                Some(expected) => self.verify_sector(at, sector, expected)?,
+                None => bail!("No checksum for the data at logical address {}", at),
+            }
+        }
+        let from = (logical - start) as usize;
+        buf.copy_from_slice(&data[from..from + buf.len()]);
+        Ok(())
+    }
+
+    /// Check a sector read from the first copy, replacing it with the first
+    /// other copy matching `expected` when it doesn't
+    fn verify_sector(&self, logical: u64, sector: &mut [u8], expected: &[u8]) -> Result<()> {
+        let csum_type = self.superblock.csum_type;
+
+        if compute_csum(csum_type, sector)? == expected {
+            return Ok(());
+        }
+        let mirrors = self.num_mirrors(logical);
+        for mirror in 1..mirrors {
+            self.read_logical_mirror(logical, sector, mirror)?;
+            if compute_csum(csum_type, sector)? == expected {
+                return Ok(());
+            }
+        }
+        bail!(
+            "Checksum mismatch for the data at logical address {} on all {} copies",
+            logical,
+            mirrors
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn computes_crc32c() {
+        assert_eq!(
+            compute_csum(BTRFS_CSUM_TYPE_CRC32, b"123456789").unwrap(),
+            0xe3069283u32.to_le_bytes()
+        );
+        assert!(compute_csum(4, b"").is_err());
+    }
+
+    #[test]
+    fn computes_xxhash64() {
+        let csum = compute_csum(BTRFS_CSUM_TYPE_XXHASH, b"abc").unwrap();
+        assert_eq!(csum, 0x44bc2cf5ad770999u64.to_le_bytes());
+        assert_eq!(csum.len(), csum_size(BTRFS_CSUM_TYPE_XXHASH).unwrap());
+    }
+
+    #[test]
+    fn computes_sha256() {
+        let csum = compute_csum(BTRFS_CSUM_TYPE_SHA256, b"abc").unwrap();
+        assert_eq!(
+            hex(&csum),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(csum.len(), csum_size(BTRFS_CSUM_TYPE_SHA256).unwrap());
+    }
+
+    #[test]
+    fn computes_blake2b_256() {
+        let csum = compute_csum(BTRFS_CSUM_TYPE_BLAKE2, b"abc").unwrap();
+        assert_eq!(
+            hex(&csum),
+            "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319"
+        );
+        assert_eq!(csum.len(), csum_size(BTRFS_CSUM_TYPE_BLAKE2).unwrap());
+    }
+}
@@ -1,10 +1,12 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 use crate::chunk_tree_cache::{ChunkTree, ChunkTreeKey};
+use crate::fs::Fs;
 use crate::structs::*;
+use crate::tree_block_cache::{TreeBlock, TreeBlockCache};
+use anyhow::{bail, Result};
 use std::fs::File;
 use std::io;
-use std::os::unix::prelude::FileExt;
 
 pub fn parse_sys_chunk_array(sb: &BtrfsSuperblock) -> Result<ChunkTree, i32> {
     let mut offset: usize = 0;
@@ -36,11 +38,6 @@ pub fn parse_sys_chunk_array(sb: &BtrfsSuperblock) -> Result<ChunkTree, i32> {
 
         let length = btrfschunk.length;
 
-        // Ignoring the second stripe
-        // One stripe is already a part of BtrfsChunk, and that is why
-        //(num_stripes - 1) * stripe_size
-        offset += chunk_size + (num_stripes - 1) * stripe_size;
-
         chunk_tree.insert(
             ChunkTreeKey {
                 start: btrfskey.offset,
@@ -48,37 +45,51 @@ pub fn parse_sys_chunk_array(sb: &BtrfsSuperblock) -> Result<ChunkTree, i32> {
             },
             btrfschunk.stripe.offset,
         )?;
+        insert_chunk_mirrors(sb, &mut chunk_tree, btrfskey.offset, chunk);
+
+        // One stripe is already a part of BtrfsChunk, and that is why
+        //(num_stripes - 1) * stripe_size
+        offset += chunk_size + (num_stripes - 1) * stripe_size;
     }
     Ok(chunk_tree)
 }
 
+/// Record the other stripes of a mirrored chunk that live on this device, so
+/// that reads can fall back to them. `chunk` starts at the `BtrfsChunk`.
+fn insert_chunk_mirrors(sb: &BtrfsSuperblock, cache: &mut ChunkTree, start: u64, chunk: &[u8]) {
+    let btrfschunk = unsafe { *(chunk.as_ptr() as *const BtrfsChunk) };
+    let stripe_size = std::mem::size_of::<BtrfsStripe>();
+    let first_stripe = std::mem::offset_of!(BtrfsChunk, stripe);
+
+    if btrfschunk.ty & BTRFS_BLOCK_GROUP_MIRRORED == 0 {
+        return;
+    }
+    for i in 1..btrfschunk.num_stripes as usize {
+        let stripe = match struct_at::<BtrfsStripe>(chunk, first_stripe + i * stripe_size) {
+            Ok(stripe) => *stripe,
+            Err(_) => break,
+        };
+        if stripe.devid == sb.dev_item.devid {
+            cache.insert_mirror(start, stripe.offset);
+        }
+    }
+}
+
 pub fn read_chunk_tree_root(
     file: &File,
-    chunk_logical_root: u64,
+    sb: &BtrfsSuperblock,
     cache: &ChunkTree,
-) -> io::Result<Vec<u8>> {
-    let size = cache
-        .find_logical(chunk_logical_root)
-        .expect("Can't find the chunk")
-        .0
-        .size;
-
-    let mut chunk_root = vec![0; size as usize];
-
-    let physical_off = cache
-        .offset(chunk_logical_root)
-        .expect("Can't find the chunk");
-
-    let root = file.read_exact_at(&mut chunk_root, physical_off)?;
-
-    Ok(chunk_root)
+    block_cache: &TreeBlockCache,
+) -> io::Result<TreeBlock> {
+    block_cache.read_tree_block(file, sb, cache, sb.chunk_root, sb.chunk_root_generation)
 }
 
 pub fn walk_chunk_root_tree(
     file: &File,
-    buf: &Vec<u8>,
+    sb: &BtrfsSuperblock,
+    buf: &[u8],
     cache: &mut ChunkTree,
-    nodesize: u32,
+    block_cache: &TreeBlockCache,
 ) -> io::Result<()> {
     let header = unsafe { &*(buf.as_ptr() as *const BtrfsHeader) };
 
@@ -102,9 +113,6 @@ pub fn walk_chunk_root_tree(
                     + item.offset as usize) as *const BtrfsChunk)
             };
 
-            let off = item.key.offset;
-            let len = chunk.length;
-            let phy_off = chunk.stripe.offset;
             cache
                 .insert(
                     ChunkTreeKey {
@@ -114,9 +122,9 @@ pub fn walk_chunk_root_tree(
                     chunk.stripe.offset,
                 )
                 .unwrap_or_else(|_| panic!("Error inserting cache"));
+            insert_chunk_mirrors(sb, cache, item.key.offset, item_data(buf, item)?);
         }
     } else {
-        println!("Node");
         for i in 0..header.nritems as usize {
             let keyptr = unsafe {
                 &*((buf.as_ptr() as usize
@@ -124,12 +132,252 @@ pub fn walk_chunk_root_tree(
                     + (i * std::mem::size_of::<BtrfsKeyPtr>()))
                     as *const BtrfsKeyPtr)
             };
-            let physical_offset = cache.offset(keyptr.blockptr).expect("error getting offset");
-
-            let mut node = vec![0; nodesize as usize];
-            file.read_exact_at(&mut node, physical_offset)?;
-            walk_chunk_root_tree(&file, &node, cache, nodesize)?;
+            let node =
+                block_cache.read_tree_block(file, sb, cache, keyptr.blockptr, keyptr.generation)?;
+            walk_chunk_root_tree(file, sb, &node, cache, block_cache)?;
         }
     }
     Ok(())
 }
+
+/// Location of the root block of a tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeRoot {
+    pub bytenr: u64,
+    pub generation: u64,
+}
+
+impl From<&BtrfsRootItem> for TreeRoot {
+    fn from(root_item: &BtrfsRootItem) -> Self {
+        TreeRoot {
+            bytenr: root_item.bytenr,
+            generation: root_item.generation,
+        }
+    }
+}
+
+impl BtrfsKey {
+    pub fn new(objectid: u64, ty: u8, offset: u64) -> BtrfsKey {
+        BtrfsKey {
+            objectid,
+            ty,
+            offset,
+        }
+    }
+
+    fn as_tuple(&self) -> (u64, u8, u64) {
+        (self.objectid, self.ty, self.offset)
+    }
+}
+
+// Keys are sorted by objectid, then type, then offset
+impl PartialEq for BtrfsKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_tuple() == other.as_tuple()
+    }
+}
+
+impl Eq for BtrfsKey {}
+
+impl PartialOrd for BtrfsKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BtrfsKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_tuple().cmp(&other.as_tuple())
+    }
+}
+
+fn corrupt(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn block_header(buf: &[u8]) -> &BtrfsHeader {
+    unsafe { &*(buf.as_ptr() as *const BtrfsHeader) }
+}
+
+/// `slot`th item of a leaf, `nritems` is checked when the block is read
+pub fn leaf_item(buf: &[u8], slot: usize) -> &BtrfsItem {
+    unsafe {
+        &*((buf.as_ptr() as usize
+            + std::mem::size_of::<BtrfsHeader>()
+            + (slot * std::mem::size_of::<BtrfsItem>())) as *const BtrfsItem)
+    }
+}
+
+/// `slot`th key pointer of a node, `nritems` is checked when the block is read
+pub fn node_keyptr(buf: &[u8], slot: usize) -> &BtrfsKeyPtr {
+    unsafe {
+        &*((buf.as_ptr() as usize
+            + std::mem::size_of::<BtrfsHeader>()
+            + (slot * std::mem::size_of::<BtrfsKeyPtr>())) as *const BtrfsKeyPtr)
+    }
+}
+
+/// Data of a leaf item, bounds checked against the block
+pub fn item_data<'a>(buf: &'a [u8], item: &BtrfsItem) -> io::Result<&'a [u8]> {
+    let start = std::mem::size_of::<BtrfsHeader>() + item.offset as usize;
+    let end = start + item.size as usize;
+
+    if end > buf.len() {
+        let key = item.key;
+        return Err(corrupt(format!("item {:?} points outside its leaf", key)));
+    }
+    Ok(&buf[start..end])
+}
+
+/// Reference a fixed size structure at `offset` of an item, bounds checked
+pub fn struct_at<T: Copy>(data: &[u8], offset: usize) -> io::Result<&T> {
+    if offset + std::mem::size_of::<T>() > data.len() {
+        return Err(corrupt(format!(
+            "truncated {} at offset {}",
+            std::any::type_name::<T>(),
+            offset
+        )));
+    }
+    Ok(unsafe { &*(data.as_ptr().add(offset) as *const T) })
+}
+
+/// Copy a structure that older filesystems store truncated (such as root
+/// items written before `generation_v2`), the missing tail reads as zeroes
+pub fn struct_prefix<T: Copy>(data: &[u8]) -> T {
+    let mut out: T = unsafe { std::mem::zeroed() };
+    let len = data.len().min(std::mem::size_of::<T>());
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), &mut out as *mut T as *mut u8, len);
+    }
+    out
+}
+
+impl Fs {
+    /// Visit every item with `min <= key <= max` in key order.
+    ///
+    /// Only the nodes whose key range overlaps `[min, max]` are read. The
+    /// visitor returns `false` to stop the walk early.
+    pub fn walk_tree<F>(
+        &self,
+        root: TreeRoot,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&BtrfsKey, &[u8]) -> Result<bool>,
+    {
+        self.walk_node(root.bytenr, root.generation, min, max, &mut f)?;
+        Ok(())
+    }
+
+    fn walk_node<F>(
+        &self,
+        bytenr: u64,
+        generation: u64,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+        f: &mut F,
+    ) -> Result<bool>
+    where
+        F: FnMut(&BtrfsKey, &[u8]) -> Result<bool>,
+    {
+        let block = self.read_tree_block(bytenr, generation)?;
+        let header = block_header(&block);
+        let nritems = header.nritems as usize;
+
+        // At the leaf
+        if header.level == 0 {
+            for i in 0..nritems {
+                let item = leaf_item(&block, i);
+                let key = item.key;
+
+                if key < *min {
+                    continue;
+                }
+                if key > *max {
+                    return Ok(false);
+                }
+                if !f(&key, item_data(&block, item)?)? {
+                    return Ok(false);
+                }
+            }
+        } else {
+            for i in 0..nritems {
+                let keyptr = node_keyptr(&block, i);
+
+                if keyptr.key > *max {
+                    return Ok(false);
+                }
+                // The child only holds keys below the next pointer's key
+                if i + 1 < nritems && node_keyptr(&block, i + 1).key <= *min {
+                    continue;
+                }
+                if !self.walk_node(keyptr.blockptr, keyptr.generation, min, max, f)? {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Addresses of the blocks from the root down to the block at `level`
+    /// whose key range covers `key`
+    pub fn search_path(&self, root: TreeRoot, key: &BtrfsKey, level: u8) -> Result<Vec<u64>> {
+        let mut path = vec![];
+        let (mut bytenr, mut generation) = (root.bytenr, root.generation);
+
+        loop {
+            path.push(bytenr);
+            let block = self.read_tree_block(bytenr, generation)?;
+            let header = block_header(&block);
+            let nritems = header.nritems as usize;
+
+            if header.level <= level {
+                return Ok(path);
+            }
+            if nritems == 0 {
+                bail!("Empty node {}", bytenr);
+            }
+            // The last child whose first key isn't past `key`
+            let slot = (1..nritems)
+                .take_while(|&i| node_keyptr(&block, i).key <= *key)
+                .last()
+                .unwrap_or(0);
+            let keyptr = node_keyptr(&block, slot);
+            bytenr = keyptr.blockptr;
+            generation = keyptr.generation;
+        }
+    }
+
+    /// Exact match search, returns a copy of the item data
+    pub fn search_tree(&self, root: TreeRoot, key: &BtrfsKey) -> Result<Option<Vec<u8>>> {
+        let mut found = None;
+
+        self.walk_tree(root, key, key, |_, data| {
+            found = Some(data.to_vec());
+            Ok(false)
+        })?;
+        Ok(found)
+    }
+
+    /// Find the `ROOT_ITEM` of tree `objectid` in the root tree.
+    ///
+    /// Snapshots carry their creation transid in the key offset, so like the
+    /// kernel this takes the item with the highest offset.
+    pub fn read_root_item(&self, objectid: u64) -> Result<Option<BtrfsRootItem>> {
+        let mut found = None;
+
+        self.walk_tree(
+            self.root_tree(),
+            &BtrfsKey::new(objectid, BTRFS_ROOT_ITEM_KEY, 0),
+            &BtrfsKey::new(objectid, BTRFS_ROOT_ITEM_KEY, u64::MAX),
+            |_, data| {
+                found = Some(struct_prefix::<BtrfsRootItem>(data));
+                Ok(true)
+            },
+        )?;
+        Ok(found)
+    }
+}
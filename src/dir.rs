@@ -0,0 +1,239 @@
+use crate::ctree::struct_at;
+use crate::fs::Fs;
+use crate::inode::{FileType, InodeId};
+use crate::structs::*;
+use crate::subvolume::parse_root_ref;
+use anyhow::{bail, Result};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+/// One name packed in a `DIR_ITEM`, `DIR_INDEX` or `XATTR_ITEM`
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// Inode (`INODE_ITEM`) or subvolume (`ROOT_ITEM`) the entry points to
+    pub location: BtrfsKey,
+    pub transid: u64,
+    /// `BTRFS_FT_*` file type
+    pub ty: u8,
+    pub name: Vec<u8>,
+    /// Only used by xattrs, which store their value after the name
+    pub data: Vec<u8>,
+}
+
+/// Entry returned by `Fs::read_dir`
+#[derive(Debug, Clone)]
+pub struct ReadDirEntry {
+    /// Dir index, which grows with every entry created in the directory
+    pub index: u64,
+    pub name: Vec<u8>,
+    /// Inode (`INODE_ITEM`) or subvolume (`ROOT_ITEM`) the entry points to
+    pub location: BtrfsKey,
+    pub file_type: FileType,
+}
+
+/// Hash used as the key offset of `DIR_ITEM` and `XATTR_ITEM` items: a crc32c
+/// seeded with `~1` and without the final inversion
+pub fn name_hash(name: &[u8]) -> u64 {
+    !crc32c::crc32c_append(1, name) as u64
+}
+
+/// Decode every entry of a dir item. Names hashing to the same value share a
+/// single item, so there can be more than one.
+pub fn parse_dir_items(data: &[u8]) -> Result<Vec<DirEntry>> {
+    let mut entries = vec![];
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let dir_item = struct_at::<BtrfsDirItem>(data, offset)?;
+        let name_start = offset + std::mem::size_of::<BtrfsDirItem>();
+        let data_start = name_start + dir_item.name_len as usize;
+        let end = data_start + dir_item.data_len as usize;
+
+        if end > data.len() {
+            bail!("Truncated dir item at offset {}", offset);
+        }
+        entries.push(DirEntry {
+            location: dir_item.location,
+            transid: dir_item.transid,
+            ty: dir_item.ty,
+            name: data[name_start..data_start].to_vec(),
+            data: data[data_start..end].to_vec(),
+        });
+        offset = end;
+    }
+    Ok(entries)
+}
+
+impl Fs {
+    /// Find the entry called `name` in directory `dir`.
+    ///
+    /// The `DIR_ITEM` is keyed by the name hash, colliding names are packed
+    /// in the same item and told apart by comparing the names.
+    pub fn lookup_entry(&self, dir: InodeId, name: &[u8]) -> Result<Option<DirEntry>> {
+        let key = BtrfsKey::new(dir.ino, BTRFS_DIR_ITEM_KEY, name_hash(name));
+
+        match self.search_tree(self.subvolume_root(dir.subvol)?, &key)? {
+            Some(data) => Ok(parse_dir_items(&data)?.into_iter().find(|e| e.name == name)),
+            None => Ok(None),
+        }
+    }
+
+    /// List directory `dir` in creation order, from its `DIR_INDEX` items.
+    /// `.` and `..` are not stored on disk and are not returned.
+    pub fn read_dir(&self, dir: InodeId) -> Result<Vec<ReadDirEntry>> {
+        let mut entries = vec![];
+
+        // The placeholder for a nested subvolume of a snapshot source is empty
+        if dir.ino == BTRFS_EMPTY_SUBVOL_DIR_OBJECTID {
+            return Ok(entries);
+        }
+        self.walk_tree(
+            self.subvolume_root(dir.subvol)?,
+            &BtrfsKey::new(dir.ino, BTRFS_DIR_INDEX_KEY, 0),
+            &BtrfsKey::new(dir.ino, BTRFS_DIR_INDEX_KEY, u64::MAX),
+            |key, data| {
+                // Each index holds exactly one name
+                for entry in parse_dir_items(data)? {
+                    entries.push(ReadDirEntry {
+                        index: key.offset,
+                        name: entry.name,
+                        location: entry.location,
+                        file_type: FileType::from_dir_type(entry.ty),
+                    });
+                }
+                Ok(true)
+            },
+        )?;
+        Ok(entries)
+    }
+
+    /// Resolve `path` from the root directory of subvolume `subvol`.
+    ///
+    /// Names are raw bytes. Empty components and `.` are skipped, a trailing
+    /// slash requires a directory and `..` never climbs above the starting
+    /// subvolume, the same as for a path below a mount point.
+    pub fn lookup_path(&self, subvol: u64, path: &OsStr) -> Result<InodeId> {
+        let root = self.subvolume_root_dir(subvol)?;
+        let mut cur = root;
+        let mut is_dir = true;
+
+        for name in path.as_bytes().split(|b| *b == b'/') {
+            if name.is_empty() {
+                continue;
+            }
+            if !is_dir {
+                bail!("{}: Not a directory", path.to_string_lossy());
+            }
+            if name == b"." {
+                continue;
+            }
+            if name == b".." {
+                if cur != root {
+                    cur = self.parent_dir(cur)?;
+                }
+                continue;
+            }
+            let entry = match self.lookup_entry(cur, name)? {
+                Some(entry) => entry,
+                None => bail!("{}: No such file or directory", path.to_string_lossy()),
+            };
+            is_dir = entry.ty == BTRFS_FT_DIR;
+            cur = self.entry_target(cur, &entry.location, &entry.name)?;
+        }
+        // A trailing slash only makes sense after a directory
+        if !is_dir && path.as_bytes().ends_with(b"/") {
+            bail!("{}: Not a directory", path.to_string_lossy());
+        }
+        Ok(cur)
+    }
+
+    pub fn subvolume_root_dir(&self, subvol: u64) -> Result<InodeId> {
+        match self.read_root_item(subvol)? {
+            Some(root_item) => Ok(InodeId::new(subvol, root_item.root_dirid)),
+            None => bail!("Subvolume {} not found", subvol),
+        }
+    }
+
+    /// Directory holding directory `dir`, the parent of the root directory of
+    /// a subvolume is the directory its `ROOT_BACKREF` points to
+    pub fn parent_dir(&self, dir: InodeId) -> Result<InodeId> {
+        if dir.ino == BTRFS_EMPTY_SUBVOL_DIR_OBJECTID {
+            bail!("Can't find the parent of a placeholder subvolume directory");
+        }
+        if dir == self.subvolume_root_dir(dir.subvol)? {
+            let mut parent = None;
+            self.walk_tree(
+                self.root_tree(),
+                &BtrfsKey::new(dir.subvol, BTRFS_ROOT_BACKREF_KEY, 0),
+                &BtrfsKey::new(dir.subvol, BTRFS_ROOT_BACKREF_KEY, u64::MAX),
+                |key, data| {
+                    let (dirid, _, _) = parse_root_ref(data)?;
+                    parent = Some(InodeId::new(key.offset, dirid));
+                    Ok(false)
+                },
+            )?;
+            // The top level is its own parent
+            return Ok(parent.unwrap_or(dir));
+        }
+
+        let mut parent = None;
+        self.walk_tree(
+            self.subvolume_root(dir.subvol)?,
+            &BtrfsKey::new(dir.ino, BTRFS_INODE_REF_KEY, 0),
+            &BtrfsKey::new(dir.ino, BTRFS_INODE_REF_KEY, u64::MAX),
+            |key, _| {
+                parent = Some(InodeId::new(dir.subvol, key.offset));
+                Ok(false)
+            },
+        )?;
+        match parent {
+            Some(parent) => Ok(parent),
+            None => bail!("Directory {} has no inode ref", dir),
+        }
+    }
+
+    /// Inode the entry `name` of directory `dir` points to.
+    ///
+    /// Entries whose location is a `ROOT_ITEM` lead into a nested subvolume
+    /// and resolve to its root directory. A snapshot keeps the entries of the
+    /// subvolumes nested in its source, but not the `ROOT_REF` backing them;
+    /// the kernel shows those as an empty directory and so do we.
+    pub fn entry_target(&self, dir: InodeId, location: &BtrfsKey, name: &[u8]) -> Result<InodeId> {
+        if location.ty != BTRFS_ROOT_ITEM_KEY {
+            return Ok(InodeId::new(dir.subvol, location.objectid));
+        }
+
+        let child = location.objectid;
+        let root_ref = self.search_tree(
+            self.root_tree(),
+            &BtrfsKey::new(dir.subvol, BTRFS_ROOT_REF_KEY, child),
+        )?;
+        let linked = match root_ref {
+            Some(data) => {
+                let (dirid, _, ref_name) = parse_root_ref(&data)?;
+                dirid == dir.ino && ref_name == name
+            }
+            None => false,
+        };
+        if !linked {
+            return Ok(InodeId::new(dir.subvol, BTRFS_EMPTY_SUBVOL_DIR_OBJECTID));
+        }
+
+        match self.read_root_item(child)? {
+            Some(root_item) => Ok(InodeId::new(child, root_item.root_dirid)),
+            None => bail!("Missing root item for subvolume {}", child),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::name_hash;
+
+    #[test]
+    fn name_hash_matches_the_kernel() {
+        // crc32c_le(~1, name) as computed by btrfs_name_hash()
+        assert_eq!(name_hash(b"default"), 0x8dbfc2d2);
+        assert_eq!(name_hash(b""), 0xfffffffe);
+    }
+}
@@ -0,0 +1,389 @@
+//! The extent tree: one item per allocated extent, data or tree block, with
+//! the back references of everything pointing to it.
+//!
+//! The first refs of an extent are stored inline in its `EXTENT_ITEM` or
+//! `METADATA_ITEM`, the others as keyed items following it, with the extent
+//! start as objectid.
+
+use crate::ctree::{struct_at, TreeRoot};
+use crate::fs::Fs;
+use crate::structs::*;
+use anyhow::{bail, Result};
+use std::fmt;
+
+const EXTENT_FLAG_NAMES: [(u64, &str); 3] = [
+    (BTRFS_EXTENT_FLAG_DATA, "DATA"),
+    (BTRFS_EXTENT_FLAG_TREE_BLOCK, "TREE_BLOCK"),
+    (BTRFS_BLOCK_FLAG_FULL_BACKREF, "FULL_BACKREF"),
+];
+
+/// A back reference to an extent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtentRef {
+    /// Tree block of tree `root`
+    TreeBlock { root: u64 },
+    /// Tree block pointed to by the node at `parent`
+    SharedBlock { parent: u64 },
+    /// Data of file `objectid` in subvolume `root`, `offset` is the file
+    /// offset the start of the extent maps to
+    Data {
+        root: u64,
+        objectid: u64,
+        offset: u64,
+        count: u32,
+    },
+    /// Data pointed to by file extent items in the leaf at `parent`
+    SharedData { parent: u64, count: u32 },
+    /// Subvolume charged for the extent by simple quotas, not a reference
+    Owner { root: u64 },
+}
+
+impl ExtentRef {
+    /// How many references this accounts for in `Extent::refs`
+    pub fn count(&self) -> u64 {
+        match self {
+            ExtentRef::TreeBlock { .. } | ExtentRef::SharedBlock { .. } => 1,
+            ExtentRef::Data { count, .. } | ExtentRef::SharedData { count, .. } => *count as u64,
+            ExtentRef::Owner { .. } => 0,
+        }
+    }
+}
+
+impl fmt::Display for ExtentRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtentRef::TreeBlock { root } => write!(f, "tree block ref root {}", root),
+            ExtentRef::SharedBlock { parent } => write!(f, "shared block ref parent {}", parent),
+            ExtentRef::Data {
+                root,
+                objectid,
+                offset,
+                count,
+            } => write!(
+                f,
+                "extent data ref root {} objectid {} offset {} count {}",
+                root, objectid, offset, count
+            ),
+            ExtentRef::SharedData { parent, count } => {
+                write!(f, "shared data ref parent {} count {}", parent, count)
+            }
+            ExtentRef::Owner { root } => write!(f, "extent owner ref root {}", root),
+        }
+    }
+}
+
+/// An allocated extent and its back references
+#[derive(Debug, Clone)]
+pub struct Extent {
+    pub bytenr: u64,
+    pub num_bytes: u64,
+    pub refs: u64,
+    pub generation: u64,
+    /// `BTRFS_EXTENT_FLAG_*` and `BTRFS_BLOCK_FLAG_*`
+    pub flags: u64,
+    /// Level of a tree block
+    pub level: Option<u8>,
+    /// First key of a tree block, only stored by non-skinny items
+    pub first_key: Option<BtrfsKey>,
+    pub inline_refs: Vec<ExtentRef>,
+    pub keyed_refs: Vec<ExtentRef>,
+}
+
+impl Extent {
+    pub fn is_data(&self) -> bool {
+        self.flags & BTRFS_EXTENT_FLAG_DATA != 0
+    }
+
+    pub fn is_tree_block(&self) -> bool {
+        self.flags & BTRFS_EXTENT_FLAG_TREE_BLOCK != 0
+    }
+
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        EXTENT_FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.flags & flag != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    /// Inline refs, then keyed ones
+    pub fn all_refs(&self) -> impl Iterator<Item = &ExtentRef> {
+        self.inline_refs.iter().chain(&self.keyed_refs)
+    }
+
+    pub fn contains(&self, logical: u64) -> bool {
+        logical >= self.bytenr && logical - self.bytenr < self.num_bytes
+    }
+}
+
+/// Decode an `EXTENT_ITEM` or `METADATA_ITEM` and its inline refs. Skinny
+/// metadata items don't store the size of the block, `node_size` is used.
+pub fn parse_extent_item(key: &BtrfsKey, data: &[u8], node_size: u32) -> Result<Extent> {
+    let item = *struct_at::<BtrfsExtentItem>(data, 0)?;
+    let mut offset = std::mem::size_of::<BtrfsExtentItem>();
+
+    let mut extent = Extent {
+        bytenr: key.objectid,
+        num_bytes: key.offset,
+        refs: item.refs,
+        generation: item.generation,
+        flags: item.flags,
+        level: None,
+        first_key: None,
+        inline_refs: vec![],
+        keyed_refs: vec![],
+    };
+    if key.ty == BTRFS_METADATA_ITEM_KEY {
+        extent.num_bytes = node_size as u64;
+        extent.level = Some(key.offset as u8);
+    } else if extent.is_tree_block() {
+        let info = *struct_at::<BtrfsTreeBlockInfo>(data, offset)?;
+        extent.level = Some(info.level);
+        extent.first_key = Some(info.key);
+        offset += std::mem::size_of::<BtrfsTreeBlockInfo>();
+    }
+    extent.inline_refs = parse_inline_refs(&data[offset..])?;
+    Ok(extent)
+}
+
+fn parse_inline_refs(data: &[u8]) -> Result<Vec<ExtentRef>> {
+    let mut refs = vec![];
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let inline = *struct_at::<BtrfsExtentInlineRef>(data, offset)?;
+        // Data refs overlay the offset field
+        let body = offset + std::mem::size_of::<u8>();
+        offset += std::mem::size_of::<BtrfsExtentInlineRef>();
+
+        let extent_ref = match inline.ty {
+            BTRFS_TREE_BLOCK_REF_KEY => ExtentRef::TreeBlock {
+                root: inline.offset,
+            },
+            BTRFS_SHARED_BLOCK_REF_KEY => ExtentRef::SharedBlock {
+                parent: inline.offset,
+            },
+            BTRFS_EXTENT_OWNER_REF_KEY => ExtentRef::Owner {
+                root: inline.offset,
+            },
+            BTRFS_EXTENT_DATA_REF_KEY => {
+                let data_ref = *struct_at::<BtrfsExtentDataRef>(data, body)?;
+                offset = body + std::mem::size_of::<BtrfsExtentDataRef>();
+                ExtentRef::Data {
+                    root: data_ref.root,
+                    objectid: data_ref.objectid,
+                    offset: data_ref.offset,
+                    count: data_ref.count,
+                }
+            }
+            BTRFS_SHARED_DATA_REF_KEY => {
+                let shared = *struct_at::<BtrfsSharedDataRef>(data, offset)?;
+                offset += std::mem::size_of::<BtrfsSharedDataRef>();
+                ExtentRef::SharedData {
+                    parent: inline.offset,
+                    count: shared.count,
+                }
+            }
+            ty => bail!("Unknown inline extent ref type {}", ty),
+        };
+        refs.push(extent_ref);
+    }
+    Ok(refs)
+}
+
+/// Decode a keyed back reference item, `None` for other item types
+pub fn parse_keyed_ref(key: &BtrfsKey, data: &[u8]) -> Result<Option<ExtentRef>> {
+    let extent_ref = match key.ty {
+        BTRFS_TREE_BLOCK_REF_KEY => ExtentRef::TreeBlock { root: key.offset },
+        BTRFS_SHARED_BLOCK_REF_KEY => ExtentRef::SharedBlock { parent: key.offset },
+        // The key offset is a hash of the ref
+        BTRFS_EXTENT_DATA_REF_KEY => {
+            let data_ref = *struct_at::<BtrfsExtentDataRef>(data, 0)?;
+            ExtentRef::Data {
+                root: data_ref.root,
+                objectid: data_ref.objectid,
+                offset: data_ref.offset,
+                count: data_ref.count,
+            }
+        }
+        BTRFS_SHARED_DATA_REF_KEY => ExtentRef::SharedData {
+            parent: key.offset,
+            count: struct_at::<BtrfsSharedDataRef>(data, 0)?.count,
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(extent_ref))
+}
+
+impl Fs {
+    pub fn extent_tree(&self) -> Result<TreeRoot> {
+        match self.read_root_item(BTRFS_EXTENT_TREE_OBJECTID)? {
+            Some(root_item) => Ok(TreeRoot::from(&root_item)),
+            None => bail!("The filesystem has no extent tree"),
+        }
+    }
+
+    /// The extent holding logical address `logical`, with all its refs
+    pub fn lookup_extent(&self, logical: u64) -> Result<Option<Extent>> {
+        let root = self.extent_tree()?;
+        let mut found = None;
+
+        // Data extents are the largest, at most the maximum extent size
+        self.walk_tree(
+            root,
+            &BtrfsKey::new(
+                logical.saturating_sub(BTRFS_MAX_EXTENT_SIZE),
+                BTRFS_EXTENT_ITEM_KEY,
+                0,
+            ),
+            &BtrfsKey::new(logical, BTRFS_METADATA_ITEM_KEY, u64::MAX),
+            |key, data| {
+                if key.ty == BTRFS_EXTENT_ITEM_KEY || key.ty == BTRFS_METADATA_ITEM_KEY {
+                    let extent = parse_extent_item(key, data, self.node_size())?;
+                    if extent.contains(logical) {
+                        found = Some(extent);
+                    }
+                }
+                Ok(true)
+            },
+        )?;
+
+        match found {
+            Some(mut extent) => {
+                self.add_keyed_refs(root, &mut extent)?;
+                Ok(Some(extent))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The tree block starting at `bytenr`, with all its refs. Unlike
+    /// `lookup_extent` this only looks at the items of `bytenr`: tree blocks
+    /// start exactly there and are never larger than a node.
+    pub fn lookup_tree_block(&self, bytenr: u64) -> Result<Option<Extent>> {
+        let root = self.extent_tree()?;
+        let mut found = None;
+
+        // Skinny metadata items have the level as key offset
+        self.walk_tree(
+            root,
+            &BtrfsKey::new(bytenr, BTRFS_METADATA_ITEM_KEY, 0),
+            &BtrfsKey::new(bytenr, BTRFS_METADATA_ITEM_KEY, BTRFS_MAX_LEVEL as u64),
+            |key, data| {
+                found = Some(parse_extent_item(key, data, self.node_size())?);
+                Ok(false)
+            },
+        )?;
+        if found.is_none() {
+            let key = BtrfsKey::new(bytenr, BTRFS_EXTENT_ITEM_KEY, self.node_size() as u64);
+            if let Some(data) = self.search_tree(root, &key)? {
+                found = Some(parse_extent_item(&key, &data, self.node_size())?);
+            }
+        }
+
+        match found {
+            Some(mut extent) if extent.is_tree_block() => {
+                self.add_keyed_refs(root, &mut extent)?;
+                Ok(Some(extent))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Add the keyed refs following the item of `extent`
+    fn add_keyed_refs(&self, root: TreeRoot, extent: &mut Extent) -> Result<()> {
+        self.walk_tree(
+            root,
+            &BtrfsKey::new(extent.bytenr, BTRFS_TREE_BLOCK_REF_KEY, 0),
+            &BtrfsKey::new(extent.bytenr, BTRFS_SHARED_DATA_REF_KEY, u64::MAX),
+            |key, data| {
+                extent.keyed_refs.extend(parse_keyed_ref(key, data)?);
+                Ok(true)
+            },
+        )
+    }
+
+    /// Call `f` on every extent with all its refs, in address order
+    pub fn walk_extents<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(Extent) -> Result<()>,
+    {
+        let mut current: Option<Extent> = None;
+
+        // Keyed refs follow the item of their extent
+        self.walk_tree(
+            self.extent_tree()?,
+            &BtrfsKey::new(0, 0, 0),
+            &BtrfsKey::new(u64::MAX, u8::MAX, u64::MAX),
+            |key, data| {
+                match key.ty {
+                    BTRFS_EXTENT_ITEM_KEY | BTRFS_METADATA_ITEM_KEY => {
+                        let extent = parse_extent_item(key, data, self.node_size())?;
+                        if let Some(done) = current.replace(extent) {
+                            f(done)?;
+                        }
+                    }
+                    _ => {
+                        if let Some(extent) = current.as_mut() {
+                            if extent.bytenr == key.objectid {
+                                extent.keyed_refs.extend(parse_keyed_ref(key, data)?);
+                            }
+                        }
+                    }
+                }
+                Ok(true)
+            },
+        )?;
+        if let Some(done) = current {
+            f(done)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_inline_refs() {
+        let mut data = vec![];
+        for value in [3u64, 10, BTRFS_EXTENT_FLAG_DATA] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.push(BTRFS_EXTENT_OWNER_REF_KEY);
+        data.extend_from_slice(&5u64.to_le_bytes());
+        data.push(BTRFS_EXTENT_DATA_REF_KEY);
+        for value in [5u64, 257, 4096] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.push(BTRFS_SHARED_DATA_REF_KEY);
+        data.extend_from_slice(&30408704u64.to_le_bytes());
+        data.extend_from_slice(&2u32.to_le_bytes());
+
+        let key = BtrfsKey::new(1 << 20, BTRFS_EXTENT_ITEM_KEY, 8192);
+        let extent = parse_extent_item(&key, &data, 16384).unwrap();
+        assert!(extent.is_data() && extent.contains((1 << 20) + 8191));
+        assert_eq!(
+            extent.inline_refs,
+            [
+                ExtentRef::Owner { root: 5 },
+                ExtentRef::Data {
+                    root: 5,
+                    objectid: 257,
+                    offset: 4096,
+                    count: 1
+                },
+                ExtentRef::SharedData {
+                    parent: 30408704,
+                    count: 2
+                },
+            ]
+        );
+        assert_eq!(extent.all_refs().map(ExtentRef::count).sum::<u64>(), 3);
+
+        data.truncate(data.len() - 1);
+        assert!(parse_extent_item(&key, &data, 16384).is_err());
+    }
+}
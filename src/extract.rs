@@ -0,0 +1,301 @@
+use crate::fs::Fs;
+use crate::inode::{FileType, InodeId, Stat};
+use anyhow::{bail, Result};
+use glob::Pattern;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileExt};
+use std::path::{Path, PathBuf};
+
+/// Largest piece of a file read at once, uncompressed extents can be 128MiB
+const EXTRACT_CHUNK_SIZE: u64 = 1024 * 1024;
+
+#[derive(Default)]
+pub struct ExtractOptions {
+    /// Only restore the paths matching one of these, with everything below
+    /// matching directories. Everything is restored when empty.
+    pub include: Vec<Pattern>,
+    /// Skip the paths matching one of these, and everything below them
+    pub exclude: Vec<Pattern>,
+    /// Print every restored path
+    pub verbose: bool,
+}
+
+#[derive(Default, Debug)]
+pub struct ExtractStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub hard_links: u64,
+    pub special: u64,
+    /// Files that couldn't be read or written, and were skipped
+    pub errors: u64,
+}
+
+struct Extractor<'a> {
+    fs: &'a Fs,
+    options: &'a ExtractOptions,
+    stats: ExtractStats,
+    /// First restored path of every inode with several links
+    links: HashMap<InodeId, PathBuf>,
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Extractor<'_> {
+    fn matches(patterns: &[Pattern], rel: &Path) -> bool {
+        patterns.iter().any(|pattern| pattern.matches_path(rel))
+    }
+
+    /// Restore inode `id`, reached through `rel` below the extraction root, to
+    /// `dest`. `included` is set when a parent directory matched an include.
+    fn visit(&mut self, id: InodeId, rel: &Path, dest: &Path, included: bool) {
+        if Self::matches(&self.options.exclude, rel) {
+            return;
+        }
+        let included = included
+            || self.options.include.is_empty()
+            || Self::matches(&self.options.include, rel);
+
+        let stat = match self.fs.stat(id) {
+            Ok(stat) => stat,
+            Err(err) => {
+                eprintln!("Skipping {}: {:#}", rel.display(), err);
+                self.stats.errors += 1;
+                return;
+            }
+        };
+        if stat.file_type() == FileType::Directory {
+            self.visit_dir(&stat, rel, dest, included);
+            return;
+        }
+        if !included {
+            return;
+        }
+
+        if let Err(err) = self.restore(&stat, dest) {
+            eprintln!("Skipping {}: {:#}", rel.display(), err);
+            self.stats.errors += 1;
+            return;
+        }
+        if self.options.verbose {
+            println!("{}", rel.display());
+        }
+    }
+
+    fn visit_dir(&mut self, stat: &Stat, rel: &Path, dest: &Path, included: bool) {
+        // With include patterns, directories only appear when something in
+        // them is restored
+        if included {
+            if let Err(err) = fs::create_dir_all(dest) {
+                eprintln!("Skipping {}: {}", rel.display(), err);
+                self.stats.errors += 1;
+                return;
+            }
+        }
+
+        let entries = match self.fs.read_dir(stat.id) {
+            Ok(entries) => entries,
+            Err(err) => {
+                eprintln!("Skipping the content of {}: {:#}", rel.display(), err);
+                self.stats.errors += 1;
+                vec![]
+            }
+        };
+        for entry in entries {
+            let name = OsStr::from_bytes(&entry.name);
+            match self.fs.entry_target(stat.id, &entry.location, &entry.name) {
+                Ok(target) => self.visit(target, &rel.join(name), &dest.join(name), included),
+                Err(err) => {
+                    eprintln!("Skipping {}: {:#}", rel.join(name).display(), err);
+                    self.stats.errors += 1;
+                }
+            }
+        }
+
+        // Children changed the directory times, set them last. The extraction
+        // root belongs to the caller and is left alone.
+        if dest.is_dir() && !rel.as_os_str().is_empty() {
+            self.stats.dirs += 1;
+            if let Err(err) = self.set_metadata(stat, dest) {
+                eprintln!(
+                    "Couldn't restore the metadata of {}: {:#}",
+                    rel.display(),
+                    err
+                );
+            }
+            if self.options.verbose {
+                println!("{}/", rel.display());
+            }
+        }
+    }
+
+    fn restore(&mut self, stat: &Stat, dest: &Path) -> Result<()> {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if stat.nlink > 1 {
+            if let Some(first) = self.links.get(&stat.id) {
+                fs::hard_link(first, dest)?;
+                self.stats.hard_links += 1;
+                return Ok(());
+            }
+        }
+
+        match stat.file_type() {
+            FileType::Regular => {
+                self.restore_file(stat, dest)?;
+                self.stats.files += 1;
+            }
+            FileType::Symlink => {
+                symlink(OsStr::from_bytes(&self.fs.readlink(stat.id)?), dest)?;
+                self.stats.symlinks += 1;
+            }
+            FileType::CharDevice | FileType::BlockDevice | FileType::Fifo | FileType::Socket => {
+                let dev = libc::makedev(stat.rdev_major(), stat.rdev_minor());
+                let path = cstring(dest)?;
+                check(unsafe { libc::mknod(path.as_ptr(), stat.mode as libc::mode_t, dev) })?;
+                self.stats.special += 1;
+            }
+            FileType::Directory | FileType::Unknown => {
+                bail!("Unknown file type in mode {:o}", stat.mode)
+            }
+        }
+        self.set_metadata(stat, dest)?;
+
+        if stat.nlink > 1 {
+            self.links.insert(stat.id, dest.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Write the data extents of a file. Holes, prealloc extents and ranges
+    /// without extents are left unwritten so they stay sparse.
+    fn restore_file(&self, stat: &Stat, dest: &Path) -> Result<()> {
+        let file = OpenOptions::new().write(true).create_new(true).open(dest)?;
+
+        // Don't leave a truncated file behind
+        if let Err(err) = self.write_file_data(stat, &file) {
+            drop(file);
+            let _ = fs::remove_file(dest);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn write_file_data(&self, stat: &Stat, file: &fs::File) -> Result<()> {
+        let id = stat.id;
+
+        let extents = self.fs.file_extents(id, 0, stat.size)?;
+        for extent in extents.iter().filter(|e| e.has_data()) {
+            let mut offset = extent.file_offset;
+            let end = extent.end().min(stat.size);
+
+            while offset < end {
+                let len = (end - offset).min(EXTRACT_CHUNK_SIZE);
+                let data = self.fs.read(id.subvol, id.ino, offset, len)?;
+                file.write_all_at(&data, offset)?;
+                offset += len;
+            }
+        }
+        file.set_len(stat.size)?;
+        Ok(())
+    }
+
+    /// Ownership, permissions, xattrs and timestamps, in the order that keeps
+    /// each step from undoing the previous ones
+    fn set_metadata(&self, stat: &Stat, dest: &Path) -> Result<()> {
+        let path = cstring(dest)?;
+        let is_symlink = stat.file_type() == FileType::Symlink;
+
+        // Changing the owner is only allowed to root, keep ours otherwise
+        if let Err(err) = check(unsafe { libc::lchown(path.as_ptr(), stat.uid, stat.gid) }) {
+            if err.raw_os_error() != Some(libc::EPERM) {
+                return Err(err.into());
+            }
+        }
+        // chown clears setuid and setgid, and symlinks have no mode of their own
+        if !is_symlink {
+            check(unsafe { libc::chmod(path.as_ptr(), stat.permissions() as libc::mode_t) })?;
+        }
+
+        for (name, value) in self.fs.xattrs(stat.id)? {
+            let cname = CString::new(name.clone())?;
+            let ret = unsafe {
+                libc::lsetxattr(
+                    path.as_ptr(),
+                    cname.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                )
+            };
+            // Some namespaces need privileges or aren't supported by the target
+            if let Err(err) = check(ret) {
+                eprintln!(
+                    "Couldn't set xattr {} on {}: {}",
+                    String::from_utf8_lossy(&name),
+                    dest.display(),
+                    err
+                );
+            }
+        }
+
+        let times = [stat.atime, stat.mtime].map(|t| libc::timespec {
+            tv_sec: t.seconds() as libc::time_t,
+            tv_nsec: t.nanoseconds() as libc::c_long,
+        });
+        check(unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+impl Fs {
+    /// Restore the tree below inode `root` into directory `dest`, like `btrfs
+    /// restore`, or to path `dest` when `root` isn't a directory. Nested
+    /// subvolumes are followed. Files that can't be read or
+    /// written are reported on stderr and counted in `ExtractStats::errors`.
+    pub fn extract(
+        &self,
+        root: InodeId,
+        dest: &Path,
+        options: &ExtractOptions,
+    ) -> Result<ExtractStats> {
+        let mut extractor = Extractor {
+            fs: self,
+            options,
+            stats: ExtractStats::default(),
+            links: HashMap::new(),
+        };
+
+        let stat = self.stat(root)?;
+        if stat.file_type() == FileType::Directory {
+            fs::create_dir_all(dest)?;
+            extractor.visit_dir(&stat, Path::new(""), dest, false);
+        } else {
+            let name = dest.file_name().map(PathBuf::from).unwrap_or_default();
+            extractor.visit(root, &name, dest, false);
+        }
+        Ok(extractor.stats)
+    }
+}
@@ -0,0 +1,301 @@
+use crate::compression::decompress;
+use crate::ctree::struct_at;
+use crate::fs::Fs;
+use crate::inode::{FileType, InodeId};
+use crate::structs::*;
+use anyhow::{bail, Context, Result};
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Size of the `BtrfsFileExtentItem` fields an inline extent stores before
+/// its data
+const INLINE_HEADER_SIZE: usize = std::mem::offset_of!(BtrfsFileExtentItem, disk_bytenr);
+
+/// One `EXTENT_DATA` item, mapping `[file_offset, file_offset + len)` of a file
+#[derive(Debug, Clone)]
+pub struct FileExtent {
+    pub file_offset: u64,
+    /// `BTRFS_FILE_EXTENT_*` type
+    pub ty: u8,
+    /// `BTRFS_COMPRESS_*` algorithm
+    pub compression: u8,
+    pub ram_bytes: u64,
+    pub disk_bytenr: u64,
+    pub disk_num_bytes: u64,
+    /// Offset of the file data in the decompressed extent
+    pub offset: u64,
+    /// Number of file bytes covered, the decompressed size for inline extents
+    pub num_bytes: u64,
+    /// Raw data of an inline extent, compressed as stored
+    pub inline_data: Vec<u8>,
+}
+
+impl FileExtent {
+    /// Regular extents without a disk location are holes
+    pub fn is_hole(&self) -> bool {
+        self.ty == BTRFS_FILE_EXTENT_REG && self.disk_bytenr == 0
+    }
+
+    /// Whether the extent holds data, as opposed to reading back as zeros
+    pub fn has_data(&self) -> bool {
+        !self.is_hole() && self.ty != BTRFS_FILE_EXTENT_PREALLOC
+    }
+
+    pub fn end(&self) -> u64 {
+        self.file_offset + self.num_bytes
+    }
+}
+
+pub fn parse_file_extent(file_offset: u64, data: &[u8]) -> Result<FileExtent> {
+    if data.len() < INLINE_HEADER_SIZE {
+        bail!("Truncated file extent item at file offset {}", file_offset);
+    }
+    let mut item = [0; std::mem::size_of::<BtrfsFileExtentItem>()];
+    item[..INLINE_HEADER_SIZE].copy_from_slice(&data[..INLINE_HEADER_SIZE]);
+    let ty = data[INLINE_HEADER_SIZE - 1];
+
+    if ty == BTRFS_FILE_EXTENT_INLINE {
+        let item = struct_at::<BtrfsFileExtentItem>(&item, 0)?;
+        return Ok(FileExtent {
+            file_offset,
+            ty,
+            compression: item.compression,
+            ram_bytes: item.ram_bytes,
+            disk_bytenr: 0,
+            disk_num_bytes: 0,
+            offset: 0,
+            num_bytes: item.ram_bytes,
+            inline_data: data[INLINE_HEADER_SIZE..].to_vec(),
+        });
+    }
+
+    let item = struct_at::<BtrfsFileExtentItem>(data, 0)?;
+    if ty != BTRFS_FILE_EXTENT_REG && ty != BTRFS_FILE_EXTENT_PREALLOC {
+        bail!(
+            "Unknown file extent type {} at file offset {}",
+            ty,
+            file_offset
+        );
+    }
+    Ok(FileExtent {
+        file_offset,
+        ty,
+        compression: item.compression,
+        ram_bytes: item.ram_bytes,
+        disk_bytenr: item.disk_bytenr,
+        disk_num_bytes: item.disk_num_bytes,
+        offset: item.offset,
+        num_bytes: item.num_bytes,
+        inline_data: vec![],
+    })
+}
+
+impl Fs {
+    /// Every extent of inode `id` overlapping `[start, end)`, in file order
+    pub fn file_extents(&self, id: InodeId, start: u64, end: u64) -> Result<Vec<FileExtent>> {
+        let mut extents = vec![];
+
+        if start >= end {
+            return Ok(extents);
+        }
+        // No extent is longer than the maximum extent size, so the one holding
+        // `start` begins at most that far before it
+        self.walk_tree(
+            self.subvolume_root(id.subvol)?,
+            &BtrfsKey::new(
+                id.ino,
+                BTRFS_EXTENT_DATA_KEY,
+                start.saturating_sub(BTRFS_MAX_EXTENT_SIZE),
+            ),
+            &BtrfsKey::new(id.ino, BTRFS_EXTENT_DATA_KEY, end - 1),
+            |key, data| {
+                let extent = parse_file_extent(key.offset, data)?;
+                if extent.end() > start {
+                    extents.push(extent);
+                }
+                Ok(true)
+            },
+        )?;
+        Ok(extents)
+    }
+
+    /// Read up to `len` bytes of inode `ino` of subvolume `subvol` starting
+    /// at `offset`. The result stops at the inode size, ranges without an
+    /// extent (holes under NO_HOLES) and prealloc extents read as zeros.
+    ///
+    /// With `verify_csums` set, data on disk is checked against the csum tree
+    /// unless the inode is NODATASUM. Inline extents live in checksummed tree
+    /// blocks and have no data checksums.
+    pub fn read(&self, subvol: u64, ino: u64, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let id = InodeId::new(subvol, ino);
+        let stat = self.stat(id)?;
+        let end = offset.saturating_add(len).min(stat.size);
+        let verify = self.verify_csums && stat.flags & BTRFS_INODE_NODATASUM == 0;
+        let read_data = |logical: u64, buf: &mut [u8], file_offset: u64| {
+            if verify {
+                self.read_logical_verified(logical, buf)
+                    .with_context(|| format!("Can't read {} at file offset {}", id, file_offset))
+            } else {
+                self.read_logical(logical, buf)
+            }
+        };
+
+        if offset >= end {
+            return Ok(vec![]);
+        }
+        let mut buf = vec![0; (end - offset) as usize];
+
+        for extent in self.file_extents(id, offset, end)? {
+            let from = extent.file_offset.max(offset);
+            let to = extent.end().min(end);
+            if from >= to || extent.is_hole() || extent.ty == BTRFS_FILE_EXTENT_PREALLOC {
+                continue;
+            }
+            let out = &mut buf[(from - offset) as usize..(to - offset) as usize];
+
+            let decompress = |data: &[u8]| {
+                decompress(
+                    extent.compression,
+                    data,
+                    extent.ram_bytes,
+                    self.superblock.sector_size,
+                )
+                .with_context(|| {
+                    format!(
+                        "Can't decompress {} at file offset {}",
+                        id, extent.file_offset
+                    )
+                })
+            };
+
+            // Sizes come from disk, check them before allocating buffers
+            let max_size = match extent.compression {
+                BTRFS_COMPRESS_NONE => BTRFS_MAX_EXTENT_SIZE,
+                _ => BTRFS_MAX_COMPRESSED,
+            };
+            if extent.ty != BTRFS_FILE_EXTENT_INLINE && extent.disk_num_bytes > max_size {
+                bail!(
+                    "{}: extent at file offset {} is {} bytes on disk, more than {}",
+                    id,
+                    extent.file_offset,
+                    extent.disk_num_bytes,
+                    max_size
+                );
+            }
+
+            if extent.ty == BTRFS_FILE_EXTENT_INLINE {
+                let data = decompress(&extent.inline_data)?;
+                let start = (from - extent.file_offset) as usize;
+                out.copy_from_slice(&data[start..start + out.len()]);
+            } else if extent.compression == BTRFS_COMPRESS_NONE {
+                let logical = extent.disk_bytenr + extent.offset + (from - extent.file_offset);
+                read_data(logical, out, from)?;
+            } else {
+                // Compressed extents are decompressed as a whole, and their
+                // checksums cover the compressed data
+                let mut disk = vec![0; extent.disk_num_bytes as usize];
+                read_data(extent.disk_bytenr, &mut disk, extent.file_offset)?;
+                let data = decompress(&disk)?;
+                let start = (extent.offset + from - extent.file_offset) as usize;
+                match data.get(start..start + out.len()) {
+                    Some(data) => out.copy_from_slice(data),
+                    None => bail!(
+                        "Extent at file offset {} is past its data",
+                        extent.file_offset
+                    ),
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Target of a symlink, stored as the data of an inline extent
+    pub fn readlink(&self, id: InodeId) -> Result<Vec<u8>> {
+        let stat = self.stat(id)?;
+
+        if stat.file_type() != FileType::Symlink {
+            bail!("{}: Not a symbolic link", id);
+        }
+        self.read(id.subvol, id.ino, 0, stat.size)
+    }
+
+    /// Open a file for reading through `std::io::Read` and `Seek`
+    pub fn open_file(&self, id: InodeId) -> Result<FileHandle<'_>> {
+        Ok(FileHandle {
+            fs: self,
+            id,
+            size: self.stat(id)?.size,
+            pos: 0,
+        })
+    }
+}
+
+/// A file opened with `Fs::open_file`
+pub struct FileHandle<'a> {
+    fs: &'a Fs,
+    id: InodeId,
+    size: u64,
+    pos: u64,
+}
+
+impl FileHandle<'_> {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for FileHandle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self
+            .fs
+            .read(self.id.subvol, self.id.ino, self.pos, buf.len() as u64)
+            .map_err(io::Error::other)?;
+
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+impl Seek for FileHandle<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_inline_and_regular_extents() {
+        let mut inline = vec![0; INLINE_HEADER_SIZE];
+        inline[8..16].copy_from_slice(&4u64.to_le_bytes());
+        inline.extend_from_slice(b"heh\n");
+        let extent = parse_file_extent(0, &inline).unwrap();
+        assert_eq!(extent.ty, BTRFS_FILE_EXTENT_INLINE);
+        assert_eq!((extent.end(), &extent.inline_data[..]), (4, &b"heh\n"[..]));
+
+        let mut reg = vec![0; std::mem::size_of::<BtrfsFileExtentItem>()];
+        reg[INLINE_HEADER_SIZE - 1] = BTRFS_FILE_EXTENT_REG;
+        reg[INLINE_HEADER_SIZE + 24..].copy_from_slice(&8192u64.to_le_bytes());
+        let extent = parse_file_extent(4096, &reg).unwrap();
+        assert!(extent.is_hole());
+        assert_eq!(extent.end(), 4096 + 8192);
+
+        assert!(parse_file_extent(0, &reg[..10]).is_err());
+    }
+}
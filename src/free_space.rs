@@ -0,0 +1,720 @@
+//! Free space caches: which parts of every block group are free, as recorded
+//! by the free space tree or, on older filesystems, by the v1 space cache.
+//!
+//! The free space tree has a `FREE_SPACE_INFO` item per block group followed
+//! by `FREE_SPACE_EXTENT` items or, for fragmented block groups, by
+//! `FREE_SPACE_BITMAP` items. The v1 cache keeps the free space of a block
+//! group in a file of the root tree, found through a header item keyed by
+//! the block group start. Both are caches of what the extent tree says, and
+//! a cache out of sync with it makes the allocator hand out used space or
+//! never find free space.
+
+use crate::block_group::BlockGroup;
+use crate::ctree::{struct_at, TreeRoot};
+use crate::file::parse_file_extent;
+use crate::fs::Fs;
+use crate::structs::*;
+use crate::volumes::Chunk;
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// Page size of most kernels writing v1 cache files, every page starts where
+/// a page of it would
+const SPACE_CACHE_PAGE_SIZE: usize = 4096;
+/// Page sizes of other Linux ports, told apart by the first page checksum
+const OTHER_PAGE_SIZES: [usize; 3] = [8192, 16384, 65536];
+const BTRFS_SUPER_MIRROR_MAX: u32 = 3;
+const BTRFS_STRIPE_LEN: u64 = 64 * 1024;
+
+/// Physical offset of superblock copy `mirror`
+fn super_offset(mirror: u32) -> u64 {
+    match mirror {
+        0 => BTRFS_SUPERBLOCK_OFFSET,
+        _ => 0x4000 << (12 * mirror),
+    }
+}
+
+/// Ranges of a chunk holding superblock copies, never free nor used by
+/// extents. Like the kernel, whole stripes around the copies are excluded.
+pub fn super_stripes(chunk: &Chunk) -> Vec<(u64, u64)> {
+    let mut excluded = vec![];
+
+    if chunk.logical < BTRFS_SUPERBLOCK_OFFSET {
+        excluded.push((chunk.logical, BTRFS_SUPERBLOCK_OFFSET - chunk.logical));
+    }
+    let num_stripes = chunk.stripes.len() as u64;
+    let parity = chunk.ty & (BTRFS_BLOCK_GROUP_RAID5 | BTRFS_BLOCK_GROUP_RAID6) != 0;
+    let io_stripe_size = if parity {
+        chunk.data_stripes() * BTRFS_STRIPE_LEN
+    } else {
+        BTRFS_STRIPE_LEN
+    };
+    for mirror in 0..BTRFS_SUPER_MIRROR_MAX {
+        let physical = super_offset(mirror);
+        for (i, (_, offset)) in chunk.stripes.iter().enumerate() {
+            if physical < *offset || physical - offset >= chunk.stripe_length() {
+                continue;
+            }
+            let mut stripe_nr = (physical - offset) / BTRFS_STRIPE_LEN;
+            if chunk.ty & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+                stripe_nr = (stripe_nr * num_stripes + i as u64) / chunk.sub_stripes.max(1) as u64;
+            } else if chunk.ty & BTRFS_BLOCK_GROUP_RAID0 != 0 {
+                stripe_nr = stripe_nr * num_stripes + i as u64;
+            }
+            let start = chunk.logical + stripe_nr * io_stripe_size;
+            let end = (start + io_stripe_size).min(chunk.logical + chunk.length);
+            excluded.push((start, end - start));
+        }
+    }
+    merge_ranges(excluded)
+}
+
+/// Sort (start, length) ranges and join the ones touching or overlapping
+fn merge_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    let mut merged: Vec<(u64, u64)> = vec![];
+
+    ranges.sort_unstable();
+    for (start, length) in ranges {
+        match merged.last_mut() {
+            Some((last, last_length)) if start <= *last + *last_length => {
+                *last_length = (*last_length).max(start + length - *last);
+            }
+            _ => merged.push((start, length)),
+        }
+    }
+    merged
+}
+
+/// Whether merged `ranges` hold address `at`
+fn covers(ranges: &[(u64, u64)], at: u64) -> bool {
+    let i = ranges.partition_point(|(start, _)| *start <= at);
+    i > 0 && at - ranges[i - 1].0 < ranges[i - 1].1
+}
+
+/// Free (start, length) runs of a bitmap whose bit `i` stands for the sector
+/// at `start + i * sector_size`
+fn bitmap_ranges(bitmap: &[u8], start: u64, sector_size: u64, nbits: u64) -> Vec<(u64, u64)> {
+    let mut ranges = vec![];
+
+    for bit in 0..nbits.min(bitmap.len() as u64 * 8) {
+        if bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0 {
+            ranges.push((start + bit * sector_size, sector_size));
+        }
+    }
+    merge_ranges(ranges)
+}
+
+/// Where the free space of a block group was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeSpaceSource {
+    Tree,
+    CacheV1,
+}
+
+impl fmt::Display for FreeSpaceSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FreeSpaceSource::Tree => write!(f, "free space tree"),
+            FreeSpaceSource::CacheV1 => write!(f, "space cache v1"),
+        }
+    }
+}
+
+/// Free space of a block group as its cache records it
+#[derive(Debug, Clone)]
+pub struct FreeSpace {
+    pub source: FreeSpaceSource,
+    /// (start, length) of every free extent, merged and in address order
+    pub extents: Vec<(u64, u64)>,
+    /// Number of free extents according to the free space tree
+    pub extent_count: Option<u64>,
+    pub bitmaps: bool,
+}
+
+impl FreeSpace {
+    pub fn total(&self) -> u64 {
+        self.extents.iter().map(|(_, length)| length).sum()
+    }
+}
+
+/// A disagreement between the free space cache of a block group and its
+/// extents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FreeSpaceProblem {
+    /// Free according to the cache, but used by an extent or a superblock
+    FreeButUsed {
+        start: u64,
+        length: u64,
+    },
+    /// Neither free nor used, the allocator will never hand it out
+    Leaked {
+        start: u64,
+        length: u64,
+    },
+    ExtentCount {
+        recorded: u64,
+        found: u64,
+    },
+    /// The used bytes of the block group item don't add up its extents
+    BlockGroupUsed {
+        recorded: u64,
+        found: u64,
+    },
+}
+
+impl fmt::Display for FreeSpaceProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FreeSpaceProblem::FreeButUsed { start, length } => {
+                write!(f, "free space {} len {} is in use", start, length)
+            }
+            FreeSpaceProblem::Leaked { start, length } => {
+                write!(f, "space {} len {} is neither free nor used", start, length)
+            }
+            FreeSpaceProblem::ExtentCount { recorded, found } => write!(
+                f,
+                "free space info counts {} extents, found {}",
+                recorded, found
+            ),
+            FreeSpaceProblem::BlockGroupUsed { recorded, found } => write!(
+                f,
+                "block group item says {} bytes used, its extents take {}",
+                recorded, found
+            ),
+        }
+    }
+}
+
+/// Compare the free space of a block group with the ranges its extents and
+/// superblock copies take
+pub fn check_free_space(
+    block_group: &BlockGroup,
+    free: &FreeSpace,
+    used: &[(u64, u64)],
+    excluded: &[(u64, u64)],
+) -> Vec<FreeSpaceProblem> {
+    let end = block_group.start + block_group.length;
+    let used = merge_ranges(used.to_vec());
+    let excluded = merge_ranges(excluded.to_vec());
+    let mut problems = vec![];
+
+    let mut bounds = vec![block_group.start, end];
+    for (start, length) in free.extents.iter().chain(&used).chain(&excluded) {
+        bounds.push((*start).clamp(block_group.start, end));
+        bounds.push((start + length).clamp(block_group.start, end));
+    }
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    // Runs of the same problem are reported once
+    let mut current: Option<(bool, u64, u64)> = None;
+    for pair in bounds.windows(2) {
+        let (start, next) = (pair[0], pair[1]);
+        let is_free = covers(&free.extents, start);
+        let is_taken = covers(&used, start) || covers(&excluded, start);
+        let bad = match (is_free, is_taken) {
+            (true, true) => Some(true),
+            (false, false) => Some(false),
+            _ => None,
+        };
+        current = match (current, bad) {
+            (Some((kind, run_start, run_end)), Some(bad_kind))
+                if kind == bad_kind && run_end == start =>
+            {
+                Some((kind, run_start, next))
+            }
+            (run, bad) => {
+                problems.extend(run.map(problem_run));
+                bad.map(|kind| (kind, start, next))
+            }
+        };
+    }
+    problems.extend(current.map(problem_run));
+
+    if let Some(recorded) = free.extent_count {
+        if recorded != free.extents.len() as u64 {
+            problems.push(FreeSpaceProblem::ExtentCount {
+                recorded,
+                found: free.extents.len() as u64,
+            });
+        }
+    }
+    let found = used.iter().map(|(_, length)| length).sum();
+    if found != block_group.used {
+        problems.push(FreeSpaceProblem::BlockGroupUsed {
+            recorded: block_group.used,
+            found,
+        });
+    }
+    problems
+}
+
+fn problem_run((free_but_used, start, end): (bool, u64, u64)) -> FreeSpaceProblem {
+    let length = end - start;
+    if free_but_used {
+        FreeSpaceProblem::FreeButUsed { start, length }
+    } else {
+        FreeSpaceProblem::Leaked { start, length }
+    }
+}
+
+impl Fs {
+    /// Free space of a block group from whichever cache the filesystem uses,
+    /// `None` when the kernel would ignore the cache and rebuild it
+    pub fn free_space(&self, block_group: &BlockGroup) -> Result<Option<FreeSpace>> {
+        let compat_ro = self.superblock.compat_ro_flags;
+
+        if compat_ro & BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE != 0 {
+            if compat_ro & BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID == 0 {
+                bail!("The free space tree is not valid");
+            }
+            return Ok(Some(self.free_space_from_tree(block_group)?));
+        }
+        if self.superblock.cache_generation != self.superblock.generation {
+            return Ok(None);
+        }
+        self.free_space_from_cache(block_group)
+    }
+
+    fn free_space_from_tree(&self, block_group: &BlockGroup) -> Result<FreeSpace> {
+        let root = match self.read_root_item(BTRFS_FREE_SPACE_TREE_OBJECTID)? {
+            Some(root_item) => TreeRoot::from(&root_item),
+            None => bail!("The filesystem has no free space tree"),
+        };
+        let sector_size = self.superblock.sector_size as u64;
+        let mut info = None;
+        let mut extents = vec![];
+
+        self.walk_tree(
+            root,
+            &BtrfsKey::new(
+                block_group.start,
+                BTRFS_FREE_SPACE_INFO_KEY,
+                block_group.length,
+            ),
+            &BtrfsKey::new(
+                block_group.start + block_group.length - 1,
+                BTRFS_FREE_SPACE_BITMAP_KEY,
+                u64::MAX,
+            ),
+            |key, data| {
+                match key.ty {
+                    BTRFS_FREE_SPACE_INFO_KEY if key.objectid == block_group.start => {
+                        info = Some(*struct_at::<BtrfsFreeSpaceInfo>(data, 0)?)
+                    }
+                    BTRFS_FREE_SPACE_EXTENT_KEY => extents.push((key.objectid, key.offset)),
+                    BTRFS_FREE_SPACE_BITMAP_KEY => extents.extend(bitmap_ranges(
+                        data,
+                        key.objectid,
+                        sector_size,
+                        key.offset / sector_size,
+                    )),
+                    _ => {}
+                }
+                Ok(true)
+            },
+        )?;
+
+        let info = match info {
+            Some(info) => info,
+            None => bail!(
+                "Block group {} has no free space info item",
+                block_group.start
+            ),
+        };
+        Ok(FreeSpace {
+            source: FreeSpaceSource::Tree,
+            extents: merge_ranges(extents),
+            extent_count: Some(info.extent_count as u64),
+            bitmaps: info.flags & BTRFS_FREE_SPACE_USING_BITMAPS != 0,
+        })
+    }
+
+    /// Read a v1 cache file. Caches the kernel discards, those missing,
+    /// empty or from another generation, are `None`, corrupted ones errors.
+    fn free_space_from_cache(&self, block_group: &BlockGroup) -> Result<Option<FreeSpace>> {
+        let root = self.root_tree();
+        let header = match self.search_tree(
+            root,
+            &BtrfsKey::new(BTRFS_FREE_SPACE_OBJECTID, 0, block_group.start),
+        )? {
+            Some(data) => *struct_at::<BtrfsFreeSpaceHeader>(&data, 0)?,
+            None => return Ok(None),
+        };
+        let (location, generation) = (header.location, header.generation);
+        if header.num_entries == 0 {
+            return Ok(None);
+        }
+        let inode = match self.search_tree(root, &location)? {
+            Some(data) => *struct_at::<BtrfsInodeItem>(&data, 0)?,
+            None => return Ok(None),
+        };
+        if inode.generation != generation || inode.size == 0 {
+            return Ok(None);
+        }
+
+        let mut file = vec![0; inode.size as usize];
+        self.walk_tree(
+            root,
+            &BtrfsKey::new(location.objectid, BTRFS_EXTENT_DATA_KEY, 0),
+            &BtrfsKey::new(location.objectid, BTRFS_EXTENT_DATA_KEY, u64::MAX),
+            |key, data| {
+                let extent = parse_file_extent(key.offset, data)?;
+                if extent.ty != BTRFS_FILE_EXTENT_REG || extent.is_hole() {
+                    return Ok(true);
+                }
+                let start = (extent.file_offset as usize).min(file.len());
+                let end = (extent.end() as usize).min(file.len());
+                self.read_logical(extent.disk_bytenr + extent.offset, &mut file[start..end])?;
+                Ok(true)
+            },
+        )?;
+
+        let cache = SpaceCacheFile::new(&file, block_group.start)?;
+        let extents = cache.read(
+            generation,
+            header.num_entries,
+            header.num_bitmaps,
+            self.superblock.sector_size as u64,
+        )?;
+        Ok(Some(FreeSpace {
+            source: FreeSpaceSource::CacheV1,
+            extents,
+            extent_count: None,
+            bitmaps: header.num_bitmaps != 0,
+        }))
+    }
+
+    /// (start, length) of every extent of the block group, data or tree block
+    pub fn block_group_extents(&self, block_group: &BlockGroup) -> Result<Vec<(u64, u64)>> {
+        let node_size = self.node_size() as u64;
+        let mut extents = vec![];
+
+        self.walk_tree(
+            self.extent_tree()?,
+            &BtrfsKey::new(block_group.start, BTRFS_EXTENT_ITEM_KEY, 0),
+            &BtrfsKey::new(
+                block_group.start + block_group.length - 1,
+                BTRFS_METADATA_ITEM_KEY,
+                u64::MAX,
+            ),
+            |key, _| {
+                match key.ty {
+                    BTRFS_EXTENT_ITEM_KEY => extents.push((key.objectid, key.offset)),
+                    BTRFS_METADATA_ITEM_KEY => extents.push((key.objectid, node_size)),
+                    _ => {}
+                }
+                Ok(true)
+            },
+        )?;
+        Ok(extents)
+    }
+}
+
+/// A v1 cache file: the checksums of every page and the generation, entries
+/// never straddling a page, then one page per bitmap. Cache files are
+/// NODATASUM, the pages carry their own checksums.
+struct SpaceCacheFile<'a> {
+    data: &'a [u8],
+    block_group: u64,
+    /// Page size of the kernel that wrote the file
+    page_size: usize,
+}
+
+/// Whether page `index` of a cache file cut in `page_size` pages matches
+/// its checksum
+fn page_checksum_ok(data: &[u8], page_size: usize, index: usize) -> Result<bool> {
+    let num_pages = data.len().div_ceil(page_size);
+    let start = index * page_size;
+    if start >= data.len() || num_pages * 4 > data.len().min(page_size) {
+        return Ok(false);
+    }
+    let page = &data[start..(start + page_size).min(data.len())];
+
+    // The checksums of the first page skip the checksums themselves
+    let skip = if index == 0 { num_pages * 4 } else { 0 };
+    let mut padded = page.to_vec();
+    padded.resize(page_size, 0);
+    let expected = u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into()?);
+    Ok(crc32c::crc32c(&padded[skip..]) == expected)
+}
+
+impl<'a> SpaceCacheFile<'a> {
+    fn new(data: &'a [u8], block_group: u64) -> Result<Self> {
+        // The file is cut in pages of the kernel that wrote it, and only the
+        // right page size makes the first checksum match. Without a match
+        // the first page is reported as corrupt when read.
+        let mut page_size = SPACE_CACHE_PAGE_SIZE;
+        if !page_checksum_ok(data, page_size, 0)? {
+            for other in OTHER_PAGE_SIZES {
+                if data.len().is_multiple_of(other) && page_checksum_ok(data, other, 0)? {
+                    page_size = other;
+                    break;
+                }
+            }
+        }
+
+        let cache = SpaceCacheFile {
+            data,
+            block_group,
+            page_size,
+        };
+        if data.len() < cache.num_pages() * 4 + 8 {
+            bail!("Space cache of block group {} is truncated", block_group);
+        }
+        Ok(cache)
+    }
+
+    fn num_pages(&self) -> usize {
+        self.data.len().div_ceil(self.page_size)
+    }
+
+    /// Page `index`, checked against its checksum
+    fn page(&self, index: usize) -> Result<&'a [u8]> {
+        let start = index * self.page_size;
+        if start >= self.data.len() {
+            bail!(
+                "Space cache of block group {} ends before page {}",
+                self.block_group,
+                index
+            );
+        }
+        if !page_checksum_ok(self.data, self.page_size, index)? {
+            bail!(
+                "Space cache of block group {} has a bad checksum in page {}",
+                self.block_group,
+                index
+            );
+        }
+        Ok(&self.data[start..(start + self.page_size).min(self.data.len())])
+    }
+
+    fn read(
+        &self,
+        generation: u64,
+        num_entries: u64,
+        num_bitmaps: u64,
+        sector_size: u64,
+    ) -> Result<Vec<(u64, u64)>> {
+        let entry_size = std::mem::size_of::<BtrfsFreeSpaceEntry>();
+        let mut offset = self.num_pages() * 4;
+        let mut page_index = 0;
+        let mut page = self.page(0)?;
+
+        let cache_generation = u64::from_le_bytes(page[offset..offset + 8].try_into()?);
+        if cache_generation != generation {
+            bail!(
+                "Space cache of block group {} is from generation {}, not {}",
+                self.block_group,
+                cache_generation,
+                generation
+            );
+        }
+        offset += 8;
+
+        let mut extents = vec![];
+        let mut bitmaps = vec![];
+        for _ in 0..num_entries {
+            if offset + entry_size > page.len() {
+                page_index += 1;
+                page = self.page(page_index)?;
+                offset = 0;
+            }
+            let entry = *struct_at::<BtrfsFreeSpaceEntry>(page, offset)?;
+            offset += entry_size;
+            match entry.ty {
+                BTRFS_FREE_SPACE_EXTENT => extents.push((entry.offset, entry.bytes)),
+                BTRFS_FREE_SPACE_BITMAP => bitmaps.push(entry.offset),
+                ty => bail!(
+                    "Space cache of block group {} has an entry of unknown type {}",
+                    self.block_group,
+                    ty
+                ),
+            }
+        }
+        if bitmaps.len() as u64 != num_bitmaps {
+            bail!(
+                "Space cache of block group {} has {} bitmaps, its header {}",
+                self.block_group,
+                bitmaps.len(),
+                num_bitmaps
+            );
+        }
+
+        // Bitmaps start on the page after the last entry, and fill a page
+        for start in bitmaps {
+            page_index += 1;
+            let bitmap = self.page(page_index)?;
+            extents.extend(bitmap_ranges(
+                bitmap,
+                start,
+                sector_size,
+                self.page_size as u64 * 8,
+            ));
+        }
+        Ok(merge_ranges(extents))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A v1 cache file of `page_size` pages holding `entries`, then one
+    /// page per bitmap
+    fn cache_file(
+        page_size: usize,
+        generation: u64,
+        entries: &[(u64, u64, u8)],
+        bitmaps: &[&[u8]],
+    ) -> Vec<u8> {
+        let num_pages = 1 + bitmaps.len();
+        let mut data = vec![0; num_pages * page_size];
+        let mut offset = num_pages * 4;
+
+        data[offset..offset + 8].copy_from_slice(&generation.to_le_bytes());
+        offset += 8;
+        for (start, bytes, ty) in entries {
+            data[offset..offset + 8].copy_from_slice(&start.to_le_bytes());
+            data[offset + 8..offset + 16].copy_from_slice(&bytes.to_le_bytes());
+            data[offset + 16] = *ty;
+            offset += std::mem::size_of::<BtrfsFreeSpaceEntry>();
+        }
+        for (i, bitmap) in bitmaps.iter().enumerate() {
+            let start = (i + 1) * page_size;
+            data[start..start + bitmap.len()].copy_from_slice(bitmap);
+        }
+        for index in 0..num_pages {
+            let skip = if index == 0 { num_pages * 4 } else { 0 };
+            let crc = crc32c::crc32c(&data[index * page_size + skip..(index + 1) * page_size]);
+            data[index * 4..index * 4 + 4].copy_from_slice(&crc.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn decodes_bitmaps() {
+        // A bitmap of 9 sectors from 16K, with sectors 4 to 8 free
+        let bitmap = [0b1111_0000, 0b0000_0001];
+        let mut extents = bitmap_ranges(&bitmap, (1 << 20) + 16384, 4096, 9);
+        assert_eq!(extents, [((1 << 20) + 32768, 20480)]);
+
+        // Adjacent ranges are merged
+        extents.push(((1 << 20) + 53248, 4096));
+        extents.push((1 << 20, 4096));
+        assert_eq!(
+            merge_ranges(extents),
+            [(1 << 20, 4096), ((1 << 20) + 32768, 24576)]
+        );
+    }
+
+    #[test]
+    fn finds_free_space_disagreements() {
+        let block_group = BlockGroup {
+            start: 1 << 20,
+            length: 1 << 20,
+            used: 12288,
+            flags: BTRFS_BLOCK_GROUP_DATA,
+        };
+        let free = FreeSpace {
+            source: FreeSpaceSource::Tree,
+            extents: vec![
+                ((1 << 20) + 32768, 20480),
+                ((1 << 20) + 65536, (1 << 20) - 65536),
+            ],
+            extent_count: Some(3),
+            bitmaps: true,
+        };
+
+        // [52K, 56K) is leaked, the last sector both free and used
+        let used = [
+            ((1 << 20) + 57344, 8192),
+            (1 << 20, 32768),
+            ((2 << 20) - 4096, 4096),
+        ];
+        assert_eq!(
+            check_free_space(&block_group, &free, &used, &[]),
+            [
+                FreeSpaceProblem::Leaked {
+                    start: (1 << 20) + 53248,
+                    length: 4096
+                },
+                FreeSpaceProblem::FreeButUsed {
+                    start: (2 << 20) - 4096,
+                    length: 4096
+                },
+                FreeSpaceProblem::ExtentCount {
+                    recorded: 3,
+                    found: 2
+                },
+                FreeSpaceProblem::BlockGroupUsed {
+                    recorded: 12288,
+                    found: 45056
+                },
+            ]
+        );
+        assert!(
+            check_free_space(&block_group, &free, &used, &[((1 << 20) + 53248, 4096)])
+                .iter()
+                .all(|problem| !matches!(problem, FreeSpaceProblem::Leaked { .. }))
+        );
+    }
+
+    #[test]
+    fn excludes_super_stripes() {
+        let chunk = Chunk {
+            logical: 64 << 20,
+            length: 1 << 30,
+            ty: BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP,
+            sub_stripes: 1,
+            stripes: vec![(1, 32 << 20), (1, 2 << 30)],
+        };
+        assert_eq!(super_stripes(&chunk), [((96 << 20), BTRFS_STRIPE_LEN)]);
+    }
+
+    #[test]
+    fn reads_v1_cache_files() {
+        let entries = [
+            ((1 << 20) + 8192, 4096, BTRFS_FREE_SPACE_EXTENT),
+            (2 << 20, 0, BTRFS_FREE_SPACE_BITMAP),
+        ];
+        let data = cache_file(SPACE_CACHE_PAGE_SIZE, 7, &entries, &[&[0b0000_0110]]);
+        let cache = SpaceCacheFile::new(&data, 1 << 20).unwrap();
+        assert_eq!(
+            cache.read(7, 2, 1, 4096).unwrap(),
+            [((1 << 20) + 8192, 4096), ((2 << 20) + 4096, 8192)]
+        );
+        assert!(cache.read(8, 2, 1, 4096).is_err());
+
+        let mut corrupted = data.clone();
+        corrupted[SPACE_CACHE_PAGE_SIZE] ^= 1;
+        let cache = SpaceCacheFile::new(&corrupted, 1 << 20).unwrap();
+        assert!(cache.read(7, 2, 1, 4096).is_err());
+    }
+
+    #[test]
+    fn reads_caches_of_other_page_sizes() {
+        let entries = [
+            ((1 << 20) + 8192, 4096, BTRFS_FREE_SPACE_EXTENT),
+            (2 << 20, 0, BTRFS_FREE_SPACE_BITMAP),
+        ];
+        // The bitmap page covers 64K * 8 sectors, the last one is free
+        let mut bitmap = vec![0; 65536];
+        bitmap[65535] = 0b1000_0000;
+        let data = cache_file(65536, 7, &entries, &[&bitmap]);
+        let cache = SpaceCacheFile::new(&data, 1 << 20).unwrap();
+        assert_eq!(cache.page_size, 65536);
+        assert_eq!(
+            cache.read(7, 2, 1, 4096).unwrap(),
+            [
+                ((1 << 20) + 8192, 4096),
+                ((2 << 20) + (65536 * 8 - 1) * 4096, 4096)
+            ]
+        );
+
+        let mut corrupted = data.clone();
+        corrupted[65536 + 10] ^= 1;
+        let cache = SpaceCacheFile::new(&corrupted, 1 << 20).unwrap();
+        assert!(cache.read(7, 2, 1, 4096).is_err());
+    }
+}
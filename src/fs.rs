@@ -0,0 +1,100 @@
+use crate::chunk_tree_cache::ChunkTree;
+use crate::ctree::{parse_sys_chunk_array, read_chunk_tree_root, walk_chunk_root_tree, TreeRoot};
+use crate::structs::*;
+use crate::tree_block_cache::{TreeBlock, TreeBlockCache};
+use anyhow::{anyhow, bail, Result};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// An opened btrfs image: the superblock, the bootstrapped chunk map and the
+/// tree block cache every tree reader goes through.
+pub struct Fs {
+    pub file: File,
+    pub superblock: BtrfsSuperblock,
+    pub chunk_tree: ChunkTree,
+    pub block_cache: TreeBlockCache,
+    /// Check file data against the csum tree when reading it
+    pub verify_csums: bool,
+}
+
+impl Fs {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Fs> {
+        let file = File::open(path)?;
+        let mut superblock = BtrfsSuperblock::new();
+
+        superblock.check_valid_superblock(&file, false)?;
+        // bootstrap chunk tree from superblock
+        let mut chunk_tree = parse_sys_chunk_array(&superblock)
+            .map_err(|_| anyhow!("Error parsing the sys chunk array"))?;
+        let block_cache = TreeBlockCache::default();
+
+        // fill chunk tree
+        let chunk_tree_root = read_chunk_tree_root(&file, &superblock, &chunk_tree, &block_cache)?;
+        walk_chunk_root_tree(
+            &file,
+            &superblock,
+            &chunk_tree_root,
+            &mut chunk_tree,
+            &block_cache,
+        )?;
+
+        Ok(Fs {
+            file,
+            superblock,
+            chunk_tree,
+            block_cache,
+            verify_csums: false,
+        })
+    }
+
+    pub fn node_size(&self) -> u32 {
+        self.superblock.node_size
+    }
+
+    pub fn read_tree_block(&self, logical: u64, generation: u64) -> io::Result<TreeBlock> {
+        self.block_cache.read_tree_block(
+            &self.file,
+            &self.superblock,
+            &self.chunk_tree,
+            logical,
+            generation,
+        )
+    }
+
+    /// Read `buf.len()` bytes of data at logical address `logical`. Extents
+    /// never span chunks, so a single mapping covers the whole read.
+    pub fn read_logical(&self, logical: u64, buf: &mut [u8]) -> Result<()> {
+        let physical = match self.chunk_tree.offset(logical) {
+            Some(physical) => physical,
+            None => bail!("No chunk maps logical address {}", logical),
+        };
+        self.file.read_exact_at(buf, physical)?;
+        Ok(())
+    }
+
+    /// Number of copies of `logical` that can be read from this device
+    pub fn num_mirrors(&self, logical: u64) -> usize {
+        self.chunk_tree.mirror_offsets(logical).len()
+    }
+
+    /// Like `read_logical`, from copy `mirror` of a DUP or RAID1 chunk, the
+    /// first stripe being mirror 0
+    pub fn read_logical_mirror(&self, logical: u64, buf: &mut [u8], mirror: usize) -> Result<()> {
+        let physical = match self.chunk_tree.mirror_offsets(logical).get(mirror) {
+            Some(physical) => *physical,
+            None => bail!("No mirror {} of logical address {}", mirror, logical),
+        };
+        self.file.read_exact_at(buf, physical)?;
+        Ok(())
+    }
+
+    /// The root tree, as pointed to by the superblock
+    pub fn root_tree(&self) -> TreeRoot {
+        TreeRoot {
+            bytenr: self.superblock.root,
+            generation: self.superblock.generation,
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use crate::ctree::{struct_at, struct_prefix};
+use crate::fs::Fs;
+use crate::structs::*;
+use crate::subvolume::push_component;
+use anyhow::{bail, Result};
+use core::fmt;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+
+/// Inode numbers are only unique within a subvolume, so anything that can
+/// cross subvolume boundaries identifies inodes by both
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InodeId {
+    pub subvol: u64,
+    pub ino: u64,
+}
+
+impl InodeId {
+    pub fn new(subvol: u64, ino: u64) -> InodeId {
+        InodeId { subvol, ino }
+    }
+}
+
+impl fmt::Display for InodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "subvol {} ino {}", self.subvol, self.ino)
+    }
+}
+
+/// File type bits of `st_mode`
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFSOCK: u32 = 0o140000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFIFO: u32 = 0o010000;
+
+const INODE_FLAG_NAMES: [(u64, &str); 13] = [
+    (BTRFS_INODE_NODATASUM, "NODATASUM"),
+    (BTRFS_INODE_NODATACOW, "NODATACOW"),
+    (BTRFS_INODE_READONLY, "READONLY"),
+    (BTRFS_INODE_NOCOMPRESS, "NOCOMPRESS"),
+    (BTRFS_INODE_PREALLOC, "PREALLOC"),
+    (BTRFS_INODE_SYNC, "SYNC"),
+    (BTRFS_INODE_IMMUTABLE, "IMMUTABLE"),
+    (BTRFS_INODE_APPEND, "APPEND"),
+    (BTRFS_INODE_NODUMP, "NODUMP"),
+    (BTRFS_INODE_NOATIME, "NOATIME"),
+    (BTRFS_INODE_DIRSYNC, "DIRSYNC"),
+    (BTRFS_INODE_COMPRESS, "COMPRESS"),
+    (BTRFS_INODE_ROOT_ITEM_INIT, "ROOT_ITEM_INIT"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Unknown,
+}
+
+impl FileType {
+    pub fn from_mode(mode: u32) -> FileType {
+        match mode & S_IFMT {
+            S_IFREG => FileType::Regular,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::Fifo,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+
+    /// Type stored in dir items, which mirrors the mode of the target inode
+    pub fn from_dir_type(ty: u8) -> FileType {
+        match ty {
+            BTRFS_FT_REG_FILE => FileType::Regular,
+            BTRFS_FT_DIR => FileType::Directory,
+            BTRFS_FT_SYMLINK => FileType::Symlink,
+            BTRFS_FT_CHRDEV => FileType::CharDevice,
+            BTRFS_FT_BLKDEV => FileType::BlockDevice,
+            BTRFS_FT_FIFO => FileType::Fifo,
+            BTRFS_FT_SOCK => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+
+    /// Type character used by `ls -l`
+    pub fn as_char(&self) -> char {
+        match self {
+            FileType::Regular => '-',
+            FileType::Directory => 'd',
+            FileType::Symlink => 'l',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Fifo => 'p',
+            FileType::Socket => 's',
+            FileType::Unknown => '?',
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FileType::Regular => "regular file",
+            FileType::Directory => "directory",
+            FileType::Symlink => "symbolic link",
+            FileType::CharDevice => "character special file",
+            FileType::BlockDevice => "block special file",
+            FileType::Fifo => "fifo",
+            FileType::Socket => "socket",
+            FileType::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One link of an inode: its entry `name` at `index` in directory `parent`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InodeName {
+    pub parent: u64,
+    pub index: u64,
+    pub name: Vec<u8>,
+}
+
+/// Decode an `INODE_REF` item. All the names an inode has in the directory
+/// `parent` (the key offset) are packed back to back in the same item.
+pub fn parse_inode_refs(parent: u64, data: &[u8]) -> Result<Vec<InodeName>> {
+    let mut names = vec![];
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let inode_ref = struct_at::<BtrfsInodeRef>(data, offset)?;
+        let start = offset + std::mem::size_of::<BtrfsInodeRef>();
+        let end = start + inode_ref.name_len as usize;
+
+        if end > data.len() {
+            bail!("Truncated inode ref at offset {}", offset);
+        }
+        names.push(InodeName {
+            parent,
+            index: inode_ref.index,
+            name: data[start..end].to_vec(),
+        });
+        offset = end;
+    }
+    Ok(names)
+}
+
+/// Decode an `INODE_EXTREF` item, which holds the refs whose
+/// (parent, name) hash collide
+pub fn parse_inode_extrefs(data: &[u8]) -> Result<Vec<InodeName>> {
+    let mut names = vec![];
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let extref = struct_at::<BtrfsInodeExtref>(data, offset)?;
+        let start = offset + std::mem::size_of::<BtrfsInodeExtref>();
+        let end = start + extref.name_len as usize;
+
+        if end > data.len() {
+            bail!("Truncated inode extref at offset {}", offset);
+        }
+        names.push(InodeName {
+            parent: extref.parent_objectid,
+            index: extref.index,
+            name: data[start..end].to_vec(),
+        });
+        offset = end;
+    }
+    Ok(names)
+}
+
+/// Inode metadata decoded from an `INODE_ITEM`
+#[derive(Clone)]
+pub struct Stat {
+    pub id: InodeId,
+    pub generation: u64,
+    pub transid: u64,
+    pub size: u64,
+    /// Bytes allocated on disk
+    pub nbytes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub rdev: u64,
+    /// `BTRFS_INODE_*` flags
+    pub flags: u64,
+    pub atime: BtrfsTimespec,
+    pub ctime: BtrfsTimespec,
+    pub mtime: BtrfsTimespec,
+    pub otime: BtrfsTimespec,
+}
+
+impl Stat {
+    fn from_inode_item(id: InodeId, item: &BtrfsInodeItem) -> Stat {
+        Stat {
+            id,
+            generation: item.generation,
+            transid: item.transid,
+            size: item.size,
+            nbytes: item.nbytes,
+            nlink: item.nlink,
+            uid: item.uid,
+            gid: item.gid,
+            mode: item.mode,
+            rdev: item.rdev,
+            flags: item.flags,
+            atime: item.atime,
+            ctime: item.ctime,
+            mtime: item.mtime,
+            otime: item.otime,
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+
+    pub fn is_device(&self) -> bool {
+        matches!(
+            self.file_type(),
+            FileType::CharDevice | FileType::BlockDevice
+        )
+    }
+
+    /// Device numbers are stored in the kernel's internal `dev_t` layout: 12
+    /// bits of major above 20 bits of minor
+    pub fn rdev_major(&self) -> u32 {
+        (self.rdev >> 20) as u32 & 0xfff
+    }
+
+    pub fn rdev_minor(&self) -> u32 {
+        self.rdev as u32 & 0xfffff
+    }
+
+    /// Permission bits, including setuid, setgid and sticky
+    pub fn permissions(&self) -> u32 {
+        self.mode & 0o7777
+    }
+
+    /// `ls -l` style mode string, such as `drwxr-xr-x`
+    pub fn mode_string(&self) -> String {
+        let mode = self.mode;
+        let mut out = String::with_capacity(10);
+        let special = [(0o4000, 'S', 's'), (0o2000, 'S', 's'), (0o1000, 'T', 't')];
+
+        out.push(self.file_type().as_char());
+        for (i, (bit, without_x, with_x)) in special.iter().enumerate() {
+            let shift = 6 - 3 * i;
+            out.push(if mode & (0o4 << shift) != 0 { 'r' } else { '-' });
+            out.push(if mode & (0o2 << shift) != 0 { 'w' } else { '-' });
+            let x = mode & (0o1 << shift) != 0;
+            out.push(match (mode & bit != 0, x) {
+                (true, true) => *with_x,
+                (true, false) => *without_x,
+                (false, true) => 'x',
+                (false, false) => '-',
+            });
+        }
+        out
+    }
+
+    /// Names of the `BTRFS_INODE_*` flags that are set
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        INODE_FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.flags & flag != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl Fs {
+    /// Every (parent, index, name) of an inode, from both its `INODE_REF` and
+    /// `INODE_EXTREF` items. The root directory of a subvolume refers to itself.
+    pub fn inode_names(&self, id: InodeId) -> Result<Vec<InodeName>> {
+        let mut names = vec![];
+
+        self.walk_tree(
+            self.subvolume_root(id.subvol)?,
+            &BtrfsKey::new(id.ino, BTRFS_INODE_REF_KEY, 0),
+            &BtrfsKey::new(id.ino, BTRFS_INODE_EXTREF_KEY, u64::MAX),
+            |key, data| {
+                if key.ty == BTRFS_INODE_REF_KEY {
+                    names.extend(parse_inode_refs(key.offset, data)?);
+                } else {
+                    names.extend(parse_inode_extrefs(data)?);
+                }
+                Ok(true)
+            },
+        )?;
+        Ok(names)
+    }
+
+    /// Every path of inode `ino` of subvolume `subvol`, one per hard link,
+    /// absolute from the top level subvolume
+    pub fn inode_paths(&self, subvol: u64, ino: u64) -> Result<Vec<PathBuf>> {
+        let id = InodeId::new(subvol, ino);
+        let mut prefix = b"/".to_vec();
+        push_component(&mut prefix, &self.subvolume_top_path(subvol)?);
+
+        if id == self.subvolume_root_dir(subvol)? {
+            return Ok(vec![PathBuf::from(OsString::from_vec(prefix))]);
+        }
+        let names = self.inode_names(id)?;
+        if names.is_empty() {
+            bail!("{} has no inode refs", id);
+        }
+
+        let mut paths = vec![];
+        for name in names {
+            let mut path = prefix.clone();
+            push_component(&mut path, &self.dir_path(subvol, name.parent)?);
+            push_component(&mut path, &name.name);
+            paths.push(PathBuf::from(OsString::from_vec(path)));
+        }
+        Ok(paths)
+    }
+
+    /// Read the `INODE_ITEM` of an inode
+    pub fn stat(&self, id: InodeId) -> Result<Stat> {
+        // Placeholder shown by snapshots for subvolumes nested in their source
+        if id.ino == BTRFS_EMPTY_SUBVOL_DIR_OBJECTID {
+            let mut item: BtrfsInodeItem = struct_prefix(&[]);
+            item.mode = S_IFDIR | 0o755;
+            item.nlink = 1;
+            return Ok(Stat::from_inode_item(id, &item));
+        }
+
+        let key = BtrfsKey::new(id.ino, BTRFS_INODE_ITEM_KEY, 0);
+        match self.search_tree(self.subvolume_root(id.subvol)?, &key)? {
+            Some(data) => Ok(Stat::from_inode_item(id, struct_at(&data, 0)?)),
+            None => bail!("No inode item for {}", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_inode_refs, InodeId, Stat, S_IFDIR, S_IFREG};
+    use crate::ctree::struct_prefix;
+    use crate::structs::{BtrfsInodeItem, BTRFS_INODE_NODATACOW, BTRFS_INODE_NODATASUM};
+
+    #[test]
+    fn decodes_mode_and_flags() {
+        let mut item: BtrfsInodeItem = struct_prefix(&[]);
+        item.mode = S_IFREG | 0o4755;
+        item.flags = BTRFS_INODE_NODATASUM | BTRFS_INODE_NODATACOW;
+        let stat = Stat::from_inode_item(InodeId::new(5, 257), &item);
+
+        assert_eq!(stat.mode_string(), "-rwsr-xr-x");
+        assert_eq!(stat.permissions(), 0o4755);
+        assert_eq!(stat.flag_names(), vec!["NODATASUM", "NODATACOW"]);
+
+        item.mode = S_IFDIR | 0o1777;
+        let stat = Stat::from_inode_item(InodeId::new(5, 256), &item);
+        assert_eq!(stat.mode_string(), "drwxrwxrwt");
+    }
+
+    #[test]
+    fn decodes_every_name_of_an_inode_ref() {
+        let mut data = vec![];
+        for (index, name) in [(2u64, &b"a"[..]), (5, &b"hard link"[..])] {
+            data.extend_from_slice(&index.to_le_bytes());
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(name);
+        }
+
+        let names = parse_inode_refs(256, &data).unwrap();
+        assert_eq!(names.len(), 2);
+        assert_eq!((names[1].parent, names[1].index), (256, 5));
+        assert_eq!(names[1].name, b"hard link");
+        assert!(parse_inode_refs(256, &data[..data.len() - 1]).is_err());
+    }
+}
@@ -1,4 +1,25 @@
 pub mod structs;
 pub mod superblock;
+pub mod backref;
+pub mod block_group;
 pub mod chunk_tree_cache;
+pub mod compression;
+pub mod csum;
 pub mod ctree;
+pub mod dir;
+pub mod extent;
+pub mod extract;
+pub mod file;
+pub mod free_space;
+pub mod fs;
+pub mod inode;
+pub mod lzo;
+pub mod qgroup;
+pub mod tree_block_cache;
+pub mod subvolume;
+pub mod tar;
+pub mod time;
+pub mod uuid;
+pub mod uuid_tree;
+pub mod volumes;
+pub mod xattr;
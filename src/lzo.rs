@@ -0,0 +1,242 @@
+//! LZO1X decompression and the segmented framing btrfs wraps it in.
+//!
+//! A compressed extent starts with the total length of the framed data, then
+//! holds one segment per sector of decompressed data, each prefixed by its
+//! compressed length. Segment headers never straddle a sector boundary: when
+//! fewer than 4 bytes remain in a sector, the rest is padding.
+
+use crate::structs::BTRFS_MAX_COMPRESSED;
+use anyhow::{bail, Result};
+
+const LZO_LEN: usize = 4;
+/// Maximum number of zero bytes in a length run, from lzo1x_decompress_safe.c
+const MAX_255_COUNT: usize = usize::MAX / 255 - 2;
+const M2_MAX_OFFSET: usize = 0x0800;
+
+fn read_len(data: &[u8], offset: usize) -> Result<usize> {
+    match data.get(offset..offset + LZO_LEN) {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into()?) as usize),
+        None => bail!("Truncated LZO length header at offset {}", offset),
+    }
+}
+
+/// Decompress a btrfs LZO extent, producing at most `max_out` bytes. No
+/// extent holds more than `BTRFS_MAX_COMPRESSED` bytes of data.
+pub fn decompress_framed(data: &[u8], sector_size: usize, max_out: usize) -> Result<Vec<u8>> {
+    if max_out as u64 > BTRFS_MAX_COMPRESSED {
+        bail!(
+            "LZO output size {} is more than {}",
+            max_out,
+            BTRFS_MAX_COMPRESSED
+        );
+    }
+    let total = read_len(data, 0)?;
+    if total > data.len() {
+        bail!(
+            "LZO total length {} is past the {} bytes of the extent",
+            total,
+            data.len()
+        );
+    }
+
+    let mut out = Vec::with_capacity(max_out);
+    let mut cur = LZO_LEN;
+    while cur < total && out.len() < max_out {
+        let seg_len = read_len(data, cur)?;
+        cur += LZO_LEN;
+        let segment = match data.get(cur..cur + seg_len) {
+            Some(segment) if cur + seg_len <= total => segment,
+            _ => bail!(
+                "LZO segment at offset {} is past the end of the data",
+                cur - LZO_LEN
+            ),
+        };
+        // Each segment decompresses to at most one sector
+        let limit = sector_size.min(max_out - out.len());
+        decompress_lzo1x(segment, &mut out, limit)?;
+        cur += seg_len;
+
+        let sector_left = sector_size - cur % sector_size;
+        if sector_left < LZO_LEN {
+            cur += sector_left;
+        }
+    }
+    Ok(out)
+}
+
+/// Plain LZO1X decompression of `input`, appending at most `limit` bytes to
+/// `out`. Matches can't reach back before the data of this stream.
+pub fn decompress_lzo1x(input: &[u8], out: &mut Vec<u8>, limit: usize) -> Result<()> {
+    let start = out.len();
+    let out_end = start + limit;
+    let mut ip = 0;
+    let mut state;
+
+    let byte = |ip: usize| -> Result<usize> {
+        match input.get(ip) {
+            Some(b) => Ok(*b as usize),
+            None => bail!("LZO input overrun"),
+        }
+    };
+    // Length runs: each zero byte adds 255 to the final byte
+    let run_length = |ip: &mut usize, base: usize| -> Result<usize> {
+        let first = *ip;
+        while byte(*ip)? == 0 {
+            *ip += 1;
+        }
+        let zeros = *ip - first;
+        if zeros > MAX_255_COUNT {
+            bail!("LZO length run too long");
+        }
+        let len = zeros * 255 + base + byte(*ip)?;
+        *ip += 1;
+        Ok(len)
+    };
+    let copy_literals = |ip: &mut usize, out: &mut Vec<u8>, len: usize| -> Result<()> {
+        let literals = match input.get(*ip..*ip + len) {
+            Some(literals) => literals,
+            None => bail!("LZO input overrun"),
+        };
+        if out.len() + len > out_end {
+            bail!("LZO output overrun");
+        }
+        out.extend_from_slice(literals);
+        *ip += len;
+        Ok(())
+    };
+    let copy_match = |out: &mut Vec<u8>, distance: usize, len: usize| -> Result<()> {
+        if distance == 0 || distance > out.len() - start {
+            bail!("LZO match distance {} is behind the output", distance);
+        }
+        if out.len() + len > out_end {
+            bail!("LZO output overrun");
+        }
+        // Byte by byte, the match may overlap what it produces
+        let from = out.len() - distance;
+        for i in 0..len {
+            out.push(out[from + i]);
+        }
+        Ok(())
+    };
+
+    // A first byte above 17 encodes an initial literal run
+    if byte(0)? > 17 {
+        let t = byte(0)? - 17;
+        ip = 1;
+        copy_literals(&mut ip, out, t)?;
+        state = if t < 4 { t } else { 4 };
+    } else {
+        state = 0;
+    }
+
+    loop {
+        let mut t = byte(ip)?;
+        ip += 1;
+        let distance;
+        let next;
+
+        if t < 16 {
+            if state == 0 {
+                // Literal run
+                if t == 0 {
+                    t = run_length(&mut ip, 15)?;
+                }
+                copy_literals(&mut ip, out, t + 3)?;
+                state = 4;
+                continue;
+            }
+            next = t & 3;
+            if state != 4 {
+                // Two byte match close behind
+                distance = 1 + (t >> 2) + (byte(ip)? << 2);
+                ip += 1;
+                t = 2;
+            } else {
+                // Three byte match right after a literal run
+                distance = 1 + M2_MAX_OFFSET + (t >> 2) + (byte(ip)? << 2);
+                ip += 1;
+                t = 3;
+            }
+        } else if t >= 64 {
+            // M2: 3 to 8 bytes, up to 2KiB behind
+            next = t & 3;
+            distance = 1 + ((t >> 2) & 7) + (byte(ip)? << 3);
+            ip += 1;
+            t = (t >> 5) + 1;
+        } else if t >= 32 {
+            // M3: up to 16KiB behind
+            t = (t & 31) + 2;
+            if t == 2 {
+                t = run_length(&mut ip, 33)?;
+            }
+            let word = byte(ip)? | byte(ip + 1)? << 8;
+            ip += 2;
+            distance = 1 + (word >> 2);
+            next = word & 3;
+        } else {
+            // M4: 16KiB to 48KiB behind, or the end of stream marker
+            let far = (t & 8) << 11;
+            t = (t & 7) + 2;
+            if t == 2 {
+                t = run_length(&mut ip, 9)?;
+            }
+            let word = byte(ip)? | byte(ip + 1)? << 8;
+            ip += 2;
+            if far + (word >> 2) == 0 {
+                if t != 3 {
+                    bail!("Corrupt LZO end of stream marker");
+                }
+                if ip != input.len() {
+                    bail!("LZO stream ends before its input");
+                }
+                return Ok(());
+            }
+            distance = far + (word >> 2) + 0x4000;
+            next = word & 3;
+        }
+
+        copy_match(out, distance, t)?;
+        // Up to 3 literals follow a match
+        copy_literals(&mut ip, out, next)?;
+        state = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_literals_and_matches() {
+        // "abc" as an initial literal run, then an M2 match of 8 bytes 3 back
+        let stream = [17 + 3, b'a', b'b', b'c', 0xe8, 0, 0x11, 0, 0];
+        let mut out = vec![];
+        decompress_lzo1x(&stream, &mut out, 64).unwrap();
+        assert_eq!(out, b"abcabcabcab");
+
+        let mut out = vec![];
+        assert!(decompress_lzo1x(&stream, &mut out, 8).is_err());
+        assert!(decompress_lzo1x(&stream[..5], &mut vec![], 64).is_err());
+    }
+
+    #[test]
+    fn follows_segment_framing() {
+        // Two literal only segments with 18 byte sectors: the second header
+        // would straddle the first sector boundary and is pushed past it
+        let mut data = vec![0; 4];
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.extend_from_slice(&[17 + 4, b'1', b'2', b'3', b'4', 0x11, 0, 0]);
+        data.resize(18, 0);
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.extend_from_slice(&[17 + 3, b'5', b'6', b'7', 0x11, 0, 0]);
+        let total = data.len() as u32;
+        data[..4].copy_from_slice(&total.to_le_bytes());
+
+        // The first segment header claims 7 bytes, the stream is 8
+        assert!(decompress_framed(&data, 18, 64).is_err());
+        data[4] = 8;
+        assert_eq!(decompress_framed(&data, 18, 64).unwrap(), b"1234567");
+        assert_eq!(decompress_framed(&data, 18, 4).unwrap(), b"1234");
+        assert!(decompress_framed(&data, 18, 1 << 40).is_err());
+    }
+}
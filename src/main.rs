@@ -1,297 +1,840 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
-use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::os::unix::prelude::FileExt;
-
-use anyhow::{bail, Ok, Result};
-use btrfs_internals::chunk_tree_cache::ChunkTree;
-use btrfs_internals::ctree::{parse_sys_chunk_array, read_chunk_tree_root, walk_chunk_root_tree};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use btrfs_internals::extract::ExtractOptions;
+use btrfs_internals::free_space::{check_free_space, super_stripes};
+use btrfs_internals::fs::Fs;
+use btrfs_internals::inode::{FileType, InodeId, Stat};
+use btrfs_internals::qgroup::{Qgroup, QgroupId, QgroupStatus};
 use btrfs_internals::structs::{
-    BtrfsDirItem, BtrfsHeader, BtrfsInodeRef, BtrfsItem, BtrfsKey, BtrfsKeyPtr, BtrfsRootItem,
-    BtrfsSuperblock, BTRFS_DIR_ITEM_KEY, BTRFS_FIRST_FREE_OBJECTID, BTRFS_FS_TREE_OBJECTID,
-    BTRFS_FT_REG_FILE, BTRFS_INODE_REF_KEY, BTRFS_ROOT_ITEM_KEY,
+    BTRFS_FS_TREE_OBJECTID, BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT, BTRFS_QGROUP_STATUS_FLAG_RESCAN,
+    BTRFS_ROOT_ITEM_KEY, BTRFS_UUID_KEY_RECEIVED_SUBVOL, BTRFS_UUID_KEY_SUBVOL,
 };
-use regex::Regex;
+use btrfs_internals::subvolume::{push_component, SubvolSelector};
+use btrfs_internals::uuid::{format_uuid, parse_uuid};
+use btrfs_internals::uuid_tree::uuid_kind_name;
+use btrfs_internals::volumes::{block_group_type_name, profile_name};
+use btrfs_internals::xattr::format_xattr_value;
+use glob::Pattern;
+
+/// Print every entry below directory `dir`, whose path is `path`, descending
+/// into nested subvolumes
+fn print_file_path(fs: &Fs, dir: InodeId, path: &[u8]) -> Result<()> {
+    for entry in fs.read_dir(dir)? {
+        let mut entry_path = path.to_vec();
+        push_component(&mut entry_path, &entry.name);
+        let id = match fs.entry_target(dir, &entry.location, &entry.name) {
+            Ok(id) => id,
+            Err(err) => {
+                eprintln!(
+                    "Couldn't resolve {}: {}",
+                    String::from_utf8_lossy(&entry_path),
+                    err
+                );
+                continue;
+            }
+        };
 
-fn read_root_tree(file: &File, root_logical: u64, cache: &ChunkTree) -> Result<Vec<u8>> {
-    let size = cache
-        .find_logical(root_logical)
-        .expect("Can't find the chunk")
-        .0
-        .size;
+        // Subvolumes show up through their content, placeholders are empty
+        if entry.location.ty == BTRFS_ROOT_ITEM_KEY {
+            if id.subvol != dir.subvol {
+                print_file_path(fs, id, &entry_path)?;
+            }
+            continue;
+        }
 
-    let physical = cache
-        .offset(root_logical)
-        .expect("error finding physical offset");
+        let display = String::from_utf8_lossy(&entry_path);
+        match entry.file_type {
+            FileType::Symlink => println!(
+                "symlink: {} -> {} ({})",
+                display,
+                String::from_utf8_lossy(&fs.readlink(id)?),
+                id
+            ),
+            ty @ (FileType::CharDevice | FileType::BlockDevice) => {
+                let stat = fs.stat(id)?;
+                println!(
+                    "{}: {} {}:{} ({})",
+                    file_type_label(ty),
+                    display,
+                    stat.rdev_major(),
+                    stat.rdev_minor(),
+                    id
+                );
+            }
+            ty => println!("{}: {} ({})", file_type_label(ty), display, id),
+        }
+        if entry.file_type == FileType::Directory {
+            print_file_path(fs, id, &entry_path)?;
+        }
+    }
+    Ok(())
+}
 
-    let mut buf = vec![0; size as usize];
+fn file_type_label(ty: FileType) -> &'static str {
+    match ty {
+        FileType::Regular => "file",
+        FileType::Directory => "dir",
+        FileType::Symlink => "symlink",
+        FileType::CharDevice => "chrdev",
+        FileType::BlockDevice => "blkdev",
+        FileType::Fifo => "fifo",
+        FileType::Socket => "socket",
+        FileType::Unknown => "unknown",
+    }
+}
 
-    file.read_exact_at(&mut buf, physical)?;
+fn subvolume_list(fs: &Fs) -> Result<()> {
+    println!(
+        "{:>6} {:>6} {:>8} {:>8} {:>8} {:>5} {:19} {:36} {:36} {:36} PATH",
+        "ID",
+        "PARENT",
+        "GEN",
+        "CTRANSID",
+        "OTRANSID",
+        "FLAGS",
+        "OTIME",
+        "UUID",
+        "PARENT_UUID",
+        "RECEIVED_UUID"
+    );
+    for subvol in fs.subvolumes()? {
+        println!(
+            "{:>6} {:>6} {:>8} {:>8} {:>8} {:>5} {:19} {:36} {:36} {:36} {}",
+            subvol.id,
+            subvol.parent_id,
+            subvol.generation,
+            subvol.ctransid,
+            subvol.otransid,
+            if subvol.is_readonly() { "ro" } else { "-" },
+            if subvol.otime.is_zero() {
+                "-".to_string()
+            } else {
+                subvol.otime.date_time()
+            },
+            format_uuid(&subvol.uuid),
+            format_uuid(&subvol.parent_uuid),
+            format_uuid(&subvol.received_uuid),
+            subvol.path
+        );
+    }
+    Ok(())
+}
 
-    Ok(buf)
+fn stat(fs: &Fs, subvol: u64, path: &OsStr) -> Result<()> {
+    let id = fs.lookup_path(subvol, path)?;
+    let stat = fs.stat(id)?;
+    let flags = stat.flag_names();
+
+    if stat.file_type() == FileType::Symlink {
+        println!(
+            "  File: {} -> {}",
+            path.to_string_lossy(),
+            String::from_utf8_lossy(&fs.readlink(id)?)
+        );
+    } else {
+        println!("  File: {}", path.to_string_lossy());
+    }
+    println!(
+        "  Size: {:<12} Bytes: {:<12} {}",
+        stat.size,
+        stat.nbytes,
+        stat.file_type()
+    );
+    println!(
+        "Subvol: {:<12} Inode: {:<12} Links: {}",
+        stat.id.subvol, stat.id.ino, stat.nlink
+    );
+    println!(
+        "Access: ({:04o}/{})  Uid: {:<6} Gid: {:<6} Device type: {},{}",
+        stat.permissions(),
+        stat.mode_string(),
+        stat.uid,
+        stat.gid,
+        stat.rdev_major(),
+        stat.rdev_minor()
+    );
+    println!(
+        " Flags: {}",
+        if flags.is_empty() {
+            "-".to_string()
+        } else {
+            flags.join(",")
+        }
+    );
+    println!("   Gen: {:<12} Transid: {}", stat.generation, stat.transid);
+    println!("Access: {}", stat.atime);
+    println!("Modify: {}", stat.mtime);
+    println!("Change: {}", stat.ctime);
+    println!(" Birth: {}", stat.otime);
+    Ok(())
+}
+
+fn print_ls_line(fs: &Fs, stat: &Stat, name: &[u8]) -> Result<()> {
+    // Devices show their numbers where the size would be
+    let size = if stat.is_device() {
+        format!("{}, {}", stat.rdev_major(), stat.rdev_minor())
+    } else {
+        stat.size.to_string()
+    };
+    let mut name = String::from_utf8_lossy(name).into_owned();
+    if stat.file_type() == FileType::Symlink {
+        name.push_str(" -> ");
+        name.push_str(&String::from_utf8_lossy(&fs.readlink(stat.id)?));
+    }
+
+    println!(
+        "{} {:>3} {:>5} {:>5} {:>10} {} {}",
+        stat.mode_string(),
+        stat.nlink,
+        stat.uid,
+        stat.gid,
+        size,
+        stat.mtime.date_time(),
+        name
+    );
+    Ok(())
 }
 
-fn read_fs_tree_root(
-    file: &File,
-    root_tree: &Vec<u8>,
-    cache: &ChunkTree,
-    nodesize: u32,
-) -> Result<Vec<u8>> {
-    let header = unsafe { &*(root_tree.as_ptr() as *const BtrfsHeader) };
-    let mut buf = vec![0; nodesize as usize];
+fn ls(fs: &Fs, subvol: u64, path: &OsStr) -> Result<()> {
+    let dir = fs.lookup_path(subvol, path)?;
+    let stat = fs.stat(dir)?;
 
-    if header.level != 0 {
-        bail!("Root tree should be a leaf");
+    if stat.file_type() != FileType::Directory {
+        return print_ls_line(fs, &stat, path.as_bytes());
+    }
+    for entry in fs.read_dir(dir)? {
+        let target = fs.entry_target(dir, &entry.location, &entry.name)?;
+        print_ls_line(fs, &fs.stat(target)?, &entry.name)?;
     }
+    Ok(())
+}
 
-    for i in 0..header.nritems as usize {
-        let item = unsafe {
-            &*((root_tree.as_ptr() as usize
-                + std::mem::size_of::<BtrfsHeader>()
-                + (i * std::mem::size_of::<BtrfsItem>())) as *const BtrfsItem)
-        };
+fn inode_resolve(fs: &Fs, subvol: u64, ino: u64) -> Result<()> {
+    for path in fs.inode_paths(subvol, ino)? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
 
-        if item.key.ty == BTRFS_ROOT_ITEM_KEY && item.key.objectid == BTRFS_FS_TREE_OBJECTID {
-            let fs_root_item = unsafe {
-                &*((root_tree.as_ptr() as usize
-                    + std::mem::size_of::<BtrfsHeader>()
-                    + item.offset as usize) as *const BtrfsRootItem)
-            };
-            let physical = cache
-                .offset(fs_root_item.bytenr)
-                .expect("error finding the physical offset");
-
-            file.read_exact_at(&mut buf, physical)?;
-        }
+fn getfattr(fs: &Fs, subvol: u64, path: &OsStr, name: Option<&OsStr>) -> Result<()> {
+    let id = fs.lookup_path(subvol, path)?;
+
+    let xattrs = match name {
+        Some(name) => match fs.getxattr(id, name.as_bytes())? {
+            Some(value) => vec![(name.as_bytes().to_vec(), value)],
+            None => bail!("{}: No such attribute", name.to_string_lossy()),
+        },
+        None => fs.xattrs(id)?,
+    };
+    for (name, value) in xattrs {
+        println!(
+            "{}={}",
+            String::from_utf8_lossy(&name),
+            format_xattr_value(&name, &value)
+        );
     }
-    Ok(buf)
+    Ok(())
 }
 
-#[derive(Debug)]
-struct InodeRefT {
-    key: BtrfsKey,
-    name: String,
+fn cat(fs: &Fs, subvol: u64, path: &OsStr) -> Result<()> {
+    let id = fs.lookup_path(subvol, path)?;
+
+    if fs.stat(id)?.file_type() == FileType::Directory {
+        bail!("{}: Is a directory", path.to_string_lossy());
+    }
+    // Read in large chunks, compressed extents are decoded whole on every read
+    let mut file = BufReader::with_capacity(128 * 1024, fs.open_file(id)?);
+    io::copy(&mut file, &mut io::stdout().lock())?;
+    Ok(())
 }
 
-fn read_inode_ref_items(
-    file: &File,
-    fs_tree: &Vec<u8>,
-    cache: &ChunkTree,
-    nodesize: u32,
-    inode_ref_cache: &mut HashMap<u64, InodeRefT>,
-) -> Result<()> {
-    let header = unsafe { &*(fs_tree.as_ptr() as *const BtrfsHeader) };
-
-    // At the leaf
-    if header.level == 0 {
-        for i in 0..header.nritems as usize {
-            let item = unsafe {
-                &*((fs_tree.as_ptr() as usize
-                    + std::mem::size_of::<BtrfsHeader>()
-                    + (i * std::mem::size_of::<BtrfsItem>()))
-                    as *const BtrfsItem)
-            };
-
-            if item.key.ty != BTRFS_INODE_REF_KEY {
+fn extract(fs: &Fs, subvol: u64, args: &[&OsStr]) -> Result<()> {
+    let mut options = ExtractOptions::default();
+    let mut paths = vec![];
+    let mut i = 0;
+
+    while i < args.len() {
+        let arg = args[i].to_string_lossy();
+        match arg.as_ref() {
+            "--include" | "--exclude" => {
+                let pattern = match args.get(i + 1) {
+                    Some(pattern) => Pattern::new(&pattern.to_string_lossy())?,
+                    None => usage(),
+                };
+                if arg == "--include" {
+                    options.include.push(pattern);
+                } else {
+                    options.exclude.push(pattern);
+                }
+                i += 2;
                 continue;
             }
+            "-v" => options.verbose = true,
+            _ => paths.push(args[i]),
+        }
+        i += 1;
+    }
+    let (path, dest) = match paths[..] {
+        [path, dest] => (path, dest),
+        _ => usage(),
+    };
+
+    let id = fs.lookup_path(subvol, path)?;
+    let mut dest = PathBuf::from(dest);
+    // A single file goes into the destination directory under its own name
+    if fs.stat(id)?.file_type() != FileType::Directory {
+        if let Some(name) = Path::new(path).file_name() {
+            dest.push(name);
+        }
+    }
 
-            let inode_ref = unsafe {
-                &*((fs_tree.as_ptr() as usize
-                    + std::mem::size_of::<BtrfsHeader>()
-                    + item.offset as usize) as *const BtrfsInodeRef)
-            };
-
-            let inode_name_slice = unsafe {
-                std::slice::from_raw_parts(
-                    (inode_ref as *const _ as *const u8).add(std::mem::size_of::<BtrfsInodeRef>()),
-                    inode_ref.name_len as usize,
-                )
-            };
-            let name = std::str::from_utf8(inode_name_slice)?.to_string();
-
-            inode_ref_cache.insert(
-                item.key.objectid,
-                InodeRefT {
-                    key: item.key,
-                    name: name,
-                },
-            );
+    let stats = fs.extract(id, &dest, &options)?;
+    eprintln!(
+        "{} files, {} directories, {} symlinks, {} hard links, {} special files restored",
+        stats.files, stats.dirs, stats.symlinks, stats.hard_links, stats.special
+    );
+    if stats.errors != 0 {
+        bail!("{} files couldn't be restored", stats.errors);
+    }
+    Ok(())
+}
+
+fn tar(fs: &Fs, subvol: u64, path: &OsStr) -> Result<()> {
+    let id = fs.lookup_path(subvol, path)?;
+    let name = Path::new(path).file_name().unwrap_or_default();
+
+    let errors = fs.write_tar(id, name.as_bytes(), io::stdout().lock())?;
+    if errors != 0 {
+        bail!("{} members couldn't be archived", errors);
+    }
+    Ok(())
+}
+
+fn csum(fs: &Fs, logical: u64, len: u64) -> Result<()> {
+    let sector_size = fs.superblock.sector_size as u64;
+    let start = logical / sector_size * sector_size;
+
+    for (i, csum) in fs
+        .lookup_csums(start, logical + len - start)?
+        .iter()
+        .enumerate()
+    {
+        let csum = match csum {
+            Some(csum) => csum.iter().map(|b| format!("{:02x}", b)).collect(),
+            None => "-".to_string(),
+        };
+        println!("{} {}", start + i as u64 * sector_size, csum);
+    }
+    Ok(())
+}
+
+fn dump_extent(fs: &Fs, logical: u64) -> Result<()> {
+    let extent = match fs.lookup_extent(logical)? {
+        Some(extent) => extent,
+        None => bail!("No extent holds logical address {}", logical),
+    };
+
+    println!(
+        "extent {} len {} refs {} gen {} flags {}",
+        extent.bytenr,
+        extent.num_bytes,
+        extent.refs,
+        extent.generation,
+        extent.flag_names().join("|")
+    );
+    if let Some(level) = extent.level {
+        match extent.first_key {
+            Some(key) => {
+                let (objectid, ty, offset) = (key.objectid, key.ty, key.offset);
+                println!(
+                    "  tree block level {} key ({} {} {})",
+                    level, objectid, ty, offset
+                );
+            }
+            None => println!("  tree block level {}", level),
         }
-    } else {
-        println!("Node");
-        for i in 0..header.nritems as usize {
-            let keyptr = unsafe {
-                &*((fs_tree.as_ptr() as usize
-                    + std::mem::size_of::<BtrfsHeader>()
-                    + (i * std::mem::size_of::<BtrfsKeyPtr>()))
-                    as *const BtrfsKeyPtr)
-            };
-
-            let physical_offset = cache.offset(keyptr.blockptr).expect("error getting offset");
-
-            let mut node = vec![0; nodesize as usize];
-            file.read_exact_at(&mut node, physical_offset)?;
-            read_inode_ref_items(file, fs_tree, cache, nodesize, inode_ref_cache)?;
+    }
+    for extent_ref in &extent.inline_refs {
+        println!("  inline {}", extent_ref);
+    }
+    for extent_ref in &extent.keyed_refs {
+        println!("  keyed {}", extent_ref);
+    }
+    let counted: u64 = extent.all_refs().map(|r| r.count()).sum();
+    if counted != extent.refs {
+        println!(
+            "  refs mismatch: the item counts {}, its backrefs {}",
+            extent.refs, counted
+        );
+    }
+    Ok(())
+}
+
+fn logical_resolve(fs: &Fs, logical: u64) -> Result<()> {
+    for found in fs.logical_resolve(logical)? {
+        match fs.inode_paths(found.id.subvol, found.id.ino) {
+            Ok(paths) => {
+                for path in paths {
+                    println!("{} offset {} ({})", path.display(), found.offset, found.id);
+                }
+            }
+            Err(err) => eprintln!("Couldn't resolve the path of {}: {}", found.id, err),
         }
     }
     Ok(())
 }
 
-fn print_file_path(
-    file: &File,
-    fs_tree: &Vec<u8>,
-    cache: &ChunkTree,
-    nodesize: u32,
-    inode_ref_cache: &HashMap<u64, InodeRefT>,
-) -> Result<()> {
-    let header = unsafe { &*(fs_tree.as_ptr() as *const BtrfsHeader) };
-
-    // At the leaf
-    if header.level == 0 {
-        for i in 0..header.nritems as usize {
-            let item = unsafe {
-                &*((fs_tree.as_ptr() as usize
-                    + std::mem::size_of::<BtrfsHeader>()
-                    + (i * std::mem::size_of::<BtrfsItem>()))
-                    as *const BtrfsItem)
-            };
-
-            if item.key.ty != BTRFS_DIR_ITEM_KEY {
-                continue;
+/// Size with a binary unit and two decimals, like btrfs-progs
+fn pretty_size(bytes: u64) -> String {
+    const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", size, UNITS[unit])
+}
+
+fn filesystem_df(fs: &Fs) -> Result<()> {
+    for info in fs.space_usage()?.space_infos {
+        println!(
+            "{}, {}: total={}, used={}",
+            block_group_type_name(info.flags),
+            profile_name(info.flags),
+            pretty_size(info.total),
+            pretty_size(info.used)
+        );
+    }
+    Ok(())
+}
+
+fn filesystem_usage(fs: &Fs) -> Result<()> {
+    let usage = fs.space_usage()?;
+    let (free, free_min) = usage.free_estimated();
+
+    println!("Overall:");
+    println!(
+        "    Device size:          {:>16}",
+        pretty_size(usage.device_size())
+    );
+    println!(
+        "    Device allocated:     {:>16}",
+        pretty_size(usage.device_allocated())
+    );
+    println!(
+        "    Device unallocated:   {:>16}",
+        pretty_size(usage.device_unallocated())
+    );
+    println!(
+        "    Used:                 {:>16}",
+        pretty_size(usage.used())
+    );
+    println!(
+        "    Free (estimated):     {:>16}      (min: {})",
+        pretty_size(free),
+        pretty_size(free_min)
+    );
+    println!("    Data ratio:           {:>16.2}", usage.data_ratio());
+    println!("    Metadata ratio:       {:>16.2}", usage.metadata_ratio());
+
+    for info in &usage.space_infos {
+        println!();
+        println!(
+            "{},{}: Size:{}, Used:{} ({:.2}%)",
+            block_group_type_name(info.flags),
+            profile_name(info.flags),
+            pretty_size(info.total),
+            pretty_size(info.used),
+            if info.total == 0 {
+                0.0
+            } else {
+                info.used as f64 * 100.0 / info.total as f64
             }
+        );
+        for (devid, raw) in &info.raw {
+            println!("   devid {:<6} {:>16}", devid, pretty_size(*raw));
+        }
+    }
 
-            let dir_item = unsafe {
-                &*((fs_tree.as_ptr() as usize
-                    + std::mem::size_of::<BtrfsHeader>()
-                    + item.offset as usize) as *const BtrfsDirItem)
-            };
+    println!();
+    println!("Unallocated:");
+    for device in &usage.devices {
+        println!(
+            "   devid {:<6} {:>16}",
+            device.devid,
+            pretty_size(usage.unallocated(device.devid))
+        );
+    }
+    Ok(())
+}
 
-            if dir_item.ty != BTRFS_FT_REG_FILE {
+fn free_space(fs: &Fs) -> Result<()> {
+    let chunks = fs.chunks()?;
+    let mut bad_block_groups = 0;
+
+    for block_group in fs.block_groups()? {
+        print!(
+            "block group {} len {} {},{}: ",
+            block_group.start,
+            block_group.length,
+            block_group_type_name(block_group.flags),
+            profile_name(block_group.flags)
+        );
+        let free = match fs.free_space(&block_group)? {
+            Some(free) => free,
+            None => {
+                println!("no free space cache");
                 continue;
             }
+        };
+        println!(
+            "{}{}, {} bytes free in {} extents",
+            free.source,
+            if free.bitmaps { " with bitmaps" } else { "" },
+            free.total(),
+            free.extents.len()
+        );
+        for (start, length) in &free.extents {
+            println!("  free {} len {}", start, length);
+        }
 
-            let mut path = String::with_capacity(1);
-            let mut curr_inode_nr = dir_item.location.objectid;
-            loop {
-                let inode_ref = inode_ref_cache
-                    .get(&curr_inode_nr)
-                    .expect("Couldn't find inode");
+        let excluded = match chunks
+            .iter()
+            .find(|chunk| chunk.logical == block_group.start)
+        {
+            Some(chunk) => super_stripes(chunk),
+            None => bail!("Block group {} has no chunk", block_group.start),
+        };
+        let used = fs.block_group_extents(&block_group)?;
+        let problems = check_free_space(&block_group, &free, &used, &excluded);
+        for problem in &problems {
+            println!("  error: {}", problem);
+        }
+        if !problems.is_empty() {
+            bad_block_groups += 1;
+        }
+    }
+    if bad_block_groups != 0 {
+        bail!(
+            "The free space of {} block groups disagrees with the extent tree",
+            bad_block_groups
+        );
+    }
+    Ok(())
+}
 
-                let parent_inode_nr = inode_ref.key.offset;
+fn qgroup_status(fs: &Fs) -> Result<QgroupStatus> {
+    let status = match fs.qgroup_status()? {
+        Some(status) => status,
+        None => bail!("Quotas are not enabled"),
+    };
+    println!(
+        "quota status: version {} generation {} flags {}{}",
+        status.version,
+        status.generation,
+        status.flag_names().join("|"),
+        match status.enable_gen {
+            Some(generation) => format!(" simple quotas since generation {}", generation),
+            None => String::new(),
+        }
+    );
+    Ok(status)
+}
 
-                if curr_inode_nr == parent_inode_nr {
-                    break;
-                }
-                path.insert_str(0, &format!("/{}", &inode_ref.name));
-                // Traverse to the parent inode
-                curr_inode_nr = parent_inode_nr;
+fn qgroup_list(ids: &[QgroupId]) -> String {
+    if ids.is_empty() {
+        return "-".to_string();
+    }
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Path of subvolume `id` from the top level, `<stale>` for ids of
+/// subvolumes that are gone
+fn subvolume_path(fs: &Fs, id: u64) -> String {
+    if id == BTRFS_FS_TREE_OBJECTID {
+        return "<toplevel>".to_string();
+    }
+    match fs.subvolume_top_path(id) {
+        Ok(path) => String::from_utf8_lossy(&path).into_owned(),
+        Err(_) => "<stale>".to_string(),
+    }
+}
+
+/// Subvolume path of a level 0 qgroup, the number of members of the others
+fn qgroup_path(fs: &Fs, qgroup: &Qgroup) -> String {
+    if qgroup.id.level() != 0 {
+        return format!("<{} member qgroups>", qgroup.children.len());
+    }
+    // Qgroups outlive their subvolumes until removed
+    subvolume_path(fs, qgroup.id.id())
+}
+
+fn qgroup_show(fs: &Fs) -> Result<()> {
+    qgroup_status(fs)?;
+    println!();
+    println!(
+        "{:<12} {:>12} {:>12} {:>14} {:>14} {:<12} {:<12} Path",
+        "Qgroupid", "Referenced", "Exclusive", "Max referenced", "Max exclusive", "Parent", "Child"
+    );
+    for qgroup in fs.qgroups()?.values() {
+        let limit = |max: Option<u64>| max.map_or("none".to_string(), pretty_size);
+        println!(
+            "{:<12} {:>12} {:>12} {:>14} {:>14} {:<12} {:<12} {}",
+            qgroup.id.to_string(),
+            pretty_size(qgroup.rfer),
+            pretty_size(qgroup.excl),
+            limit(qgroup.max_rfer()),
+            limit(qgroup.max_excl()),
+            qgroup_list(&qgroup.parents),
+            qgroup_list(&qgroup.children),
+            qgroup_path(fs, qgroup)
+        );
+    }
+    Ok(())
+}
+
+fn qgroup_check(fs: &Fs) -> Result<()> {
+    let status = qgroup_status(fs)?;
+    let qgroups = fs.qgroups()?;
+    let counted = fs.qgroup_recount(&status, &qgroups)?;
+    let mut mismatches = 0;
+
+    if status.flags & (BTRFS_QGROUP_STATUS_FLAG_RESCAN | BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT) != 0
+    {
+        println!("quotas are being rescanned or marked inconsistent, differences are expected");
+    }
+    for (id, qgroup) in &qgroups {
+        let usage = counted[id];
+        for (what, recorded, found) in [
+            ("referenced", qgroup.rfer, usage.rfer),
+            ("exclusive", qgroup.excl, usage.excl),
+        ] {
+            if recorded != found {
+                println!("{}: {} {} recorded, {} counted", id, what, recorded, found);
+                mismatches += 1;
             }
-            println!("file: {}", path);
-        }
-    } else {
-        println!("Node");
-        for i in 0..header.nritems as usize {
-            let keyptr = unsafe {
-                &*((fs_tree.as_ptr() as usize
-                    + std::mem::size_of::<BtrfsHeader>()
-                    + (i * std::mem::size_of::<BtrfsKeyPtr>()))
-                    as *const BtrfsKeyPtr)
-            };
-
-            let physical_offset = cache.offset(keyptr.blockptr).expect("error getting offset");
-
-            let mut node = vec![0; nodesize as usize];
-            file.read_exact_at(&mut node, physical_offset)?;
-            print_file_path(file, fs_tree, cache, nodesize, inode_ref_cache)?;
         }
     }
+    if mismatches != 0 {
+        bail!(
+            "{} qgroup numbers disagree with the extent tree",
+            mismatches
+        );
+    }
+    println!("{} qgroups match the extent tree", qgroups.len());
     Ok(())
 }
 
-// return the file inode
-// path should be absolute
-fn lookup_path(path: &str, inode_ref_cache: &HashMap<u64, InodeRefT>) -> Result<u64> {
-    let mut parent_ino = BTRFS_FIRST_FREE_OBJECTID as u64;
-    let re = Regex::new(r"([\w+|\.]+)").unwrap();
-    let mut cap_idx = 0;
-    let mut match_idx = 0;
-
-    for cap in re.captures_iter(path) {
-        let item = &cap[1];
-        cap_idx += 1;
-        // Lookup a bit inefficient as we loop through all dir items
-        for (ino, inode_ref) in inode_ref_cache {
-            if inode_ref.key.offset == parent_ino && item == inode_ref.name {
-                match_idx += 1;
-                parent_ino = *ino;
-                break;
-            }
+fn uuid_tree(fs: &Fs) -> Result<()> {
+    let generation = fs.superblock.generation;
+    let uuid_tree_generation = fs.superblock.uuid_tree_generation;
+
+    if uuid_tree_generation != generation {
+        println!(
+            "uuid tree generation {} is behind generation {}, the kernel will rebuild it",
+            uuid_tree_generation, generation
+        );
+    }
+    for entry in fs.uuid_tree_entries()? {
+        for subvol in entry.subvols {
+            println!(
+                "{} {:<13} {:>6} {}",
+                format_uuid(&entry.uuid),
+                uuid_kind_name(entry.ty),
+                subvol,
+                subvolume_path(fs, subvol)
+            );
         }
-        // Early return on invalid path as we don't store the fs tree type
-        if match_idx != cap_idx {
-            bail!("Invalid file path")
+    }
+
+    let problems = fs.check_uuid_tree()?;
+    for problem in &problems {
+        println!("error: {}", problem);
+    }
+    if !problems.is_empty() {
+        bail!(
+            "The uuid tree and the root items disagree about {} subvolume uuids",
+            problems.len()
+        );
+    }
+    Ok(())
+}
+
+fn uuid_lookup(fs: &Fs, uuid: &str) -> Result<()> {
+    let uuid = parse_uuid(uuid)?;
+    let mut found = false;
+
+    for ty in [BTRFS_UUID_KEY_SUBVOL, BTRFS_UUID_KEY_RECEIVED_SUBVOL] {
+        for subvol in fs.lookup_uuid(&uuid, ty)? {
+            println!(
+                "{:<13} {:>6} {}",
+                uuid_kind_name(ty),
+                subvol,
+                subvolume_path(fs, subvol)
+            );
+            found = true;
         }
     }
-    Ok(parent_ino)
+    if !found {
+        bail!(
+            "No subvolume with uuid {} in the uuid tree",
+            format_uuid(&uuid)
+        );
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    println!("usage: btrfs-internals <image> [options] [command]");
+    println!();
+    println!("options:");
+    println!("    --subvol <path>       work on the subvolume at <path> from the top level");
+    println!("    --subvolid <id>       work on the subvolume with id <id>");
+    println!("    --subvol-uuid <uuid>  work on the subvolume with uuid <uuid>");
+    println!("    --verify-csums        check file data against the csum tree when reading it");
+    println!("    --cache-stats         print tree block cache statistics to stderr at the end");
+    println!();
+    println!("commands:");
+    println!("    subvolume list    list subvolumes and snapshots");
+    println!("    filesystem df     show allocated and used space per block group type");
+    println!("    filesystem usage  show device allocation, used and estimated free space");
+    println!("    free-space        show the free space cache of every block group and check it");
+    println!("    qgroup show       list quota groups with their usage, limits and relations");
+    println!("    qgroup check      recount qgroup usage from the extent tree and compare");
+    println!("    uuid-tree         list the uuid tree and check it against the root items");
+    println!("    uuid-lookup <uuid>  show the subvolumes with uuid or received uuid <uuid>");
+    println!("    stat <path>       show the inode metadata of <path>");
+    println!("    ls <path>         list a directory in creation order, like ls -l");
+    println!("    inode-resolve <ino>  show every path of inode <ino>");
+    println!("    cat <path>        write the content of <path> to stdout");
+    println!("    extract [--include <glob>] [--exclude <glob>] [-v] <path> <dest>");
+    println!("                      restore <path> and everything below it into <dest>");
+    println!(
+        "    tar [path]        write <path> and everything below it to stdout as a tar archive"
+    );
+    println!("    getfattr <path> [name]  show the extended attributes of <path>, decoded");
+    println!("    csum <logical> [len]  show the data checksums of a logical address range");
+    println!("    dump-extent <logical>  show the extent holding <logical> and its backrefs");
+    println!("    logical-resolve <logical>  show every file using the data at <logical>");
+    std::process::exit(1);
 }
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<OsString> = env::args_os().collect();
 
     if args.len() < 2 {
         println!("No arguments provided");
-        println!("usage: btrfs-internals <image>");
-        panic!();
-    }
-    let file = File::open(&args[1])?;
-    let mut superblock = BtrfsSuperblock::new();
-
-    superblock.check_valid_superblock(&file, false)?;
-    // bootstrap chunk tree from superblock
-    let mut chunktree_cache = parse_sys_chunk_array(&superblock).unwrap_or_else(|error| {
-        panic!("Error parsing the sys chunk arr");
-    });
-
-    // fill chunk tree
-    let chunk_tree_root = read_chunk_tree_root(&file, superblock.chunk_root, &chunktree_cache)?;
-
-    walk_chunk_root_tree(
-        &file,
-        &chunk_tree_root,
-        &mut chunktree_cache,
-        superblock.node_size,
-    )?;
-
-    // fill fs tree
-    // read root tree to find fs tree
-    let root_tree = read_root_tree(&file, superblock.root, &chunktree_cache)?;
-
-    let fs_tree_root =
-        read_fs_tree_root(&file, &root_tree, &chunktree_cache, superblock.node_size)?;
-
-    let mut inode_ref_map = HashMap::new();
-
-    read_inode_ref_items(
-        &file,
-        &fs_tree_root,
-        &chunktree_cache,
-        superblock.node_size,
-        &mut inode_ref_map,
-    )?;
-
-    print_file_path(
-        &file,
-        &fs_tree_root,
-        &chunktree_cache,
-        superblock.node_size,
-        &inode_ref_map,
-    )?;
-
-    let ino = lookup_path("/hello/yellp/heh.txt", &inode_ref_map)?;
-    println!("{:?}", inode_ref_map[&ino]);
+        usage();
+    }
+    let mut fs = Fs::open(&args[1])?;
+
+    // Without an explicit subvolume use the default one, as mount does
+    let mut selector = SubvolSelector::Default;
+    let mut cache_stats = false;
+    let mut i = 2;
+    while i < args.len() && args[i].as_bytes().starts_with(b"--") {
+        if args[i] == "--verify-csums" {
+            fs.verify_csums = true;
+            i += 1;
+            continue;
+        }
+        if args[i] == "--cache-stats" {
+            cache_stats = true;
+            i += 1;
+            continue;
+        }
+        let value = match args.get(i + 1) {
+            Some(value) => value.clone(),
+            None => usage(),
+        };
+        selector = match args[i].to_str() {
+            Some("--subvol") => SubvolSelector::Path(value),
+            Some("--subvolid") => SubvolSelector::Id(value.to_string_lossy().parse()?),
+            Some("--subvol-uuid") => SubvolSelector::Uuid(parse_uuid(&value.to_string_lossy())?),
+            _ => usage(),
+        };
+        i += 2;
+    }
+    // Only the command words need to be valid UTF-8, paths are passed as is
+    let cmd: Vec<&OsStr> = args[i..].iter().map(|s| s.as_os_str()).collect();
+    let result = run(&fs, &selector, &cmd);
 
-    Ok(())
+    // On stderr, so that it doesn't end up in cat or tar output
+    if cache_stats {
+        eprintln!("{}", fs.block_cache.stats());
+    }
+    result
+}
+
+fn run(fs: &Fs, selector: &SubvolSelector, cmd: &[&OsStr]) -> Result<()> {
+    let name = cmd.first().map(|c| c.to_string_lossy());
+    let rest = cmd.get(1..).unwrap_or_default();
+
+    if let (Some("subvolume"), [list]) = (name.as_deref(), rest) {
+        if *list == "list" {
+            return subvolume_list(fs);
+        }
+    }
+    // Commands on logical addresses don't look at any subvolume
+    match (name.as_deref(), rest) {
+        (Some("filesystem"), [cmd]) if *cmd == "df" => return filesystem_df(fs),
+        (Some("filesystem"), [cmd]) if *cmd == "usage" => return filesystem_usage(fs),
+        (Some("free-space"), []) => return free_space(fs),
+        (Some("qgroup"), [cmd]) if *cmd == "show" => return qgroup_show(fs),
+        (Some("qgroup"), [cmd]) if *cmd == "check" => return qgroup_check(fs),
+        (Some("uuid-tree"), []) => return uuid_tree(fs),
+        (Some("uuid-lookup"), [uuid]) => return uuid_lookup(fs, &uuid.to_string_lossy()),
+        (Some("csum"), [logical]) => {
+            return csum(fs, logical.to_string_lossy().parse()?, 1);
+        }
+        (Some("csum"), [logical, len]) => {
+            return csum(
+                fs,
+                logical.to_string_lossy().parse()?,
+                len.to_string_lossy().parse()?,
+            );
+        }
+        (Some("dump-extent"), [logical]) => {
+            return dump_extent(fs, logical.to_string_lossy().parse()?);
+        }
+        (Some("logical-resolve"), [logical]) => {
+            return logical_resolve(fs, logical.to_string_lossy().parse()?);
+        }
+        _ => {}
+    }
+    let subvol = fs.resolve_subvolume(selector)?;
+
+    match (name.as_deref(), rest) {
+        (None, _) => {}
+        (Some("stat"), [path]) => return stat(fs, subvol, path),
+        (Some("ls"), []) => return ls(fs, subvol, OsStr::new("/")),
+        (Some("ls"), [path]) => return ls(fs, subvol, path),
+        (Some("inode-resolve"), [ino]) => {
+            return inode_resolve(fs, subvol, ino.to_string_lossy().parse()?)
+        }
+        (Some("extract"), args) => return extract(fs, subvol, args),
+        (Some("tar"), []) => return tar(fs, subvol, OsStr::new("/")),
+        (Some("tar"), [path]) => return tar(fs, subvol, path),
+        (Some("cat"), [path]) => return cat(fs, subvol, path),
+        (Some("getfattr"), [path]) => return getfattr(fs, subvol, path, None),
+        (Some("getfattr"), [path, name]) => return getfattr(fs, subvol, path, Some(name)),
+        _ => usage(),
+    }
+
+    // Every file of the subvolume, with paths from the top level
+    let mut path = b"/".to_vec();
+    push_component(&mut path, &fs.subvolume_top_path(subvol)?);
+    print_file_path(fs, fs.subvolume_root_dir(subvol)?, &path)
 }
@@ -0,0 +1,357 @@
+//! Quota groups: the quota tree and a recount of its numbers from the extent
+//! tree.
+//!
+//! Every subvolume has a level 0 qgroup with the same id, higher level
+//! qgroups group others through relation items. A qgroup references every
+//! extent one of its subvolumes uses, and has it exclusively when no
+//! subvolume outside it does. With simple quotas an extent is only charged to
+//! the subvolume that created it, so referenced and exclusive are the same.
+
+use crate::ctree::{block_header, struct_at, TreeRoot};
+use crate::extent::{Extent, ExtentRef};
+use crate::fs::Fs;
+use crate::structs::*;
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+const QGROUP_STATUS_FLAG_NAMES: [(u64, &str); 4] = [
+    (BTRFS_QGROUP_STATUS_FLAG_ON, "ON"),
+    (BTRFS_QGROUP_STATUS_FLAG_RESCAN, "RESCAN"),
+    (BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT, "INCONSISTENT"),
+    (BTRFS_QGROUP_STATUS_FLAG_SIMPLE_MODE, "SIMPLE_MODE"),
+];
+
+/// A qgroupid, the level in the top 16 bits and the id below
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QgroupId(pub u64);
+
+impl QgroupId {
+    pub fn new(level: u16, id: u64) -> QgroupId {
+        QgroupId(((level as u64) << 48) | id)
+    }
+
+    pub fn level(&self) -> u16 {
+        (self.0 >> 48) as u16
+    }
+
+    /// Subvolume id of a level 0 qgroup
+    pub fn id(&self) -> u64 {
+        self.0 & ((1 << 48) - 1)
+    }
+}
+
+impl fmt::Display for QgroupId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.level(), self.id())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QgroupStatus {
+    pub version: u64,
+    pub generation: u64,
+    /// `BTRFS_QGROUP_STATUS_FLAG_*`
+    pub flags: u64,
+    pub rescan: u64,
+    /// Generation simple quotas were enabled in, extents older than it are
+    /// not accounted
+    pub enable_gen: Option<u64>,
+}
+
+impl QgroupStatus {
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        QGROUP_STATUS_FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.flags & flag != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    pub fn is_simple(&self) -> bool {
+        self.flags & BTRFS_QGROUP_STATUS_FLAG_SIMPLE_MODE != 0
+    }
+}
+
+/// A qgroup with its info and limit items and relations
+#[derive(Debug, Clone, Default)]
+pub struct Qgroup {
+    pub id: QgroupId,
+    pub generation: u64,
+    pub rfer: u64,
+    pub rfer_cmpr: u64,
+    pub excl: u64,
+    pub excl_cmpr: u64,
+    /// `BTRFS_QGROUP_LIMIT_*`, which of the limits below are set
+    pub limit_flags: u64,
+    pub max_rfer: u64,
+    pub max_excl: u64,
+    pub parents: Vec<QgroupId>,
+    pub children: Vec<QgroupId>,
+}
+
+impl Qgroup {
+    pub fn max_rfer(&self) -> Option<u64> {
+        (self.limit_flags & BTRFS_QGROUP_LIMIT_MAX_RFER != 0).then_some(self.max_rfer)
+    }
+
+    pub fn max_excl(&self) -> Option<u64> {
+        (self.limit_flags & BTRFS_QGROUP_LIMIT_MAX_EXCL != 0).then_some(self.max_excl)
+    }
+}
+
+/// Referenced and exclusive bytes of a qgroup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QgroupUsage {
+    pub rfer: u64,
+    pub excl: u64,
+}
+
+/// Whether tree `id` is a subvolume, the only trees quotas account
+fn is_fs_tree(id: u64) -> bool {
+    id == BTRFS_FS_TREE_OBJECTID
+        || (BTRFS_FIRST_FREE_OBJECTID as u64..=BTRFS_LAST_FREE_OBJECTID).contains(&id)
+}
+
+/// Qgroups holding level 0 qgroup `id`, itself included
+fn ancestors(qgroups: &BTreeMap<QgroupId, Qgroup>, id: QgroupId) -> BTreeSet<QgroupId> {
+    let mut found = BTreeSet::new();
+    let mut todo = vec![id];
+
+    while let Some(id) = todo.pop() {
+        if found.insert(id) {
+            if let Some(qgroup) = qgroups.get(&id) {
+                todo.extend(&qgroup.parents);
+            }
+        }
+    }
+    found
+}
+
+/// Charge an extent of `size` bytes used by subvolumes `roots` to the
+/// qgroups holding them
+fn account(
+    qgroups: &BTreeMap<QgroupId, Qgroup>,
+    usage: &mut BTreeMap<QgroupId, QgroupUsage>,
+    roots: &BTreeSet<u64>,
+    size: u64,
+) {
+    let sets: Vec<BTreeSet<QgroupId>> = roots
+        .iter()
+        .map(|root| ancestors(qgroups, QgroupId::new(0, *root)))
+        .collect();
+    let all: BTreeSet<QgroupId> = sets.iter().flatten().copied().collect();
+
+    for id in all {
+        let entry = match usage.get_mut(&id) {
+            Some(entry) => entry,
+            // Subvolumes without a qgroup still make extents shared
+            None => continue,
+        };
+        entry.rfer += size;
+        if sets.iter().all(|set| set.contains(&id)) {
+            entry.excl += size;
+        }
+    }
+}
+
+impl Fs {
+    pub fn quota_tree(&self) -> Result<Option<TreeRoot>> {
+        Ok(self
+            .read_root_item(BTRFS_QUOTA_TREE_OBJECTID)?
+            .map(|root_item| TreeRoot::from(&root_item)))
+    }
+
+    /// Quota status, `None` when quotas are disabled
+    pub fn qgroup_status(&self) -> Result<Option<QgroupStatus>> {
+        let root = match self.quota_tree()? {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        let data = match self.search_tree(root, &BtrfsKey::new(0, BTRFS_QGROUP_STATUS_KEY, 0))? {
+            Some(data) => data,
+            None => bail!("The quota tree has no status item"),
+        };
+        let item = *struct_at::<BtrfsQgroupStatusItem>(&data, 0)?;
+        let mut status = QgroupStatus {
+            version: item.version,
+            generation: item.generation,
+            flags: item.flags,
+            rescan: item.rescan,
+            enable_gen: None,
+        };
+        if status.is_simple() {
+            status.enable_gen = Some(*struct_at::<u64>(
+                &data,
+                std::mem::size_of::<BtrfsQgroupStatusItem>(),
+            )?);
+        }
+        Ok(Some(status))
+    }
+
+    /// Every qgroup, by qgroupid
+    pub fn qgroups(&self) -> Result<BTreeMap<QgroupId, Qgroup>> {
+        let root = match self.quota_tree()? {
+            Some(root) => root,
+            None => bail!("Quotas are not enabled"),
+        };
+        let mut qgroups: BTreeMap<QgroupId, Qgroup> = BTreeMap::new();
+        let mut relations = vec![];
+
+        self.walk_tree(
+            root,
+            &BtrfsKey::new(0, 0, 0),
+            &BtrfsKey::new(u64::MAX, u8::MAX, u64::MAX),
+            |key, data| {
+                let id = QgroupId(key.offset);
+                match key.ty {
+                    BTRFS_QGROUP_INFO_KEY => {
+                        let item = *struct_at::<BtrfsQgroupInfoItem>(data, 0)?;
+                        let qgroup = qgroups.entry(id).or_default();
+                        qgroup.generation = item.generation;
+                        qgroup.rfer = item.rfer;
+                        qgroup.rfer_cmpr = item.rfer_cmpr;
+                        qgroup.excl = item.excl;
+                        qgroup.excl_cmpr = item.excl_cmpr;
+                    }
+                    BTRFS_QGROUP_LIMIT_KEY => {
+                        let item = *struct_at::<BtrfsQgroupLimitItem>(data, 0)?;
+                        let qgroup = qgroups.entry(id).or_default();
+                        qgroup.limit_flags = item.flags;
+                        qgroup.max_rfer = item.max_rfer;
+                        qgroup.max_excl = item.max_excl;
+                    }
+                    // Parents are at a higher level, so have larger ids
+                    BTRFS_QGROUP_RELATION_KEY if key.objectid < key.offset => {
+                        relations.push((QgroupId(key.objectid), id))
+                    }
+                    _ => {}
+                }
+                Ok(true)
+            },
+        )?;
+
+        for (id, qgroup) in qgroups.iter_mut() {
+            qgroup.id = *id;
+        }
+        for (child, parent) in relations {
+            match qgroups.get_mut(&child) {
+                Some(qgroup) => qgroup.parents.push(parent),
+                None => bail!(
+                    "Qgroup relation to {} from missing qgroup {}",
+                    parent,
+                    child
+                ),
+            }
+            match qgroups.get_mut(&parent) {
+                Some(qgroup) => qgroup.children.push(child),
+                None => bail!(
+                    "Qgroup relation from {} to missing qgroup {}",
+                    child,
+                    parent
+                ),
+            }
+        }
+        Ok(qgroups)
+    }
+
+    /// Subvolume charged for an extent by simple quotas: the owner ref of
+    /// data, the tree of a tree block
+    fn extent_owner(&self, extent: &Extent) -> Result<Option<u64>> {
+        for extent_ref in extent.all_refs() {
+            match *extent_ref {
+                ExtentRef::Owner { root } => return Ok(Some(root)),
+                ExtentRef::TreeBlock { root } if extent.is_tree_block() => return Ok(Some(root)),
+                _ => {}
+            }
+        }
+        if extent.is_tree_block() {
+            let block = self.read_tree_block(extent.bytenr, extent.generation)?;
+            return Ok(Some(block_header(&block).owner));
+        }
+        Ok(None)
+    }
+
+    /// Referenced and exclusive bytes of every qgroup, recomputed from the
+    /// extent tree. This resolves the backrefs of every extent, which takes
+    /// a while on large filesystems. The roots of tree blocks are cached for
+    /// the whole recount, every leaf and node is only resolved once.
+    pub fn qgroup_recount(
+        &self,
+        status: &QgroupStatus,
+        qgroups: &BTreeMap<QgroupId, Qgroup>,
+    ) -> Result<BTreeMap<QgroupId, QgroupUsage>> {
+        let mut usage: BTreeMap<QgroupId, QgroupUsage> = qgroups
+            .keys()
+            .map(|id| (*id, QgroupUsage::default()))
+            .collect();
+        let mut block_roots = HashMap::new();
+
+        self.walk_extents(|extent| {
+            let roots: BTreeSet<u64> = if status.is_simple() {
+                if extent.generation < status.enable_gen.unwrap_or(0) {
+                    return Ok(());
+                }
+                self.extent_owner(&extent)?.into_iter().collect()
+            } else {
+                self.extent_roots(&extent, &mut block_roots)?
+            };
+            let roots: BTreeSet<u64> = roots.into_iter().filter(|root| is_fs_tree(*root)).collect();
+            if !roots.is_empty() {
+                account(qgroups, &mut usage, &roots, extent.num_bytes);
+            }
+            Ok(())
+        })?;
+        Ok(usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accounts_shared_extents() {
+        let group = QgroupId::new(1, 100);
+        let mut qgroups = BTreeMap::new();
+        for id in [
+            QgroupId::new(0, 256),
+            QgroupId::new(0, 257),
+            QgroupId::new(0, 258),
+            group,
+        ] {
+            qgroups.insert(
+                id,
+                Qgroup {
+                    id,
+                    ..Default::default()
+                },
+            );
+        }
+        for child in [256, 257] {
+            qgroups
+                .get_mut(&QgroupId::new(0, child))
+                .unwrap()
+                .parents
+                .push(group);
+        }
+        let mut usage = qgroups
+            .keys()
+            .map(|id| (*id, QgroupUsage::default()))
+            .collect();
+
+        account(&qgroups, &mut usage, &BTreeSet::from([256]), 4096);
+        account(&qgroups, &mut usage, &BTreeSet::from([256, 257]), 8192);
+        account(&qgroups, &mut usage, &BTreeSet::from([257, 258]), 16384);
+        // A subvolume without a qgroup still shares the extent
+        account(&qgroups, &mut usage, &BTreeSet::from([258, 300]), 32768);
+
+        let expect = |rfer, excl| QgroupUsage { rfer, excl };
+        assert_eq!(usage[&QgroupId::new(0, 256)], expect(12288, 4096));
+        assert_eq!(usage[&QgroupId::new(0, 257)], expect(24576, 0));
+        assert_eq!(usage[&QgroupId::new(0, 258)], expect(49152, 0));
+        assert_eq!(usage[&group], expect(28672, 12288));
+        assert_eq!(group.to_string(), "1/100");
+        assert_eq!((group.level(), group.id()), (1, 100));
+    }
+}
@@ -1,20 +1,142 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
-const BTRFS_CSUM_SIZE: usize = 32;
+pub const BTRFS_CSUM_SIZE: usize = 32;
 const BTRFS_LABEL_SIZE: usize = 256;
 const BTRFS_FSID_SIZE: usize = 16;
-const BTRFS_UUID_SIZE: usize = 16;
+pub const BTRFS_UUID_SIZE: usize = 16;
 const BTRFS_SYSTEM_CHUNK_ARRAY_SIZE: usize = 2048;
 
+pub const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+pub const BTRFS_EXTENT_TREE_OBJECTID: u64 = 2;
+pub const BTRFS_CHUNK_TREE_OBJECTID: u64 = 3;
 pub const BTRFS_FS_TREE_OBJECTID: u64 = 5;
+pub const BTRFS_CSUM_TREE_OBJECTID: u64 = 7;
+pub const BTRFS_QUOTA_TREE_OBJECTID: u64 = 8;
+pub const BTRFS_UUID_TREE_OBJECTID: u64 = 9;
+pub const BTRFS_FREE_SPACE_TREE_OBJECTID: u64 = 10;
+pub const BTRFS_BLOCK_GROUP_TREE_OBJECTID: u64 = 11;
+/// Objectid of the `DEV_ITEM` items in the chunk tree
+pub const BTRFS_DEV_ITEMS_OBJECTID: u64 = 1;
+/// Objectid of every `CHUNK_ITEM`, the key offset is the logical address
+pub const BTRFS_FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+/// Directory in the root tree holding the "default" subvolume entry
+pub const BTRFS_ROOT_TREE_DIR_OBJECTID: u64 = 6;
 pub const BTRFS_FIRST_FREE_OBJECTID:usize = 256;
+/// Inode number of the placeholder directory a snapshot shows in place of a
+/// nested subvolume
+pub const BTRFS_EMPTY_SUBVOL_DIR_OBJECTID: u64 = 2;
+pub const BTRFS_LAST_FREE_OBJECTID: u64 = -256i64 as u64;
+/// Objectid of every `EXTENT_CSUM` item, the key offset is the logical address
+pub const BTRFS_EXTENT_CSUM_OBJECTID: u64 = -10i64 as u64;
+/// Objectid of the v1 space cache headers in the root tree, the key offset is
+/// the start of the block group
+pub const BTRFS_FREE_SPACE_OBJECTID: u64 = -11i64 as u64;
 
+pub const BTRFS_INODE_ITEM_KEY: u8 = 1;
 pub const BTRFS_INODE_REF_KEY: u8 = 12;
+pub const BTRFS_INODE_EXTREF_KEY: u8 = 13;
+pub const BTRFS_XATTR_ITEM_KEY: u8 = 24;
 pub const BTRFS_DIR_ITEM_KEY: u8 = 84;
+pub const BTRFS_DIR_INDEX_KEY: u8 = 96;
+pub const BTRFS_EXTENT_DATA_KEY: u8 = 108;
+pub const BTRFS_EXTENT_CSUM_KEY: u8 = 128;
 pub const BTRFS_ROOT_ITEM_KEY: u8 = 132;
+pub const BTRFS_ROOT_BACKREF_KEY: u8 = 144;
+pub const BTRFS_ROOT_REF_KEY: u8 = 156;
+pub const BTRFS_EXTENT_ITEM_KEY: u8 = 168;
+/// Skinny extent item of a tree block, the key offset is its level
+pub const BTRFS_METADATA_ITEM_KEY: u8 = 169;
+/// Only found inline, the root owning the extent for simple quotas
+pub const BTRFS_EXTENT_OWNER_REF_KEY: u8 = 172;
+pub const BTRFS_TREE_BLOCK_REF_KEY: u8 = 176;
+pub const BTRFS_EXTENT_DATA_REF_KEY: u8 = 178;
+pub const BTRFS_SHARED_BLOCK_REF_KEY: u8 = 182;
+pub const BTRFS_SHARED_DATA_REF_KEY: u8 = 184;
+pub const BTRFS_BLOCK_GROUP_ITEM_KEY: u8 = 192;
+/// Free space tree items, keyed by (start, type, length)
+pub const BTRFS_FREE_SPACE_INFO_KEY: u8 = 198;
+pub const BTRFS_FREE_SPACE_EXTENT_KEY: u8 = 199;
+pub const BTRFS_FREE_SPACE_BITMAP_KEY: u8 = 200;
+pub const BTRFS_DEV_ITEM_KEY: u8 = 216;
 pub const BTRFS_CHUNK_ITEM_KEY: u8 = 228;
+/// Quota tree items. Status, info and limit items have objectid 0, info and
+/// limit items the qgroupid as offset. Relations are stored both ways, as
+/// (child, type, parent) and (parent, type, child).
+pub const BTRFS_QGROUP_STATUS_KEY: u8 = 240;
+pub const BTRFS_QGROUP_INFO_KEY: u8 = 242;
+pub const BTRFS_QGROUP_LIMIT_KEY: u8 = 244;
+pub const BTRFS_QGROUP_RELATION_KEY: u8 = 246;
+/// UUID tree items, keyed by the two halves of the uuid as little endian
+/// numbers and holding the ids of the subvolumes with that uuid
+pub const BTRFS_UUID_KEY_SUBVOL: u8 = 251;
+pub const BTRFS_UUID_KEY_RECEIVED_SUBVOL: u8 = 252;
 
+/// `BtrfsDirItem.ty`
+pub const BTRFS_FT_UNKNOWN: u8 = 0;
 pub const BTRFS_FT_REG_FILE: u8 = 1;
+pub const BTRFS_FT_DIR: u8 = 2;
+pub const BTRFS_FT_CHRDEV: u8 = 3;
+pub const BTRFS_FT_BLKDEV: u8 = 4;
+pub const BTRFS_FT_FIFO: u8 = 5;
+pub const BTRFS_FT_SOCK: u8 = 6;
+pub const BTRFS_FT_SYMLINK: u8 = 7;
+pub const BTRFS_FT_XATTR: u8 = 8;
+
+/// `BtrfsInodeItem.flags`
+pub const BTRFS_INODE_NODATASUM: u64 = 1 << 0;
+pub const BTRFS_INODE_NODATACOW: u64 = 1 << 1;
+pub const BTRFS_INODE_READONLY: u64 = 1 << 2;
+pub const BTRFS_INODE_NOCOMPRESS: u64 = 1 << 3;
+pub const BTRFS_INODE_PREALLOC: u64 = 1 << 4;
+pub const BTRFS_INODE_SYNC: u64 = 1 << 5;
+pub const BTRFS_INODE_IMMUTABLE: u64 = 1 << 6;
+pub const BTRFS_INODE_APPEND: u64 = 1 << 7;
+pub const BTRFS_INODE_NODUMP: u64 = 1 << 8;
+pub const BTRFS_INODE_NOATIME: u64 = 1 << 9;
+pub const BTRFS_INODE_DIRSYNC: u64 = 1 << 10;
+pub const BTRFS_INODE_COMPRESS: u64 = 1 << 11;
+pub const BTRFS_INODE_ROOT_ITEM_INIT: u64 = 1 << 31;
+
+/// `BtrfsExtentItem.flags`
+pub const BTRFS_EXTENT_FLAG_DATA: u64 = 1 << 0;
+pub const BTRFS_EXTENT_FLAG_TREE_BLOCK: u64 = 1 << 1;
+/// Backrefs of the tree block point to parent blocks, not to roots
+pub const BTRFS_BLOCK_FLAG_FULL_BACKREF: u64 = 1 << 8;
+
+/// `BtrfsRootItem.flags`: the subvolume is read-only
+pub const BTRFS_ROOT_SUBVOL_RDONLY: u64 = 1 << 0;
+
+pub const BTRFS_CSUM_TYPE_CRC32: u16 = 0;
+pub const BTRFS_CSUM_TYPE_XXHASH: u16 = 1;
+pub const BTRFS_CSUM_TYPE_SHA256: u16 = 2;
+pub const BTRFS_CSUM_TYPE_BLAKE2: u16 = 3;
+
+/// `BtrfsChunk.ty`: what the chunk holds and its RAID profile
+pub const BTRFS_BLOCK_GROUP_DATA: u64 = 1 << 0;
+pub const BTRFS_BLOCK_GROUP_SYSTEM: u64 = 1 << 1;
+pub const BTRFS_BLOCK_GROUP_METADATA: u64 = 1 << 2;
+pub const BTRFS_BLOCK_GROUP_RAID0: u64 = 1 << 3;
+pub const BTRFS_BLOCK_GROUP_RAID1: u64 = 1 << 4;
+pub const BTRFS_BLOCK_GROUP_DUP: u64 = 1 << 5;
+pub const BTRFS_BLOCK_GROUP_RAID10: u64 = 1 << 6;
+pub const BTRFS_BLOCK_GROUP_RAID5: u64 = 1 << 7;
+pub const BTRFS_BLOCK_GROUP_RAID6: u64 = 1 << 8;
+pub const BTRFS_BLOCK_GROUP_RAID1C3: u64 = 1 << 9;
+pub const BTRFS_BLOCK_GROUP_RAID1C4: u64 = 1 << 10;
+/// Profiles where every stripe holds a full copy of the chunk
+pub const BTRFS_BLOCK_GROUP_MIRRORED: u64 = BTRFS_BLOCK_GROUP_RAID1
+    | BTRFS_BLOCK_GROUP_DUP
+    | BTRFS_BLOCK_GROUP_RAID1C3
+    | BTRFS_BLOCK_GROUP_RAID1C4;
+pub const BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE: u64 = 1 << 0;
+/// The free space tree is in sync with the extent tree
+pub const BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID: u64 = 1 << 1;
+/// Block group items live in their own tree instead of the extent tree
+pub const BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE: u64 = 1 << 3;
+pub const BTRFS_FEATURE_INCOMPAT_METADATA_UUID: u64 = 1 << 10;
+/// Extents are charged to the subvolume creating them only
+pub const BTRFS_FEATURE_INCOMPAT_SIMPLE_QUOTA: u64 = 1 << 16;
+
 pub const BTRFS_SUPERBLOCK_OFFSET: u64 = 0x10_000;
 pub const BTRFS_DUP_SUPERBLOCK_OFFSET: u64 = 0x400_0000;
 pub const BTRFS_SUPERBLOCK_MAGIC: [u8; 8] = *b"_BHRfS_M";
@@ -235,6 +357,46 @@ pub struct BtrfsDirItem {
     pub ty: u8,
 }
 
+/// `BtrfsFileExtentItem.ty`
+pub const BTRFS_FILE_EXTENT_INLINE: u8 = 0;
+pub const BTRFS_FILE_EXTENT_REG: u8 = 1;
+pub const BTRFS_FILE_EXTENT_PREALLOC: u8 = 2;
+
+/// `BtrfsFileExtentItem.compression`
+pub const BTRFS_COMPRESS_NONE: u8 = 0;
+pub const BTRFS_COMPRESS_ZLIB: u8 = 1;
+pub const BTRFS_COMPRESS_LZO: u8 = 2;
+pub const BTRFS_COMPRESS_ZSTD: u8 = 3;
+
+/// No data extent is bigger than this, compressed or not
+pub const BTRFS_MAX_EXTENT_SIZE: u64 = 128 * 1024 * 1024;
+/// Neither the compressed nor the uncompressed size of a compressed extent is
+/// bigger than this
+pub const BTRFS_MAX_COMPRESSED: u64 = 128 * 1024;
+/// Tree blocks have levels 0 (leaves) to `BTRFS_MAX_LEVEL - 1`
+pub const BTRFS_MAX_LEVEL: u8 = 8;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+/// Payload of an `EXTENT_DATA` item. Inline extents stop after `ty` and are
+/// followed by the (possibly compressed) file data.
+pub struct BtrfsFileExtentItem {
+    pub generation: u64,
+    /// Size of the extent once decompressed
+    pub ram_bytes: u64,
+    pub compression: u8,
+    pub encryption: u8,
+    pub other_encoding: u16,
+    pub ty: u8,
+    /// Start of the extent on disk, 0 for a hole
+    pub disk_bytenr: u64,
+    pub disk_num_bytes: u64,
+    /// Offset of the file data in the decompressed extent
+    pub offset: u64,
+    /// Number of file bytes covered by this item
+    pub num_bytes: u64,
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsInodeRef {
@@ -242,6 +404,165 @@ pub struct BtrfsInodeRef {
     pub name_len: u16,
 }
 
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+/// Extended inode ref, used once the refs from one directory overflow an
+/// `INODE_REF` item. Keyed by a hash of the parent and name, followed by the name.
+pub struct BtrfsInodeExtref {
+    pub parent_objectid: u64,
+    pub index: u64,
+    pub name_len: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+/// Payload of both `ROOT_REF` and `ROOT_BACKREF` items, followed by the name
+pub struct BtrfsRootRef {
+    /// directory in the parent subvolume holding the link
+    pub dirid: u64,
+    /// dir index of the link
+    pub sequence: u64,
+    pub name_len: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+/// Start of `EXTENT_ITEM` and `METADATA_ITEM` items, followed by a
+/// `BtrfsTreeBlockInfo` for tree blocks in an `EXTENT_ITEM`, then inline refs
+pub struct BtrfsExtentItem {
+    pub refs: u64,
+    pub generation: u64,
+    pub flags: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsTreeBlockInfo {
+    /// first key of the block
+    pub key: BtrfsKey,
+    pub level: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+/// Header of an inline ref, `offset` holds the root or parent of the ref.
+/// Data refs have their `BtrfsExtentDataRef` in place of `offset`, shared
+/// data refs a `BtrfsSharedDataRef` after it.
+pub struct BtrfsExtentInlineRef {
+    pub ty: u8,
+    pub offset: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsExtentDataRef {
+    /// subvolume holding the file
+    pub root: u64,
+    /// inode number
+    pub objectid: u64,
+    /// file offset of the start of the extent
+    pub offset: u64,
+    pub count: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsSharedDataRef {
+    pub count: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsBlockGroupItem {
+    /// bytes allocated to extents in the block group
+    pub used: u64,
+    /// always `BTRFS_FIRST_CHUNK_TREE_OBJECTID`
+    pub chunk_objectid: u64,
+    /// `BTRFS_BLOCK_GROUP_*` type and profile
+    pub flags: u64,
+}
+
+/// `BtrfsFreeSpaceInfo.flags`: the block group has bitmap items instead of
+/// extent items
+pub const BTRFS_FREE_SPACE_USING_BITMAPS: u32 = 1 << 0;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsFreeSpaceInfo {
+    /// number of free extents, bitmaps or not
+    pub extent_count: u32,
+    pub flags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+/// v1 space cache header, in the root tree
+pub struct BtrfsFreeSpaceHeader {
+    /// inode item of the cache file
+    pub location: BtrfsKey,
+    pub generation: u64,
+    pub num_entries: u64,
+    pub num_bitmaps: u64,
+}
+
+/// `BtrfsFreeSpaceEntry.ty`
+pub const BTRFS_FREE_SPACE_EXTENT: u8 = 1;
+pub const BTRFS_FREE_SPACE_BITMAP: u8 = 2;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+/// Entry of a v1 space cache file
+pub struct BtrfsFreeSpaceEntry {
+    pub offset: u64,
+    pub bytes: u64,
+    pub ty: u8,
+}
+
+/// `BtrfsQgroupStatusItem.flags`
+pub const BTRFS_QGROUP_STATUS_FLAG_ON: u64 = 1 << 0;
+pub const BTRFS_QGROUP_STATUS_FLAG_RESCAN: u64 = 1 << 1;
+/// The numbers can't be trusted until the next rescan
+pub const BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT: u64 = 1 << 2;
+pub const BTRFS_QGROUP_STATUS_FLAG_SIMPLE_MODE: u64 = 1 << 3;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+/// Followed by the generation simple quotas were enabled in, with
+/// `BTRFS_QGROUP_STATUS_FLAG_SIMPLE_MODE`
+pub struct BtrfsQgroupStatusItem {
+    pub version: u64,
+    pub generation: u64,
+    pub flags: u64,
+    /// progress of a rescan, the next extent to account
+    pub rescan: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsQgroupInfoItem {
+    pub generation: u64,
+    pub rfer: u64,
+    pub rfer_cmpr: u64,
+    pub excl: u64,
+    pub excl_cmpr: u64,
+}
+
+/// `BtrfsQgroupLimitItem.flags`: which limits are set
+pub const BTRFS_QGROUP_LIMIT_MAX_RFER: u64 = 1 << 0;
+pub const BTRFS_QGROUP_LIMIT_MAX_EXCL: u64 = 1 << 1;
+pub const BTRFS_QGROUP_LIMIT_RSV_RFER: u64 = 1 << 2;
+pub const BTRFS_QGROUP_LIMIT_RSV_EXCL: u64 = 1 << 3;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsQgroupLimitItem {
+    pub flags: u64,
+    pub max_rfer: u64,
+    pub max_excl: u64,
+    pub rsv_rfer: u64,
+    pub rsv_excl: u64,
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct BtrfsKey {
@@ -0,0 +1,366 @@
+use crate::ctree::{struct_at, struct_prefix, TreeRoot};
+use crate::dir::parse_dir_items;
+use crate::fs::Fs;
+use crate::inode::InodeId;
+use crate::structs::*;
+use crate::uuid::{format_uuid, Uuid};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+
+/// A subvolume or snapshot, as described by its `ROOT_ITEM` and the
+/// `ROOT_BACKREF` linking it into its parent
+#[derive(Clone)]
+pub struct Subvolume {
+    pub id: u64,
+    /// Subvolume holding the directory entry of this one, 0 for the top level
+    pub parent_id: u64,
+    /// Directory of the parent subvolume holding the entry
+    pub dir_id: u64,
+    pub name: Vec<u8>,
+    /// Path relative to the top level subvolume, `<unlinked>` when it can't
+    /// be resolved
+    pub path: String,
+    pub generation: u64,
+    pub ctransid: u64,
+    pub otransid: u64,
+    pub uuid: Uuid,
+    pub parent_uuid: Uuid,
+    pub received_uuid: Uuid,
+    pub flags: u64,
+    pub otime: BtrfsTimespec,
+    pub root_item: BtrfsRootItem,
+}
+
+impl Subvolume {
+    pub(crate) fn new(id: u64, root_item: BtrfsRootItem) -> Subvolume {
+        let mut subvol = Subvolume {
+            id,
+            parent_id: 0,
+            dir_id: 0,
+            name: vec![],
+            path: String::new(),
+            generation: root_item.generation,
+            ctransid: 0,
+            otransid: 0,
+            uuid: [0; BTRFS_UUID_SIZE],
+            parent_uuid: [0; BTRFS_UUID_SIZE],
+            received_uuid: [0; BTRFS_UUID_SIZE],
+            flags: root_item.flags,
+            otime: BtrfsTimespec { sec: 0, nsec: 0 },
+            root_item,
+        };
+
+        // Root items written by old kernels stop before `generation_v2`, the
+        // fields after it are only valid when both generations agree
+        if root_item.generation_v2 == root_item.generation {
+            subvol.ctransid = root_item.ctransid;
+            subvol.otransid = root_item.otransid;
+            subvol.uuid = root_item.uuid;
+            subvol.parent_uuid = root_item.parent_uuid;
+            subvol.received_uuid = root_item.received_uuid;
+            subvol.otime = root_item.otime;
+        }
+        subvol
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.flags & BTRFS_ROOT_SUBVOL_RDONLY != 0
+    }
+
+    pub fn tree_root(&self) -> TreeRoot {
+        TreeRoot::from(&self.root_item)
+    }
+}
+
+/// How to pick the subvolume to work on, mirroring the `subvol=` and
+/// `subvolid=` mount options
+#[derive(Debug, Clone)]
+pub enum SubvolSelector {
+    /// The subvolume set with `btrfs subvolume set-default`
+    Default,
+    Id(u64),
+    /// Path relative to the top level subvolume, in raw bytes
+    Path(OsString),
+    Uuid(Uuid),
+}
+
+/// Decode a `ROOT_REF`/`ROOT_BACKREF` payload into (dirid, sequence, name)
+pub fn parse_root_ref(data: &[u8]) -> Result<(u64, u64, Vec<u8>)> {
+    let root_ref = struct_at::<BtrfsRootRef>(data, 0)?;
+    let start = std::mem::size_of::<BtrfsRootRef>();
+    let end = start + root_ref.name_len as usize;
+
+    if end > data.len() {
+        bail!("Truncated root ref name");
+    }
+    Ok((root_ref.dirid, root_ref.sequence, data[start..end].to_vec()))
+}
+
+impl Fs {
+    /// Every live subvolume and snapshot, excluding the top level, sorted by id
+    pub fn subvolumes(&self) -> Result<Vec<Subvolume>> {
+        let mut subvols = vec![];
+
+        for (mut subvol, path) in self.linked_subvolumes()? {
+            subvol.path = match path {
+                Some(path) => String::from_utf8_lossy(&path).into_owned(),
+                None => "<unlinked>".to_string(),
+            };
+            subvols.push(subvol);
+        }
+        Ok(subvols)
+    }
+
+    /// Every live subvolume sorted by id, with the raw path of those whose
+    /// backref chain leads to the top level
+    fn linked_subvolumes(&self) -> Result<Vec<(Subvolume, Option<Vec<u8>>)>> {
+        let mut subvols = BTreeMap::new();
+
+        self.walk_tree(
+            self.root_tree(),
+            &BtrfsKey::new(BTRFS_FIRST_FREE_OBJECTID as u64, 0, 0),
+            &BtrfsKey::new(BTRFS_LAST_FREE_OBJECTID, u8::MAX, u64::MAX),
+            |key, data| {
+                match key.ty {
+                    BTRFS_ROOT_ITEM_KEY => {
+                        let root_item = struct_prefix::<BtrfsRootItem>(data);
+                        // Deleted subvolumes keep their root item until cleaned up
+                        if root_item.refs != 0 {
+                            subvols.insert(key.objectid, Subvolume::new(key.objectid, root_item));
+                        }
+                    }
+                    BTRFS_ROOT_BACKREF_KEY => {
+                        if let Some(subvol) = subvols.get_mut(&{ key.objectid }) {
+                            let (dirid, _, name) = parse_root_ref(data)?;
+                            subvol.parent_id = key.offset;
+                            subvol.dir_id = dirid;
+                            subvol.name = name;
+                        }
+                    }
+                    _ => {}
+                }
+                Ok(true)
+            },
+        )?;
+
+        // One broken backref chain shouldn't hide every other subvolume
+        let mut known = HashMap::new();
+        let mut dirs = HashMap::new();
+        let paths: Vec<_> = subvols
+            .keys()
+            .map(|id| self.linked_path(&subvols, *id, &mut known, &mut dirs).ok())
+            .collect();
+        Ok(subvols.into_values().zip(paths).collect())
+    }
+
+    /// Path of subvolume `id` from the backrefs collected in `subvols`.
+    /// Paths of subvolumes and directories are kept in `paths` and `dirs`, so
+    /// that parents shared by several subvolumes are only looked up once.
+    fn linked_path(
+        &self,
+        subvols: &BTreeMap<u64, Subvolume>,
+        id: u64,
+        paths: &mut HashMap<u64, Vec<u8>>,
+        dirs: &mut HashMap<(u64, u64), Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let mut chain: Vec<&Subvolume> = vec![];
+        let mut id = id;
+        let mut path = loop {
+            if id == BTRFS_FS_TREE_OBJECTID {
+                break vec![];
+            }
+            if let Some(path) = paths.get(&id) {
+                break path.clone();
+            }
+            if chain.iter().any(|subvol| subvol.id == id) {
+                bail!("Subvolume backrefs loop at {}", id);
+            }
+            match subvols.get(&id) {
+                Some(subvol) if subvol.parent_id != 0 => {
+                    chain.push(subvol);
+                    id = subvol.parent_id;
+                }
+                _ => bail!("Subvolume {} is not linked in the filesystem", id),
+            }
+        };
+
+        for subvol in chain.into_iter().rev() {
+            push_component(
+                &mut path,
+                &self.cached_dir_path(subvol.parent_id, subvol.dir_id, dirs)?,
+            );
+            push_component(&mut path, &subvol.name);
+            paths.insert(subvol.id, path.clone());
+        }
+        Ok(path)
+    }
+
+    /// Id of the default subvolume, from the "default" entry of the root tree
+    /// directory. Like the kernel, fall back to the top level when it's missing.
+    pub fn default_subvolume_id(&self) -> Result<u64> {
+        let mut id = BTRFS_FS_TREE_OBJECTID;
+
+        self.walk_tree(
+            self.root_tree(),
+            &BtrfsKey::new(BTRFS_ROOT_TREE_DIR_OBJECTID, BTRFS_DIR_ITEM_KEY, 0),
+            &BtrfsKey::new(BTRFS_ROOT_TREE_DIR_OBJECTID, BTRFS_DIR_ITEM_KEY, u64::MAX),
+            |_, data| {
+                for entry in parse_dir_items(data)? {
+                    if entry.name == b"default" {
+                        id = entry.location.objectid;
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            },
+        )?;
+        Ok(id)
+    }
+
+    /// Turn a selector into a subvolume id, checking that the subvolume exists
+    pub fn resolve_subvolume(&self, selector: &SubvolSelector) -> Result<u64> {
+        let id = match selector {
+            SubvolSelector::Default => self.default_subvolume_id()?,
+            SubvolSelector::Id(id) => *id,
+            SubvolSelector::Path(path) => {
+                let components = path_components(path.as_bytes());
+                if components.is_empty() {
+                    return Ok(BTRFS_FS_TREE_OBJECTID);
+                }
+                let found = self.linked_subvolumes()?.into_iter().find(|(_, linked)| {
+                    linked
+                        .as_deref()
+                        .is_some_and(|linked| path_components(linked) == components)
+                });
+                match found {
+                    Some((subvol, _)) => subvol.id,
+                    None => bail!("No subvolume at path {}", path.to_string_lossy()),
+                }
+            }
+            SubvolSelector::Uuid(uuid) => match self.subvolume_by_uuid(uuid)? {
+                Some(id) => id,
+                None => bail!("No subvolume with uuid {}", format_uuid(uuid)),
+            },
+        };
+        // Make sure the tree is there before handing out the id
+        self.subvolume_root(id)?;
+        Ok(id)
+    }
+
+    /// Root of the fs tree of subvolume `id`
+    pub fn subvolume_root(&self, id: u64) -> Result<TreeRoot> {
+        if id != BTRFS_FS_TREE_OBJECTID
+            && !(BTRFS_FIRST_FREE_OBJECTID as u64..=BTRFS_LAST_FREE_OBJECTID).contains(&id)
+        {
+            bail!("{} is not a subvolume id", id);
+        }
+        match self.read_root_item(id)? {
+            Some(root_item) if root_item.refs != 0 => Ok(TreeRoot::from(&root_item)),
+            _ => bail!("Subvolume {} not found", id),
+        }
+    }
+
+    /// Path of the root of subvolume `id` relative to the top level, following
+    /// its `ROOT_BACKREF` chain
+    pub fn subvolume_top_path(&self, id: u64) -> Result<Vec<u8>> {
+        let mut path = vec![];
+        let mut id = id;
+        let mut seen = HashSet::new();
+
+        while id != BTRFS_FS_TREE_OBJECTID {
+            if !seen.insert(id) {
+                bail!("Subvolume backrefs loop at {}", id);
+            }
+            let mut backref = None;
+            self.walk_tree(
+                self.root_tree(),
+                &BtrfsKey::new(id, BTRFS_ROOT_BACKREF_KEY, 0),
+                &BtrfsKey::new(id, BTRFS_ROOT_BACKREF_KEY, u64::MAX),
+                |key, data| {
+                    backref = Some((key.offset, parse_root_ref(data)?));
+                    Ok(false)
+                },
+            )?;
+            let (parent, (dirid, _, name)) = match backref {
+                Some(backref) => backref,
+                None => bail!("Subvolume {} is not linked in the filesystem", id),
+            };
+
+            let mut prefix = self.dir_path(parent, dirid)?;
+            push_component(&mut prefix, &name);
+            push_component(&mut prefix, &path);
+            path = prefix;
+            id = parent;
+        }
+        Ok(path)
+    }
+
+    /// Path of directory `dirid` relative to the root directory of subvolume
+    /// `subvol`. Directories can't be hard linked so they have a single name.
+    pub fn dir_path(&self, subvol: u64, dirid: u64) -> Result<Vec<u8>> {
+        self.cached_dir_path(subvol, dirid, &mut HashMap::new())
+    }
+
+    /// `dir_path` remembering the path of every directory it goes through in
+    /// `dirs`, keyed by (subvolume, inode)
+    fn cached_dir_path(
+        &self,
+        subvol: u64,
+        dirid: u64,
+        dirs: &mut HashMap<(u64, u64), Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let mut chain = vec![];
+        let mut ino = dirid;
+        let mut seen = HashSet::new();
+
+        let mut path = loop {
+            if let Some(path) = dirs.get(&(subvol, ino)) {
+                break path.clone();
+            }
+            if !seen.insert(ino) {
+                bail!("Directory refs loop at inode {}", ino);
+            }
+            let name = match self
+                .inode_names(InodeId::new(subvol, ino))?
+                .into_iter()
+                .next()
+            {
+                Some(name) => name,
+                None => bail!("Directory {} has no inode ref", ino),
+            };
+            // The root directory refers to itself
+            if name.parent == ino {
+                dirs.insert((subvol, ino), vec![]);
+                break vec![];
+            }
+            chain.push((ino, name.name));
+            ino = name.parent;
+        };
+
+        for (ino, name) in chain.into_iter().rev() {
+            push_component(&mut path, &name);
+            dirs.insert((subvol, ino), path.clone());
+        }
+        Ok(path)
+    }
+}
+
+/// Components of a slash separated path, without the empty and `.` ones
+fn path_components(path: &[u8]) -> Vec<&[u8]> {
+    path.split(|b| *b == b'/')
+        .filter(|component| !component.is_empty() && *component != b".")
+        .collect()
+}
+
+/// Append `component` to a slash separated path, empty components are skipped
+pub fn push_component(path: &mut Vec<u8>, component: &[u8]) {
+    if component.is_empty() {
+        return;
+    }
+    if !path.is_empty() && !path.ends_with(b"/") {
+        path.push(b'/');
+    }
+    path.extend_from_slice(component);
+}
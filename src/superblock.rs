@@ -5,6 +5,12 @@ use std::fs::File;
 use std::io;
 use std::os::unix::prelude::FileExt;
 
+impl Default for BtrfsSuperblock {
+    fn default() -> Self {
+        BtrfsSuperblock::new()
+    }
+}
+
 impl BtrfsSuperblock {
     pub fn new() -> BtrfsSuperblock {
         unsafe { std::mem::zeroed() }
@@ -24,13 +30,13 @@ impl BtrfsSuperblock {
     }
 
     pub fn check_valid_superblock(&mut self, file: &File, debug: bool) -> io::Result<()> {
-        self.get_superblock(&file)?;
+        self.get_superblock(file)?;
 
         if self.magic != BTRFS_SUPERBLOCK_MAGIC {
             println!("Error reading the superblock {:?}", self.magic);
             std::process::exit(1);
         }
-        if debug == true {
+        if debug {
             println!(
                 "sys_chunk_array_size: {}",
                 self.sys_chunk_array_size as usize
@@ -0,0 +1,490 @@
+//! A POSIX pax tar writer and a tree walker feeding it.
+//!
+//! Everything ustar can't hold goes in pax extended headers: long or non
+//! UTF-8 names, large sizes and ids, nanosecond mtimes and xattrs (as
+//! `SCHILY.xattr.*`). Non UTF-8 names are kept as raw bytes and flagged with
+//! `hdrcharset=BINARY`. Files with holes use the GNU sparse 1.0 format, which
+//! both GNU tar and bsdtar restore as sparse files.
+
+use crate::fs::Fs;
+use crate::inode::{FileType, InodeId, Stat};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+const BLOCK_SIZE: usize = 512;
+/// Largest piece of a file read at once
+const TAR_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Largest values fitting the octal fields of a ustar header
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+const USTAR_MAX_ID: u64 = 0o7777777;
+
+const TYPE_REGULAR: u8 = b'0';
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_CHAR_DEVICE: u8 = b'3';
+const TYPE_BLOCK_DEVICE: u8 = b'4';
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_FIFO: u8 = b'6';
+const TYPE_PAX: u8 = b'x';
+
+/// One archive member, before it's split between the ustar and pax headers
+#[derive(Debug, Clone, Default)]
+pub struct TarEntry {
+    pub path: Vec<u8>,
+    pub ty: u8,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// Size of the data following the header
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub link: Vec<u8>,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    /// Extra pax records, such as xattrs or sparse file information
+    pub records: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Encode one pax record, `"<len> <key>=<value>\n"` where `len` counts itself
+pub fn pax_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+
+    let mut record = format!("{} ", len).into_bytes();
+    record.extend_from_slice(key);
+    record.push(b'=');
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let last = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = last);
+    field[..last].copy_from_slice(digits.as_bytes());
+    field[last] = 0;
+}
+
+/// Split `path` into the ustar name and prefix fields, if it fits
+fn split_ustar_path(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    if path.len() <= 100 {
+        return Some((path, b""));
+    }
+    // The prefix ends at a slash, which isn't stored
+    let start = path.len().saturating_sub(101);
+    (start..path.len().min(156))
+        .filter(|i| path[*i] == b'/')
+        .map(|i| (&path[i + 1..], &path[..i]))
+        .find(|(name, _)| !name.is_empty() && name.len() <= 100)
+}
+
+pub struct TarWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> TarWriter<W> {
+        TarWriter { out }
+    }
+
+    /// Write the headers of `entry`. Exactly `entry.size` bytes of data must
+    /// follow through `write_data`, then `pad`.
+    pub fn append_header(&mut self, entry: &TarEntry) -> io::Result<()> {
+        let mut records = vec![];
+        let ustar_path = split_ustar_path(&entry.path);
+        let binary_path = std::str::from_utf8(&entry.path).is_err();
+        let binary_link = std::str::from_utf8(&entry.link).is_err();
+
+        // Without it readers take pax names as UTF-8 and mangle the others
+        if binary_path || binary_link {
+            records.push((b"hdrcharset".to_vec(), b"BINARY".to_vec()));
+        }
+        if ustar_path.is_none() || binary_path {
+            records.push((b"path".to_vec(), entry.path.clone()));
+        }
+        if entry.link.len() > 100 || binary_link {
+            records.push((b"linkpath".to_vec(), entry.link.clone()));
+        }
+        if entry.size > USTAR_MAX_SIZE {
+            records.push((b"size".to_vec(), entry.size.to_string().into_bytes()));
+        }
+        if entry.uid > USTAR_MAX_ID {
+            records.push((b"uid".to_vec(), entry.uid.to_string().into_bytes()));
+        }
+        if entry.gid > USTAR_MAX_ID {
+            records.push((b"gid".to_vec(), entry.gid.to_string().into_bytes()));
+        }
+        if entry.mtime_nsec != 0 || entry.mtime < 0 || entry.mtime as u64 > USTAR_MAX_SIZE {
+            let mtime = if entry.mtime < 0 && entry.mtime_nsec != 0 {
+                // The fraction is added to the seconds, go one second lower
+                format!(
+                    "-{}.{:09}",
+                    -(entry.mtime + 1),
+                    1_000_000_000 - entry.mtime_nsec
+                )
+            } else {
+                format!("{}.{:09}", entry.mtime, entry.mtime_nsec)
+            };
+            records.push((b"mtime".to_vec(), mtime.into_bytes()));
+        }
+        records.extend(entry.records.iter().cloned());
+
+        if !records.is_empty() {
+            let data: Vec<u8> = records
+                .iter()
+                .flat_map(|(key, value)| pax_record(key, value))
+                .collect();
+            let name = entry.path.rsplit(|b| *b == b'/').find(|n| !n.is_empty());
+            let mut pax_path = b"PaxHeaders/".to_vec();
+            pax_path.extend_from_slice(name.unwrap_or(b"entry"));
+            pax_path.truncate(100);
+
+            let pax = TarEntry {
+                path: pax_path,
+                ty: TYPE_PAX,
+                mode: 0o644,
+                size: data.len() as u64,
+                mtime: entry.mtime.clamp(0, USTAR_MAX_SIZE as i64),
+                ..Default::default()
+            };
+            self.write_ustar_header(&pax)?;
+            self.write_data(&data)?;
+            self.pad(data.len() as u64)?;
+        }
+        self.write_ustar_header(entry)
+    }
+
+    /// ustar header of `entry`, fields too large for it were already stored
+    /// in pax records and are clamped here
+    fn write_ustar_header(&mut self, entry: &TarEntry) -> io::Result<()> {
+        let mut header = [0u8; BLOCK_SIZE];
+        let (name, prefix) = split_ustar_path(&entry.path).unwrap_or_else(|| {
+            let start = entry.path.len().saturating_sub(100);
+            (&entry.path[start..], b"")
+        });
+
+        header[..name.len()].copy_from_slice(name);
+        octal(&mut header[100..108], (entry.mode & 0o7777) as u64);
+        octal(&mut header[108..116], entry.uid.min(USTAR_MAX_ID));
+        octal(&mut header[116..124], entry.gid.min(USTAR_MAX_ID));
+        octal(&mut header[124..136], entry.size.min(USTAR_MAX_SIZE));
+        octal(
+            &mut header[136..148],
+            entry.mtime.clamp(0, USTAR_MAX_SIZE as i64) as u64,
+        );
+        header[156] = entry.ty;
+        let link = &entry.link[..entry.link.len().min(100)];
+        header[157..157 + link.len()].copy_from_slice(link);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        octal(&mut header[329..337], entry.dev_major as u64);
+        octal(&mut header[337..345], entry.dev_minor as u64);
+        header[345..345 + prefix.len()].copy_from_slice(prefix);
+
+        // The checksum is computed with its own field filled with spaces
+        header[148..156].fill(b' ');
+        let sum: u64 = header.iter().map(|b| *b as u64).sum();
+        header[148..154].copy_from_slice(format!("{:06o}", sum).as_bytes());
+        header[154] = 0;
+
+        self.out.write_all(&header)
+    }
+
+    pub fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)
+    }
+
+    /// Fill the last block of `len` bytes of data
+    pub fn pad(&mut self, len: u64) -> io::Result<()> {
+        let rem = (len % BLOCK_SIZE as u64) as usize;
+        if rem != 0 {
+            self.out.write_all(&[0; BLOCK_SIZE][rem..])?;
+        }
+        Ok(())
+    }
+
+    /// End of archive marker, two zero blocks
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0; 2 * BLOCK_SIZE])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+struct Archiver<'a, W: Write> {
+    fs: &'a Fs,
+    tar: TarWriter<W>,
+    /// First archived path of every inode with several links
+    links: HashMap<InodeId, Vec<u8>>,
+    errors: u64,
+}
+
+impl<W: Write> Archiver<'_, W> {
+    fn entry(&self, stat: &Stat, path: Vec<u8>, ty: u8) -> Result<TarEntry> {
+        let mut records = vec![];
+        for (name, value) in self.fs.xattrs(stat.id)? {
+            let mut key = b"SCHILY.xattr.".to_vec();
+            key.extend_from_slice(&name);
+            records.push((key, value));
+        }
+
+        Ok(TarEntry {
+            path,
+            ty,
+            mode: stat.permissions(),
+            uid: stat.uid as u64,
+            gid: stat.gid as u64,
+            size: 0,
+            mtime: stat.mtime.seconds(),
+            mtime_nsec: stat.mtime.nanoseconds(),
+            link: vec![],
+            dev_major: 0,
+            dev_minor: 0,
+            records,
+        })
+    }
+
+    fn visit(&mut self, id: InodeId, path: Vec<u8>) -> Result<()> {
+        let stat = self.fs.stat(id)?;
+
+        if stat.nlink > 1 && stat.file_type() != FileType::Directory {
+            if let Some(first) = self.links.get(&id) {
+                let mut entry = self.entry(&stat, path, TYPE_HARD_LINK)?;
+                entry.link = first.clone();
+                entry.records.clear();
+                return Ok(self.tar.append_header(&entry)?);
+            }
+        }
+
+        match stat.file_type() {
+            FileType::Directory => {
+                // The root of the archive has no entry of its own
+                if !path.is_empty() {
+                    let mut dir_path = path.clone();
+                    dir_path.push(b'/');
+                    let entry = self.entry(&stat, dir_path, TYPE_DIRECTORY)?;
+                    self.tar.append_header(&entry)?;
+                }
+                for child in self.fs.read_dir(id)? {
+                    let target = self.fs.entry_target(id, &child.location, &child.name)?;
+                    let mut child_path = path.clone();
+                    if !child_path.is_empty() {
+                        child_path.push(b'/');
+                    }
+                    child_path.extend_from_slice(&child.name);
+
+                    if let Err(err) = self.visit(target, child_path.clone()) {
+                        eprintln!(
+                            "Skipping {}: {:#}",
+                            String::from_utf8_lossy(&child_path),
+                            err
+                        );
+                        self.errors += 1;
+                    }
+                }
+            }
+            FileType::Regular => self.append_file(&stat, path.clone())?,
+            FileType::Symlink => {
+                let mut entry = self.entry(&stat, path.clone(), TYPE_SYMLINK)?;
+                entry.link = self.fs.readlink(id)?;
+                self.tar.append_header(&entry)?;
+            }
+            FileType::CharDevice | FileType::BlockDevice | FileType::Fifo => {
+                let ty = match stat.file_type() {
+                    FileType::CharDevice => TYPE_CHAR_DEVICE,
+                    FileType::BlockDevice => TYPE_BLOCK_DEVICE,
+                    _ => TYPE_FIFO,
+                };
+                let mut entry = self.entry(&stat, path.clone(), ty)?;
+                entry.dev_major = stat.rdev_major();
+                entry.dev_minor = stat.rdev_minor();
+                self.tar.append_header(&entry)?;
+            }
+            FileType::Socket | FileType::Unknown => {
+                eprintln!(
+                    "{}: {} ignored",
+                    String::from_utf8_lossy(&path),
+                    stat.file_type()
+                );
+                return Ok(());
+            }
+        }
+        if stat.nlink > 1 && stat.file_type() != FileType::Directory {
+            self.links.insert(id, path);
+        }
+        Ok(())
+    }
+
+    /// Regular files, as GNU sparse 1.0 members when they have holes
+    fn append_file(&mut self, stat: &Stat, path: Vec<u8>) -> Result<()> {
+        let id = stat.id;
+
+        // Byte ranges holding data, adjacent extents merged
+        let mut regions: Vec<(u64, u64)> = vec![];
+        for extent in self.fs.file_extents(id, 0, stat.size)? {
+            if !extent.has_data() {
+                continue;
+            }
+            let (start, end) = (extent.file_offset, extent.end().min(stat.size));
+            match regions.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ if start < end => regions.push((start, end)),
+                _ => {}
+            }
+        }
+        let data_size: u64 = regions.iter().map(|(start, end)| end - start).sum();
+        let sparse = data_size != stat.size;
+
+        let mut entry = self.entry(stat, path.clone(), TYPE_REGULAR)?;
+        let mut map = vec![];
+        if sparse {
+            // A last empty region marks where the file ends
+            if regions.last().map(|r| r.1) != Some(stat.size) {
+                regions.push((stat.size, stat.size));
+            }
+            map = format!("{}\n", regions.len()).into_bytes();
+            for (start, end) in &regions {
+                map.extend_from_slice(format!("{}\n{}\n", start, end - start).as_bytes());
+            }
+            map.resize(map.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+
+            let (dir, name) = match path.iter().rposition(|b| *b == b'/') {
+                Some(i) => (&path[..i + 1], &path[i + 1..]),
+                None => (&b""[..], &path[..]),
+            };
+            entry.path = [dir, b"GNUSparseFile.0/", name].concat();
+            entry.records.extend([
+                (b"GNU.sparse.major".to_vec(), b"1".to_vec()),
+                (b"GNU.sparse.minor".to_vec(), b"0".to_vec()),
+                (b"GNU.sparse.name".to_vec(), path.clone()),
+                (
+                    b"GNU.sparse.realsize".to_vec(),
+                    stat.size.to_string().into_bytes(),
+                ),
+            ]);
+        } else {
+            regions = vec![(0, stat.size)];
+        }
+        entry.size = map.len() as u64 + data_size;
+
+        self.tar.append_header(&entry)?;
+        self.tar.write_data(&map)?;
+
+        // Once the header is out, the size is fixed: pad the rest with zeros
+        // on errors to keep the archive readable
+        let mut written = map.len() as u64;
+        let mut result = Ok(());
+        'regions: for (start, end) in regions {
+            let mut offset = start;
+            while offset < end {
+                let len = (end - offset).min(TAR_CHUNK_SIZE);
+                match self.fs.read(id.subvol, id.ino, offset, len) {
+                    Ok(data) if data.len() as u64 == len => self.tar.write_data(&data)?,
+                    Ok(_) => {
+                        result = Err(anyhow::anyhow!("Short read at offset {}", offset));
+                        break 'regions;
+                    }
+                    Err(err) => {
+                        result = Err(err);
+                        break 'regions;
+                    }
+                }
+                written += len;
+                offset += len;
+            }
+        }
+        let total = entry.size;
+        while written < total {
+            let len = (total - written).min(BLOCK_SIZE as u64) as usize;
+            self.tar.write_data(&[0; BLOCK_SIZE][..len])?;
+            written += len as u64;
+        }
+        self.tar.pad(total)?;
+        result
+    }
+}
+
+impl Fs {
+    /// Write the tree below inode `root` to `out` as a pax tar archive, with
+    /// paths relative to `root`. A `root` that isn't a directory is archived
+    /// as `name`. Members that can't be read are reported on stderr and left
+    /// out, or zero filled when their header was already written. Returns
+    /// the number of such errors.
+    pub fn write_tar<W: Write>(&self, root: InodeId, name: &[u8], out: W) -> Result<u64> {
+        let mut archiver = Archiver {
+            fs: self,
+            tar: TarWriter::new(out),
+            links: HashMap::new(),
+            errors: 0,
+        };
+
+        let path = match self.stat(root)?.file_type() {
+            FileType::Directory => vec![],
+            _ => name.to_vec(),
+        };
+        if let Err(err) = archiver.visit(root, path) {
+            eprintln!("{:#}", err);
+            archiver.errors += 1;
+        }
+        archiver.tar.finish()?;
+        Ok(archiver.errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pax_record_length_counts_itself() {
+        assert_eq!(pax_record(b"path", b"a"), b"9 path=a\n");
+        // 7 bytes of digits and key push the length from one to two digits
+        let record = pax_record(b"k", &[b'v'; 6]);
+        assert_eq!(record.len(), 12);
+        assert!(record.starts_with(b"12 k="));
+    }
+
+    #[test]
+    fn splits_long_paths_into_prefix_and_name() {
+        let path = [&[b'd'; 120][..], b"/file"].concat();
+        assert_eq!(
+            split_ustar_path(&path),
+            Some((&b"file"[..], &[b'd'; 120][..]))
+        );
+        assert_eq!(split_ustar_path(&[b'f'; 101]), None);
+    }
+
+    #[test]
+    fn flags_binary_names() {
+        let mut tar = TarWriter::new(vec![]);
+        tar.append_header(&TarEntry {
+            path: b"caf\xe9".to_vec(),
+            ty: TYPE_REGULAR,
+            ..Default::default()
+        })
+        .unwrap();
+        let pax = &tar.out[BLOCK_SIZE..2 * BLOCK_SIZE];
+        let records = [
+            pax_record(b"hdrcharset", b"BINARY"),
+            pax_record(b"path", b"caf\xe9"),
+        ]
+        .concat();
+        assert!(pax.starts_with(&records));
+
+        let mut tar = TarWriter::new(vec![]);
+        tar.append_header(&TarEntry {
+            path: "café".into(),
+            ty: TYPE_REGULAR,
+            ..Default::default()
+        })
+        .unwrap();
+        // UTF-8 names short enough for ustar need no pax header
+        assert_eq!(tar.out.len(), BLOCK_SIZE);
+    }
+}
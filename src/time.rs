@@ -0,0 +1,88 @@
+use crate::structs::BtrfsTimespec;
+use core::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+impl BtrfsTimespec {
+    /// Seconds since the epoch, stored as a signed value on disk
+    pub fn seconds(&self) -> i64 {
+        self.sec as i64
+    }
+
+    pub fn nanoseconds(&self) -> u32 {
+        self.nsec
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.seconds() == 0 && self.nanoseconds() == 0
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        let secs = self.seconds();
+        let nsec = Duration::from_nanos(self.nanoseconds() as u64);
+
+        if secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(secs as u64) + nsec
+        } else {
+            UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nsec
+        }
+    }
+
+    /// `YYYY-MM-DD HH:MM:SS` in UTC, without the sub-second part
+    pub fn date_time(&self) -> String {
+        let secs = self.seconds();
+        let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+        let rem = secs.rem_euclid(86400);
+
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            rem / 3600,
+            rem % 3600 / 60,
+            rem % 60
+        )
+    }
+}
+
+impl fmt::Display for BtrfsTimespec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09} +0000", self.date_time(), self.nanoseconds())
+    }
+}
+
+/// Convert days since 1970-01-01 into a (year, month, day) civil date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Shift the epoch to 0000-03-01 so leap days land at the end of the year
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::structs::BtrfsTimespec;
+
+    #[test]
+    fn formats_utc_dates() {
+        let ts = BtrfsTimespec {
+            sec: 951782400,
+            nsec: 5,
+        };
+        assert_eq!(ts.to_string(), "2000-02-29 00:00:00.000000005 +0000");
+
+        let ts = BtrfsTimespec {
+            sec: -1i64 as u64,
+            nsec: 0,
+        };
+        assert_eq!(ts.date_time(), "1969-12-31 23:59:59");
+    }
+}
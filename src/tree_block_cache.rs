@@ -0,0 +1,266 @@
+use crate::chunk_tree_cache::ChunkTree;
+use crate::csum::compute_csum;
+use crate::structs::*;
+use core::fmt;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::os::unix::prelude::FileExt;
+use std::rc::Rc;
+
+/// Number of tree blocks kept around when no explicit capacity is given
+pub const DEFAULT_CACHE_CAPACITY: usize = 4096;
+
+/// A validated tree block, shared between the cache and its readers
+pub type TreeBlock = Rc<Vec<u8>>;
+
+/// Blocks are identified by their logical address and the generation the
+/// parent pointer expects, so a stale copy is never returned for a newer one
+type BlockKey = (u64, u64);
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tree block cache: {} hits, {} misses, {} evictions",
+            self.hits, self.misses, self.evictions
+        )
+    }
+}
+
+struct Lru {
+    capacity: usize,
+    /// Monotonic counter used to order the entries by last use
+    tick: u64,
+    blocks: HashMap<BlockKey, (TreeBlock, u64)>,
+    /// Last use tick -> block, the first entry is the eviction candidate
+    order: BTreeMap<u64, BlockKey>,
+    stats: CacheStats,
+}
+
+/// Bounded LRU cache of tree blocks shared by all the tree readers.
+///
+/// Readers only hold a shared reference, the bookkeeping lives behind a
+/// `RefCell` so that recursive walks can keep blocks borrowed while reading
+/// their children.
+pub struct TreeBlockCache {
+    lru: RefCell<Lru>,
+}
+
+impl Default for TreeBlockCache {
+    fn default() -> Self {
+        TreeBlockCache::new(DEFAULT_CACHE_CAPACITY)
+    }
+}
+
+impl TreeBlockCache {
+    pub fn new(capacity: usize) -> TreeBlockCache {
+        TreeBlockCache {
+            lru: RefCell::new(Lru {
+                capacity: capacity.max(1),
+                tick: 0,
+                blocks: HashMap::new(),
+                order: BTreeMap::new(),
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lru.borrow().stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.borrow().blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Look up a block and mark it as most recently used
+    pub fn get(&self, logical: u64, generation: u64) -> Option<TreeBlock> {
+        let mut lru = self.lru.borrow_mut();
+        let lru = &mut *lru;
+        let key = (logical, generation);
+
+        lru.tick += 1;
+        let tick = lru.tick;
+        match lru.blocks.get_mut(&key) {
+            Some((block, last_used)) => {
+                lru.order.remove(last_used);
+                *last_used = tick;
+                lru.order.insert(tick, key);
+                lru.stats.hits += 1;
+                Some(block.clone())
+            }
+            None => {
+                lru.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, logical: u64, generation: u64, block: TreeBlock) {
+        let mut lru = self.lru.borrow_mut();
+        let key = (logical, generation);
+
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some((_, last_used)) = lru.blocks.insert(key, (block, tick)) {
+            lru.order.remove(&last_used);
+        }
+        lru.order.insert(tick, key);
+
+        while lru.blocks.len() > lru.capacity {
+            let (_, oldest) = lru.order.pop_first().expect("lru order out of sync");
+            lru.blocks.remove(&oldest);
+            lru.stats.evictions += 1;
+        }
+    }
+
+    /// Return the tree block at `logical`, reading and validating it on a miss.
+    ///
+    /// `generation` is the generation recorded by whoever points at the block
+    /// (the superblock, a root item or a key pointer).
+    pub fn read_tree_block(
+        &self,
+        file: &File,
+        sb: &BtrfsSuperblock,
+        chunk_tree: &ChunkTree,
+        logical: u64,
+        generation: u64,
+    ) -> io::Result<TreeBlock> {
+        if let Some(block) = self.get(logical, generation) {
+            return Ok(block);
+        }
+
+        let physical = chunk_tree.offset(logical).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no chunk maps logical address {}", logical),
+            )
+        })?;
+
+        let mut buf = vec![0; sb.node_size as usize];
+        file.read_exact_at(&mut buf, physical)?;
+        validate_tree_block(&buf, sb, logical, generation)?;
+
+        let block = Rc::new(buf);
+        self.insert(logical, generation, block.clone());
+        Ok(block)
+    }
+}
+
+fn validate_tree_block(
+    buf: &[u8],
+    sb: &BtrfsSuperblock,
+    logical: u64,
+    generation: u64,
+) -> io::Result<()> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    let header = unsafe { &*(buf.as_ptr() as *const BtrfsHeader) };
+
+    let (bytenr, header_gen) = (header.bytenr, header.generation);
+
+    if bytenr != logical {
+        return invalid(format!("tree block {} claims to be at {}", logical, bytenr));
+    }
+    if header_gen != generation {
+        return invalid(format!(
+            "tree block {} has generation {}, expected {}",
+            logical, header_gen, generation
+        ));
+    }
+    let ptr_size = if header.level == 0 {
+        std::mem::size_of::<BtrfsItem>()
+    } else {
+        std::mem::size_of::<BtrfsKeyPtr>()
+    };
+    if std::mem::size_of::<BtrfsHeader>() + header.nritems as usize * ptr_size > buf.len() {
+        return invalid(format!("tree block {} has too many items", logical));
+    }
+    let fsid = if sb.incompat_flags & BTRFS_FEATURE_INCOMPAT_METADATA_UUID != 0 {
+        sb.metadata_uuid
+    } else {
+        sb.fsid
+    };
+    if header.fsid != fsid {
+        return invalid(format!(
+            "tree block {} belongs to another filesystem",
+            logical
+        ));
+    }
+    // Unknown checksum types are errors, a block that can't be checked
+    // isn't trusted
+    let csum = match compute_csum(sb.csum_type, &buf[BTRFS_CSUM_SIZE..]) {
+        Ok(csum) => csum,
+        Err(err) => return invalid(format!("tree block {}: {}", logical, err)),
+    };
+    if header.csum[..csum.len()] != csum[..] {
+        return invalid(format!("tree block {} checksum mismatch", logical));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_tree_block, TreeBlockCache};
+    use crate::structs::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = TreeBlockCache::new(2);
+
+        cache.insert(4096, 7, Rc::new(vec![1]));
+        cache.insert(8192, 7, Rc::new(vec![2]));
+        // Touch the first block so that the second one becomes the oldest
+        assert!(cache.get(4096, 7).is_some());
+        cache.insert(12288, 7, Rc::new(vec![3]));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(8192, 7).is_none());
+        assert_eq!(*cache.get(4096, 7).unwrap(), vec![1]);
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn generation_is_part_of_the_key() {
+        let cache = TreeBlockCache::new(8);
+
+        cache.insert(4096, 7, Rc::new(vec![1]));
+        assert!(cache.get(4096, 8).is_none());
+        assert!(cache.get(4096, 7).is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn checks_tree_block_checksums() {
+        let mut sb = BtrfsSuperblock::new();
+        let mut buf = vec![0; 4096];
+        // bytenr and generation of the header, after the csum and fsid
+        buf[48..56].copy_from_slice(&(1u64 << 20).to_le_bytes());
+        buf[80..88].copy_from_slice(&7u64.to_le_bytes());
+        let csum = crc32c::crc32c(&buf[BTRFS_CSUM_SIZE..]);
+        buf[..4].copy_from_slice(&csum.to_le_bytes());
+
+        assert!(validate_tree_block(&buf, &sb, 1 << 20, 7).is_ok());
+        let mut corrupted = buf.clone();
+        corrupted[1000] ^= 1;
+        assert!(validate_tree_block(&corrupted, &sb, 1 << 20, 7).is_err());
+
+        sb.csum_type = 0xff;
+        assert!(validate_tree_block(&buf, &sb, 1 << 20, 7).is_err());
+    }
+}
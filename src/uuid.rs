@@ -0,0 +1,41 @@
+use crate::structs::BTRFS_UUID_SIZE;
+use anyhow::{bail, Result};
+
+pub type Uuid = [u8; BTRFS_UUID_SIZE];
+
+pub fn is_null_uuid(uuid: &Uuid) -> bool {
+    uuid.iter().all(|b| *b == 0)
+}
+
+/// Canonical 8-4-4-4-12 representation, `-` for the null uuid like btrfs-progs
+pub fn format_uuid(uuid: &Uuid) -> String {
+    if is_null_uuid(uuid) {
+        return "-".to_string();
+    }
+
+    let mut out = String::with_capacity(36);
+    for (i, b) in uuid.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            out.push('-');
+        }
+        out.push_str(&format!("{:02x}", b));
+    }
+    out
+}
+
+pub fn parse_uuid(s: &str) -> Result<Uuid> {
+    let hex: Vec<u8> = s.bytes().filter(|b| *b != b'-').collect();
+    let mut uuid = [0; BTRFS_UUID_SIZE];
+
+    if hex.len() != 2 * BTRFS_UUID_SIZE {
+        bail!("Invalid uuid {}", s);
+    }
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = std::str::from_utf8(pair)?;
+        uuid[i] = match u8::from_str_radix(pair, 16) {
+            Ok(b) => b,
+            Err(_) => bail!("Invalid uuid {}", s),
+        };
+    }
+    Ok(uuid)
+}
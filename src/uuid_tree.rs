@@ -0,0 +1,213 @@
+//! The UUID tree: subvolume ids by uuid and by received uuid, so that send
+//! and receive can match subvolumes across filesystems without going over
+//! every root item.
+//!
+//! It duplicates what the root items say, and the kernel rebuilds it when
+//! `uuid_tree_generation` in the superblock lags behind the filesystem
+//! generation, e.g. after a kernel without UUID tree support mounted it.
+
+use crate::ctree::TreeRoot;
+use crate::fs::Fs;
+use crate::structs::*;
+use crate::subvolume::Subvolume;
+use crate::uuid::{format_uuid, is_null_uuid, Uuid};
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Key of the UUID tree item of `uuid`
+pub fn uuid_key(uuid: &Uuid, ty: u8) -> Result<BtrfsKey> {
+    Ok(BtrfsKey::new(
+        u64::from_le_bytes(uuid[..8].try_into()?),
+        ty,
+        u64::from_le_bytes(uuid[8..].try_into()?),
+    ))
+}
+
+fn uuid_from_key(key: &BtrfsKey) -> Uuid {
+    let mut uuid = [0; BTRFS_UUID_SIZE];
+
+    uuid[..8].copy_from_slice(&{ key.objectid }.to_le_bytes());
+    uuid[8..].copy_from_slice(&{ key.offset }.to_le_bytes());
+    uuid
+}
+
+fn parse_subvol_ids(data: &[u8]) -> Result<Vec<u64>> {
+    if !data.len().is_multiple_of(8) {
+        bail!(
+            "UUID tree item of {} bytes is not a list of ids",
+            data.len()
+        );
+    }
+    data.chunks_exact(8)
+        .map(|id| Ok(u64::from_le_bytes(id.try_into()?)))
+        .collect()
+}
+
+/// "uuid" or "received uuid", for `BTRFS_UUID_KEY_*`
+pub fn uuid_kind_name(ty: u8) -> &'static str {
+    match ty {
+        BTRFS_UUID_KEY_SUBVOL => "uuid",
+        BTRFS_UUID_KEY_RECEIVED_SUBVOL => "received uuid",
+        _ => "unknown",
+    }
+}
+
+/// An item of the UUID tree
+#[derive(Debug, Clone)]
+pub struct UuidEntry {
+    /// `BTRFS_UUID_KEY_SUBVOL` or `BTRFS_UUID_KEY_RECEIVED_SUBVOL`
+    pub ty: u8,
+    pub uuid: Uuid,
+    pub subvols: Vec<u64>,
+}
+
+/// A disagreement between the UUID tree and the root items
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UuidTreeProblem {
+    /// A subvolume whose uuid or received uuid has no entry
+    Missing { ty: u8, uuid: Uuid, subvol: u64 },
+    /// An entry for a subvolume that is gone or has another uuid
+    Stale { ty: u8, uuid: Uuid, subvol: u64 },
+}
+
+impl fmt::Display for UuidTreeProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UuidTreeProblem::Missing { ty, uuid, subvol } => write!(
+                f,
+                "subvolume {} with {} {} has no entry",
+                subvol,
+                uuid_kind_name(*ty),
+                format_uuid(uuid)
+            ),
+            UuidTreeProblem::Stale { ty, uuid, subvol } => write!(
+                f,
+                "entry for {} {} names subvolume {}, which doesn't have it",
+                uuid_kind_name(*ty),
+                format_uuid(uuid),
+                subvol
+            ),
+        }
+    }
+}
+
+impl Fs {
+    pub fn uuid_tree(&self) -> Result<Option<TreeRoot>> {
+        Ok(self
+            .read_root_item(BTRFS_UUID_TREE_OBJECTID)?
+            .map(|root_item| TreeRoot::from(&root_item)))
+    }
+
+    /// Ids of the subvolumes with `uuid` as their uuid or, with
+    /// `BTRFS_UUID_KEY_RECEIVED_SUBVOL`, as their received uuid
+    pub fn lookup_uuid(&self, uuid: &Uuid, ty: u8) -> Result<Vec<u64>> {
+        let root = match self.uuid_tree()? {
+            Some(root) => root,
+            None => bail!("The filesystem has no UUID tree"),
+        };
+        match self.search_tree(root, &uuid_key(uuid, ty)?)? {
+            Some(data) => parse_subvol_ids(&data),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Every item of the UUID tree, in key order
+    pub fn uuid_tree_entries(&self) -> Result<Vec<UuidEntry>> {
+        let root = match self.uuid_tree()? {
+            Some(root) => root,
+            None => bail!("The filesystem has no UUID tree"),
+        };
+        let mut entries = vec![];
+
+        self.walk_tree(
+            root,
+            &BtrfsKey::new(0, 0, 0),
+            &BtrfsKey::new(u64::MAX, u8::MAX, u64::MAX),
+            |key, data| {
+                entries.push(UuidEntry {
+                    ty: key.ty,
+                    uuid: uuid_from_key(key),
+                    subvols: parse_subvol_ids(data)?,
+                });
+                Ok(true)
+            },
+        )?;
+        Ok(entries)
+    }
+
+    /// Live subvolume with uuid `uuid`, found through the UUID tree when
+    /// there is one. Entries are checked against the root items and, as the
+    /// tree may be out of date, root items are scanned when none matches.
+    pub fn subvolume_by_uuid(&self, uuid: &Uuid) -> Result<Option<u64>> {
+        if self.uuid_tree()?.is_some() {
+            for id in self.lookup_uuid(uuid, BTRFS_UUID_KEY_SUBVOL)? {
+                if let Some(root_item) = self.read_root_item(id)? {
+                    if root_item.refs != 0 && Subvolume::new(id, root_item).uuid == *uuid {
+                        return Ok(Some(id));
+                    }
+                }
+            }
+        }
+        Ok(self
+            .subvolumes()?
+            .into_iter()
+            .find(|subvol| subvol.uuid == *uuid)
+            .map(|subvol| subvol.id))
+    }
+
+    /// Compare the UUID tree with the uuids and received uuids of every
+    /// subvolume, the top level included
+    pub fn check_uuid_tree(&self) -> Result<Vec<UuidTreeProblem>> {
+        let mut subvols = self.subvolumes()?;
+        if let Some(root_item) = self.read_root_item(BTRFS_FS_TREE_OBJECTID)? {
+            subvols.push(Subvolume::new(BTRFS_FS_TREE_OBJECTID, root_item));
+        }
+
+        let mut expected = BTreeSet::new();
+        for subvol in &subvols {
+            for (ty, uuid) in [
+                (BTRFS_UUID_KEY_SUBVOL, subvol.uuid),
+                (BTRFS_UUID_KEY_RECEIVED_SUBVOL, subvol.received_uuid),
+            ] {
+                if !is_null_uuid(&uuid) {
+                    expected.insert((ty, uuid, subvol.id));
+                }
+            }
+        }
+        let mut found = BTreeSet::new();
+        for entry in self.uuid_tree_entries()? {
+            for subvol in entry.subvols {
+                found.insert((entry.ty, entry.uuid, subvol));
+            }
+        }
+
+        let missing = expected
+            .difference(&found)
+            .map(|&(ty, uuid, subvol)| UuidTreeProblem::Missing { ty, uuid, subvol });
+        let stale = found
+            .difference(&expected)
+            .map(|&(ty, uuid, subvol)| UuidTreeProblem::Stale { ty, uuid, subvol });
+        Ok(missing.chain(stale).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_uuids_by_halves() {
+        let uuid: Uuid = std::array::from_fn(|i| 0xd0 + i as u8);
+        let key = uuid_key(&uuid, BTRFS_UUID_KEY_RECEIVED_SUBVOL).unwrap();
+
+        assert_eq!({ key.objectid }, 0xd7d6d5d4d3d2d1d0);
+        assert_eq!({ key.offset }, 0xdfdedddcdbdad9d8);
+        assert_eq!(uuid_from_key(&key), uuid);
+
+        let mut data = 257u64.to_le_bytes().to_vec();
+        data.extend_from_slice(&300u64.to_le_bytes());
+        assert_eq!(parse_subvol_ids(&data).unwrap(), [257, 300]);
+        assert!(parse_subvol_ids(&data[1..]).is_err());
+    }
+}
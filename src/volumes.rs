@@ -0,0 +1,193 @@
+//! The chunk tree as a whole: the devices of the filesystem and every chunk
+//! with its profile and stripes, where `ChunkTree` only keeps the logical to
+//! physical map needed for reads.
+
+use crate::ctree::struct_at;
+use crate::fs::Fs;
+use crate::structs::*;
+use crate::uuid::Uuid;
+use anyhow::Result;
+
+const PROFILE_NAMES: [(u64, &str); 8] = [
+    (BTRFS_BLOCK_GROUP_RAID0, "RAID0"),
+    (BTRFS_BLOCK_GROUP_RAID1, "RAID1"),
+    (BTRFS_BLOCK_GROUP_DUP, "DUP"),
+    (BTRFS_BLOCK_GROUP_RAID10, "RAID10"),
+    (BTRFS_BLOCK_GROUP_RAID5, "RAID5"),
+    (BTRFS_BLOCK_GROUP_RAID6, "RAID6"),
+    (BTRFS_BLOCK_GROUP_RAID1C3, "RAID1C3"),
+    (BTRFS_BLOCK_GROUP_RAID1C4, "RAID1C4"),
+];
+
+/// Every profile bit of `BTRFS_BLOCK_GROUP_*`
+pub const BTRFS_BLOCK_GROUP_PROFILE_MASK: u64 = BTRFS_BLOCK_GROUP_RAID0
+    | BTRFS_BLOCK_GROUP_RAID1
+    | BTRFS_BLOCK_GROUP_DUP
+    | BTRFS_BLOCK_GROUP_RAID10
+    | BTRFS_BLOCK_GROUP_RAID5
+    | BTRFS_BLOCK_GROUP_RAID6
+    | BTRFS_BLOCK_GROUP_RAID1C3
+    | BTRFS_BLOCK_GROUP_RAID1C4;
+
+/// Every type bit of `BTRFS_BLOCK_GROUP_*`
+pub const BTRFS_BLOCK_GROUP_TYPE_MASK: u64 =
+    BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_SYSTEM | BTRFS_BLOCK_GROUP_METADATA;
+
+/// Type of a block group as btrfs-progs shows it, "Data+Metadata" for mixed
+/// block groups
+pub fn block_group_type_name(flags: u64) -> &'static str {
+    match flags & BTRFS_BLOCK_GROUP_TYPE_MASK {
+        BTRFS_BLOCK_GROUP_DATA => "Data",
+        BTRFS_BLOCK_GROUP_METADATA => "Metadata",
+        BTRFS_BLOCK_GROUP_SYSTEM => "System",
+        t if t == BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA => "Data+Metadata",
+        _ => "unknown",
+    }
+}
+
+/// RAID profile of a block group, "single" without any profile bit
+pub fn profile_name(flags: u64) -> &'static str {
+    PROFILE_NAMES
+        .iter()
+        .find(|(profile, _)| flags & profile != 0)
+        .map_or("single", |(_, name)| name)
+}
+
+/// A `CHUNK_ITEM`: a range of logical addresses and where its stripes are
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub logical: u64,
+    pub length: u64,
+    /// `BTRFS_BLOCK_GROUP_*` type and profile
+    pub ty: u64,
+    pub sub_stripes: u16,
+    /// (devid, physical offset) of every stripe
+    pub stripes: Vec<(u64, u64)>,
+}
+
+impl Chunk {
+    /// Number of stripes holding distinct data, the others being copies or
+    /// parity
+    pub fn data_stripes(&self) -> u64 {
+        let num_stripes = self.stripes.len() as u64;
+
+        if self.ty & BTRFS_BLOCK_GROUP_RAID0 != 0 {
+            num_stripes
+        } else if self.ty & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+            num_stripes / (self.sub_stripes.max(1) as u64)
+        } else if self.ty & BTRFS_BLOCK_GROUP_RAID5 != 0 {
+            num_stripes.saturating_sub(1)
+        } else if self.ty & BTRFS_BLOCK_GROUP_RAID6 != 0 {
+            num_stripes.saturating_sub(2)
+        } else {
+            1
+        }
+    }
+
+    /// Bytes every stripe takes on its device
+    pub fn stripe_length(&self) -> u64 {
+        self.length / self.data_stripes().max(1)
+    }
+}
+
+pub fn parse_chunk(logical: u64, data: &[u8]) -> Result<Chunk> {
+    let chunk = *struct_at::<BtrfsChunk>(data, 0)?;
+    let first_stripe = std::mem::offset_of!(BtrfsChunk, stripe);
+    let stripe_size = std::mem::size_of::<BtrfsStripe>();
+
+    let mut stripes = vec![];
+    for i in 0..chunk.num_stripes as usize {
+        let stripe = struct_at::<BtrfsStripe>(data, first_stripe + i * stripe_size)?;
+        stripes.push((stripe.devid, stripe.offset));
+    }
+    Ok(Chunk {
+        logical,
+        length: chunk.length,
+        ty: chunk.ty,
+        sub_stripes: chunk.sub_stripes,
+        stripes,
+    })
+}
+
+/// A `DEV_ITEM`
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub devid: u64,
+    pub total_bytes: u64,
+    /// Bytes allocated to chunks, as recorded by the device item
+    pub bytes_used: u64,
+    pub uuid: Uuid,
+}
+
+impl Fs {
+    /// Every chunk, in logical address order
+    pub fn chunks(&self) -> Result<Vec<Chunk>> {
+        let mut chunks = vec![];
+
+        self.walk_tree(
+            self.tree_root(BTRFS_CHUNK_TREE_OBJECTID)?,
+            &BtrfsKey::new(BTRFS_FIRST_CHUNK_TREE_OBJECTID, BTRFS_CHUNK_ITEM_KEY, 0),
+            &BtrfsKey::new(
+                BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+                BTRFS_CHUNK_ITEM_KEY,
+                u64::MAX,
+            ),
+            |key, data| {
+                chunks.push(parse_chunk(key.offset, data)?);
+                Ok(true)
+            },
+        )?;
+        Ok(chunks)
+    }
+
+    /// Every device of the filesystem, by devid
+    pub fn devices(&self) -> Result<Vec<Device>> {
+        let mut devices = vec![];
+
+        self.walk_tree(
+            self.tree_root(BTRFS_CHUNK_TREE_OBJECTID)?,
+            &BtrfsKey::new(BTRFS_DEV_ITEMS_OBJECTID, BTRFS_DEV_ITEM_KEY, 0),
+            &BtrfsKey::new(BTRFS_DEV_ITEMS_OBJECTID, BTRFS_DEV_ITEM_KEY, u64::MAX),
+            |_, data| {
+                let item = struct_at::<BtrfsDevItem>(data, 0)?;
+                devices.push(Device {
+                    devid: item.devid,
+                    total_bytes: item.total_bytes,
+                    bytes_used: item.bytes_used,
+                    uuid: item.uuid,
+                });
+                Ok(true)
+            },
+        )?;
+        Ok(devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_stripe_lengths() {
+        let mut chunk = Chunk {
+            logical: 0,
+            length: 3 << 30,
+            ty: BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID5,
+            sub_stripes: 1,
+            stripes: vec![(1, 0), (2, 0), (3, 0), (4, 0)],
+        };
+        assert_eq!(chunk.stripe_length(), 1 << 30);
+        chunk.ty = BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID10;
+        chunk.sub_stripes = 2;
+        assert_eq!(chunk.stripe_length(), 3 << 29);
+        chunk.ty = BTRFS_BLOCK_GROUP_SYSTEM | BTRFS_BLOCK_GROUP_DUP;
+        assert_eq!(chunk.stripe_length(), 3 << 30);
+
+        assert_eq!(profile_name(chunk.ty), "DUP");
+        assert_eq!(profile_name(BTRFS_BLOCK_GROUP_DATA), "single");
+        assert_eq!(
+            block_group_type_name(BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA),
+            "Data+Metadata"
+        );
+    }
+}
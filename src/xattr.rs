@@ -0,0 +1,314 @@
+use crate::dir::{name_hash, parse_dir_items};
+use crate::fs::Fs;
+use crate::inode::InodeId;
+use crate::structs::*;
+use anyhow::{bail, Result};
+
+pub const XATTR_POSIX_ACL_ACCESS: &[u8] = b"system.posix_acl_access";
+pub const XATTR_POSIX_ACL_DEFAULT: &[u8] = b"system.posix_acl_default";
+pub const XATTR_SECURITY_CAPABILITY: &[u8] = b"security.capability";
+pub const XATTR_BTRFS_COMPRESSION: &[u8] = b"btrfs.compression";
+
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+
+pub const ACL_USER_OBJ: u16 = 0x01;
+pub const ACL_USER: u16 = 0x02;
+pub const ACL_GROUP_OBJ: u16 = 0x04;
+pub const ACL_GROUP: u16 = 0x08;
+pub const ACL_MASK: u16 = 0x10;
+pub const ACL_OTHER: u16 = 0x20;
+
+const VFS_CAP_REVISION_MASK: u32 = 0xff00_0000;
+const VFS_CAP_REVISION_1: u32 = 0x0100_0000;
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x1;
+
+/// Capability names indexed by bit number, from linux/capability.h
+const CAP_NAMES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclEntry {
+    /// `ACL_*` tag
+    pub tag: u16,
+    /// rwx bits
+    pub perm: u16,
+    /// uid or gid for `ACL_USER` and `ACL_GROUP` entries
+    pub id: u32,
+}
+
+impl AclEntry {
+    /// `getfacl` style text, such as `user:1000:rw-`
+    pub fn to_text(&self) -> String {
+        let perm = format!(
+            "{}{}{}",
+            if self.perm & 4 != 0 { 'r' } else { '-' },
+            if self.perm & 2 != 0 { 'w' } else { '-' },
+            if self.perm & 1 != 0 { 'x' } else { '-' }
+        );
+        match self.tag {
+            ACL_USER_OBJ => format!("user::{}", perm),
+            ACL_USER => format!("user:{}:{}", self.id, perm),
+            ACL_GROUP_OBJ => format!("group::{}", perm),
+            ACL_GROUP => format!("group:{}:{}", self.id, perm),
+            ACL_MASK => format!("mask::{}", perm),
+            ACL_OTHER => format!("other::{}", perm),
+            tag => format!("tag({:#x}):{}:{}", tag, self.id, perm),
+        }
+    }
+}
+
+/// Decode the value of `system.posix_acl_access` or `system.posix_acl_default`
+pub fn parse_posix_acl(value: &[u8]) -> Result<Vec<AclEntry>> {
+    if value.len() < 4 || !(value.len() - 4).is_multiple_of(8) {
+        bail!("Invalid POSIX ACL of {} bytes", value.len());
+    }
+    let version = u32::from_le_bytes(value[0..4].try_into()?);
+    if version != POSIX_ACL_XATTR_VERSION {
+        bail!("Unsupported POSIX ACL version {}", version);
+    }
+
+    let mut entries = vec![];
+    for entry in value[4..].chunks_exact(8) {
+        entries.push(AclEntry {
+            tag: u16::from_le_bytes([entry[0], entry[1]]),
+            perm: u16::from_le_bytes([entry[2], entry[3]]),
+            id: u32::from_le_bytes(entry[4..8].try_into()?),
+        });
+    }
+    Ok(entries)
+}
+
+/// File capabilities decoded from `security.capability`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCaps {
+    pub permitted: u64,
+    pub inheritable: u64,
+    pub effective: bool,
+    /// Owner uid of a user namespace, only for revision 3
+    pub rootid: Option<u32>,
+}
+
+impl FileCaps {
+    /// `getcap` style text, such as `cap_net_bind_service=ep`
+    pub fn to_text(&self) -> String {
+        let mut out = vec![];
+
+        for (bit, name) in CAP_NAMES.iter().enumerate() {
+            let p = self.permitted & (1 << bit) != 0;
+            let i = self.inheritable & (1 << bit) != 0;
+            if !p && !i {
+                continue;
+            }
+            let mut flags = String::from("=");
+            if self.effective {
+                flags.push('e');
+            }
+            if i {
+                flags.push('i');
+            }
+            if p {
+                flags.push('p');
+            }
+            out.push(format!("{}{}", name, flags));
+        }
+        if let Some(rootid) = self.rootid {
+            out.push(format!("[rootid={}]", rootid));
+        }
+        out.join(" ")
+    }
+}
+
+/// Decode a `vfs_cap_data` or `vfs_ns_cap_data` structure
+pub fn parse_file_caps(value: &[u8]) -> Result<FileCaps> {
+    let word = |i: usize| -> Result<u32> {
+        match value.get(4 * i..4 * i + 4) {
+            Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into()?)),
+            None => bail!("Truncated security.capability"),
+        }
+    };
+    let magic = word(0)?;
+    let effective = magic & VFS_CAP_FLAGS_EFFECTIVE != 0;
+
+    match magic & VFS_CAP_REVISION_MASK {
+        VFS_CAP_REVISION_1 => Ok(FileCaps {
+            permitted: word(1)? as u64,
+            inheritable: word(2)? as u64,
+            effective,
+            rootid: None,
+        }),
+        rev @ (VFS_CAP_REVISION_2 | VFS_CAP_REVISION_3) => Ok(FileCaps {
+            permitted: word(1)? as u64 | (word(3)? as u64) << 32,
+            inheritable: word(2)? as u64 | (word(4)? as u64) << 32,
+            effective,
+            rootid: if rev == VFS_CAP_REVISION_3 {
+                Some(word(5)?)
+            } else {
+                None
+            },
+        }),
+        rev => bail!("Unknown capability revision {:#x}", rev >> 24),
+    }
+}
+
+/// Human readable form of an xattr value: ACLs and capabilities are decoded,
+/// text is quoted and anything else is shown in hex
+pub fn format_xattr_value(name: &[u8], value: &[u8]) -> String {
+    let decoded = match name {
+        XATTR_POSIX_ACL_ACCESS | XATTR_POSIX_ACL_DEFAULT => {
+            parse_posix_acl(value).ok().map(|acl| {
+                acl.iter()
+                    .map(|entry| entry.to_text())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+        }
+        XATTR_SECURITY_CAPABILITY => parse_file_caps(value).ok().map(|caps| caps.to_text()),
+        _ => None,
+    };
+    if let Some(text) = decoded {
+        return text;
+    }
+
+    // btrfs.compression and most user xattrs are NUL terminated or plain text
+    let text = value.strip_suffix(b"\0").unwrap_or(value);
+    match std::str::from_utf8(text) {
+        Ok(text) if !text.chars().any(|c| c.is_control()) => format!("\"{}\"", text),
+        _ => {
+            let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("0x{}", hex)
+        }
+    }
+}
+
+impl Fs {
+    /// Every (name, value) pair of an inode's extended attributes
+    pub fn xattrs(&self, id: InodeId) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut xattrs = vec![];
+
+        if id.ino == BTRFS_EMPTY_SUBVOL_DIR_OBJECTID {
+            return Ok(xattrs);
+        }
+        self.walk_tree(
+            self.subvolume_root(id.subvol)?,
+            &BtrfsKey::new(id.ino, BTRFS_XATTR_ITEM_KEY, 0),
+            &BtrfsKey::new(id.ino, BTRFS_XATTR_ITEM_KEY, u64::MAX),
+            |_, data| {
+                // Names whose hash collide share the same item
+                for entry in parse_dir_items(data)? {
+                    xattrs.push((entry.name, entry.data));
+                }
+                Ok(true)
+            },
+        )?;
+        Ok(xattrs)
+    }
+
+    pub fn listxattr(&self, id: InodeId) -> Result<Vec<Vec<u8>>> {
+        Ok(self.xattrs(id)?.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Value of xattr `name`, found through the hash of its name
+    pub fn getxattr(&self, id: InodeId, name: &[u8]) -> Result<Option<Vec<u8>>> {
+        if id.ino == BTRFS_EMPTY_SUBVOL_DIR_OBJECTID {
+            return Ok(None);
+        }
+        let key = BtrfsKey::new(id.ino, BTRFS_XATTR_ITEM_KEY, name_hash(name));
+
+        match self.search_tree(self.subvolume_root(id.subvol)?, &key)? {
+            Some(data) => Ok(parse_dir_items(&data)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .map(|entry| entry.data)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_posix_acl() {
+        let mut value = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (ACL_USER_OBJ, 6u16, u32::MAX),
+            (ACL_USER, 4, 1000),
+            (ACL_GROUP_OBJ, 5, u32::MAX),
+            (ACL_MASK, 7, u32::MAX),
+            (ACL_OTHER, 0, u32::MAX),
+        ] {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+
+        assert_eq!(
+            format_xattr_value(XATTR_POSIX_ACL_ACCESS, &value),
+            "user::rw-,user:1000:r--,group::r-x,mask::rwx,other::---"
+        );
+        assert!(parse_posix_acl(&value[..7]).is_err());
+    }
+
+    #[test]
+    fn decodes_file_capabilities() {
+        // cap_net_bind_service and cap_net_raw, effective, revision 2
+        let mut value = vec![];
+        for word in [VFS_CAP_REVISION_2 | 1, (1 << 10) | (1 << 13), 0, 0, 0] {
+            value.extend_from_slice(&u32::to_le_bytes(word));
+        }
+
+        assert_eq!(
+            format_xattr_value(XATTR_SECURITY_CAPABILITY, &value),
+            "cap_net_bind_service=ep cap_net_raw=ep"
+        );
+        assert_eq!(
+            format_xattr_value(XATTR_BTRFS_COMPRESSION, b"zstd"),
+            "\"zstd\""
+        );
+        assert_eq!(format_xattr_value(b"user.bin", &[0, 1]), "0x0001");
+    }
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use btrfs_internals::backref::FileOffset;
+use btrfs_internals::inode::InodeId;
+use btrfs_internals::structs::*;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+
+#[test]
+fn resolves_logical_addresses() {
+    let fs = common::open("test.img");
+    let big = fs.lookup_path(5, OsStr::new("big.bin")).unwrap();
+    let clone = fs.lookup_path(5, OsStr::new("big-clone.bin")).unwrap();
+    let bytenr = fs.file_extents(big, 0, u64::MAX).unwrap()[0].disk_bytenr;
+
+    // Through the shared leaves, the snapshot 258 of the top level has the
+    // same files
+    let at = |subvol, id: InodeId, offset| FileOffset {
+        id: InodeId::new(subvol, id.ino),
+        offset,
+    };
+    assert_eq!(
+        fs.logical_resolve(bytenr + 4096).unwrap(),
+        [
+            at(5, big, 4096),
+            at(5, clone, 4096),
+            at(258, big, 4096),
+            at(258, clone, 4096)
+        ]
+    );
+
+    // Compressed files are reported where their use of the extent starts
+    for name in ["lzo.txt", "zlib.txt", "zstd.txt"] {
+        let id = fs.lookup_path(5, OsStr::new(name)).unwrap();
+        let extent = &fs.file_extents(id, 0, u64::MAX).unwrap()[0];
+        assert_eq!(
+            fs.logical_resolve(extent.disk_bytenr + 100).unwrap(),
+            [at(5, id, 0), at(258, id, 0)]
+        );
+    }
+
+    // Tree blocks aren't data
+    let root = fs.subvolume_root(BTRFS_FS_TREE_OBJECTID).unwrap();
+    assert!(fs.logical_resolve(root.bytenr).is_err());
+}
+
+#[test]
+fn finds_the_trees_using_extents() {
+    let fs = common::open("test.img");
+    let top = fs.subvolume_root(BTRFS_FS_TREE_OBJECTID).unwrap();
+    let snapshot = fs.subvolume_root(258).unwrap();
+
+    // The snapshot has its own root node, and shares everything below it
+    assert_eq!(
+        fs.tree_block_roots(top.bytenr).unwrap(),
+        BTreeSet::from([5])
+    );
+    assert_eq!(
+        fs.tree_block_roots(snapshot.bytenr).unwrap(),
+        BTreeSet::from([258])
+    );
+    let hello = fs.lookup_path(5, OsStr::new("hello")).unwrap();
+    let key = BtrfsKey::new(hello.ino, BTRFS_DIR_INDEX_KEY, 0);
+    let leaf = *fs.search_path(top, &key, 0).unwrap().last().unwrap();
+    assert_eq!(fs.tree_block_roots(leaf).unwrap(), BTreeSet::from([5, 258]));
+
+    let big = fs.lookup_path(5, OsStr::new("big.bin")).unwrap();
+    let bytenr = fs.file_extents(big, 0, u64::MAX).unwrap()[0].disk_bytenr;
+    let extent = fs.lookup_extent(bytenr).unwrap().unwrap();
+    assert_eq!(
+        fs.extent_roots(&extent, &mut HashMap::new()).unwrap(),
+        BTreeSet::from([5, 258])
+    );
+
+    // Subvolume 256 has its own tree
+    let file = fs.lookup_path(256, OsStr::new("in-subvol.txt")).unwrap();
+    let root = fs.subvolume_root(file.subvol).unwrap();
+    assert_eq!(
+        fs.tree_block_roots(root.bytenr).unwrap(),
+        BTreeSet::from([256])
+    );
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use btrfs_internals::structs::*;
+
+const MIXED_DUP: u64 = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP;
+
+#[test]
+fn lists_block_groups() {
+    // The block group items in the extent tree, then in the block group tree
+    for name in ["test.img", "bgtree.img"] {
+        let fs = common::open(name);
+        let block_groups = fs.block_groups().unwrap();
+        let ranges: Vec<_> = block_groups
+            .iter()
+            .map(|block_group| (block_group.start, block_group.length, block_group.flags))
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                (1 << 20, 16 << 20, MIXED_DUP),
+                (17 << 20, 8 << 20, BTRFS_BLOCK_GROUP_DATA)
+            ]
+        );
+
+        // Used bytes are the sum of the extents in the block group
+        let mut used = 0;
+        fs.walk_extents(|extent| {
+            if extent.bytenr < 17 << 20 {
+                used += extent.num_bytes;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(block_groups[0].used, used);
+        assert_eq!(block_groups[1].used, 0);
+    }
+}
+
+#[test]
+fn sums_up_space_usage() {
+    let fs = common::open("test.img");
+    let usage = fs.space_usage().unwrap();
+    let infos: Vec<_> = usage
+        .space_infos
+        .iter()
+        .map(|info| (info.flags, info.total, info.raw_total()))
+        .collect();
+    assert_eq!(
+        infos,
+        [
+            (BTRFS_BLOCK_GROUP_DATA, 8 << 20, 8 << 20),
+            (MIXED_DUP, 16 << 20, 32 << 20)
+        ]
+    );
+    assert_eq!(usage.device_size(), 64 << 20);
+    assert_eq!(usage.device_allocated(), 40 << 20);
+    assert_eq!(usage.device_unallocated(), 24 << 20);
+    assert_eq!(usage.metadata_ratio(), 2.0);
+    assert_eq!(usage.data_ratio(), 40.0 / 24.0);
+}
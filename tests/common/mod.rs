@@ -0,0 +1,37 @@
+//! Filesystem images for the integration tests, written by
+//! `tests/data/mkimg.py` and checked in gzipped.
+//!
+//! `test.img` holds subvolume 256 at `subvols/sv1`, the default subvolume,
+//! its read-only snapshot 257 at `subvols/sv1/snap` with a received uuid and
+//! a snapshot 258 of the top level at `subvols/sv1/top-snap`. The top level
+//! has hard links, reflinks, sparse, compressed and special files, and a few
+//! deliberately damaged ones. `bgtree.img`, `v1cache.img` and
+//! `v1cache-64k.img` are the same filesystem with a block group tree, a v1
+//! space cache, and a v1 space cache written with 64K pages. `mkfs.img`,
+//! written by the kernel, is built by `tests/data/mkfs-image.sh` and not
+//! checked in.
+
+use btrfs_internals::fs::Fs;
+use flate2::read::GzDecoder;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+
+/// Open image `name`, decompressed into the target directory unless an
+/// up to date copy is already there
+pub fn open(name: &str) -> Fs {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let gz = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(format!("{}.gz", name));
+    let modified = |path: &PathBuf| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+
+    if modified(&path) < modified(&gz) {
+        // Tests run in parallel, only ever expose a complete image
+        let tmp = path.with_extension(format!("{:?}", std::thread::current().id()));
+        let mut out = File::create(&tmp).unwrap();
+        io::copy(&mut GzDecoder::new(File::open(gz).unwrap()), &mut out).unwrap();
+        fs::rename(&tmp, &path).unwrap();
+    }
+    Fs::open(path).unwrap()
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use btrfs_internals::structs::*;
+use std::ffi::OsStr;
+
+/// Check `name` and its inline sibling read back as written by the generator.
+/// The regular extent starts 100 bytes into its decompressed data.
+fn check_compressed_file(name: &str, compression: u8) {
+    let fs = common::open("test.img");
+    let id = fs.lookup_path(5, OsStr::new(name)).unwrap();
+    let extents = fs.file_extents(id, 0, u64::MAX).unwrap();
+    assert_eq!(extents.len(), 1);
+    assert_eq!(extents[0].compression, compression);
+    assert_eq!(extents[0].offset, 100);
+
+    let expected: Vec<u8> = (0..2000)
+        .flat_map(|i| format!("line {:05} of {}\n", i, name).into_bytes())
+        .skip(100)
+        .collect();
+    assert_eq!(fs.read(5, id.ino, 0, u64::MAX).unwrap(), expected);
+    // Reads from the middle of the extent
+    assert_eq!(
+        fs.read(5, id.ino, 5000, 1000).unwrap(),
+        expected[5000..6000]
+    );
+
+    let inline = format!("{}.inline", name);
+    let id = fs.lookup_path(5, OsStr::new(&inline)).unwrap();
+    let extents = fs.file_extents(id, 0, u64::MAX).unwrap();
+    assert_eq!(extents[0].ty, BTRFS_FILE_EXTENT_INLINE);
+    assert_eq!(extents[0].compression, compression);
+    assert_eq!(
+        fs.read(5, id.ino, 0, u64::MAX).unwrap(),
+        format!("inline {}\n", name).repeat(20).into_bytes()
+    );
+}
+
+#[test]
+fn reads_zlib_files() {
+    check_compressed_file("zlib.txt", BTRFS_COMPRESS_ZLIB);
+
+    // A corrupt stream is an error, not garbage
+    let fs = common::open("test.img");
+    let id = fs.lookup_path(5, OsStr::new("zlib-corrupt.txt")).unwrap();
+    let err = fs.read(5, id.ino, 0, u64::MAX).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("Corrupt zlib stream"),
+        "{:#}",
+        err
+    );
+}
+
+#[test]
+fn reads_lzo_files() {
+    check_compressed_file("lzo.txt", BTRFS_COMPRESS_LZO);
+
+    // Segments of literals, long runs and far matches
+    let fs = common::open("test.img");
+    let id = fs.lookup_path(5, OsStr::new("lzo-mixed.bin")).unwrap();
+    let data = fs.read(5, id.ino, 0, u64::MAX).unwrap();
+    assert_eq!(data.len(), 20000 + 3000 + 5000 + 600 + 4);
+    assert_eq!(data[20000..23000], [b'z'; 3000]);
+    assert_eq!(data[23000..28000], data[..5000]);
+    assert_eq!(data[28000..28200], data[100..300]);
+    assert_eq!(data[28400..28600], data[100..300]);
+    assert!(data.ends_with(b"tail"));
+}
+
+#[test]
+fn reads_zstd_files() {
+    check_compressed_file("zstd.txt", BTRFS_COMPRESS_ZSTD);
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use btrfs_internals::structs::*;
+use std::ffi::OsStr;
+
+fn all_keys() -> (BtrfsKey, BtrfsKey) {
+    (
+        BtrfsKey::new(0, 0, 0),
+        BtrfsKey::new(u64::MAX, u8::MAX, u64::MAX),
+    )
+}
+
+#[test]
+fn walks_trees() {
+    let fs = common::open("test.img");
+    let root = fs.subvolume_root(BTRFS_FS_TREE_OBJECTID).unwrap();
+    let (min, max) = all_keys();
+
+    let mut keys = vec![];
+    fs.walk_tree(root, &min, &max, |key, _| {
+        keys.push(*key);
+        Ok(true)
+    })
+    .unwrap();
+    assert_eq!(keys.len(), 462);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // Only the items of one inode, across leaves
+    let hello = fs.lookup_path(5, OsStr::new("hello")).unwrap();
+    let mut items = vec![];
+    fs.walk_tree(
+        root,
+        &BtrfsKey::new(hello.ino, 0, 0),
+        &BtrfsKey::new(hello.ino, u8::MAX, u64::MAX),
+        |key, _| {
+            items.push(*key);
+            Ok(true)
+        },
+    )
+    .unwrap();
+    let expected: Vec<_> = keys
+        .iter()
+        .filter(|key| key.objectid == hello.ino)
+        .copied()
+        .collect();
+    assert_eq!(items, expected);
+
+    // The visitor stops the walk
+    let mut visited = 0;
+    fs.walk_tree(root, &min, &max, |_, _| {
+        visited += 1;
+        Ok(visited < 3)
+    })
+    .unwrap();
+    assert_eq!(visited, 3);
+}
+
+#[test]
+fn reads_multi_level_root_trees() {
+    let fs = common::open("test.img");
+    let root = fs.root_tree();
+
+    // The root tree of the image has leaves of 3 items, under 2 levels of nodes
+    let key = BtrfsKey::new(258, BTRFS_ROOT_ITEM_KEY, 0);
+    let path = fs.search_path(root, &key, 0).unwrap();
+    assert_eq!(path.len(), 3);
+    assert_eq!(path[0], root.bytenr);
+    assert_eq!(fs.search_path(root, &key, 1).unwrap(), path[..2]);
+
+    for id in [BTRFS_FS_TREE_OBJECTID, 256, 257, 258] {
+        let root_item = fs.read_root_item(id).unwrap().unwrap();
+        assert_eq!({ root_item.root_dirid }, BTRFS_FIRST_FREE_OBJECTID as u64);
+    }
+    assert!(fs.read_root_item(300).unwrap().is_none());
+}
+
+#[test]
+fn searches_paths() {
+    let fs = common::open("test.img");
+    let root = fs.subvolume_root(BTRFS_FS_TREE_OBJECTID).unwrap();
+    let hello = fs.lookup_path(5, OsStr::new("hello")).unwrap();
+    let key = BtrfsKey::new(hello.ino, BTRFS_DIR_INDEX_KEY, 0);
+
+    let path = fs.search_path(root, &key, 0).unwrap();
+    assert_eq!(path.len(), 3);
+    assert_eq!(path[0], root.bytenr);
+
+    // The snapshot of the top level shares everything below its root
+    let snapshot = fs.subvolume_root(258).unwrap();
+    let snapshot_path = fs.search_path(snapshot, &key, 0).unwrap();
+    assert_eq!(snapshot_path[0], snapshot.bytenr);
+    assert_eq!(snapshot_path[1..], path[1..]);
+}
@@ -0,0 +1,82 @@
+"""Small greedy LZO1X encoder, using M2/M3/M4 matches and long runs."""
+import struct
+
+def _run(out, n):
+    while n > 255:
+        out.append(0); n -= 255
+    out.append(n)
+
+def compress_lzo1x(data):
+    out = bytearray()
+    table = {}
+    i = lit = 0
+    last_match = None  # index in out of the byte holding `next`
+    first = True
+
+    def emit_literals(end):
+        nonlocal lit, first, last_match
+        n = end - lit
+        if n == 0:
+            return
+        if first and n <= 238:
+            out.append(17 + n)
+        elif n <= 3 and last_match is not None:
+            out[last_match] |= n
+        elif n <= 18:
+            out.append(n - 3)
+        else:
+            out.append(0); _run(out, n - 18)
+        out.extend(data[lit:end])
+        first = False
+        lit = end
+
+    while i + 3 <= len(data):
+        k = data[i:i + 3]
+        cand = table.get(k)
+        table[k] = i
+        if cand is None or i - cand > 49151:
+            i += 1
+            continue
+        d = i - cand
+        n = 3
+        while i + n < len(data) and data[cand + n] == data[i + n] and n < 2000:
+            n += 1
+        emit_literals(i)
+        first = False
+        if n <= 8 and d <= 2048:
+            D = d - 1
+            out.append(((n - 1) << 5) | ((D & 7) << 2)); last_match = len(out) - 1
+            out.append(D >> 3)
+        elif d <= 16384:
+            D = d - 1
+            if n <= 33:
+                out.append(32 | (n - 2))
+            else:
+                out.append(32); _run(out, n - 33)
+            last_match = len(out)
+            out += struct.pack('<H', D << 2)
+        else:
+            D = d - 16384
+            hi = (D & 0x4000) >> 11
+            if n <= 9:
+                out.append(16 | hi | (n - 2))
+            else:
+                out.append(16 | hi); _run(out, n - 9)
+            last_match = len(out)
+            out += struct.pack('<H', (D & 0x3fff) << 2)
+        i += n
+        lit = i
+    emit_literals(len(data))
+    out += b'\x11\x00\x00'
+    return bytes(out)
+
+def compress_btrfs(data, sector=4096):
+    out = bytearray(4)
+    for off in range(0, len(data), sector):
+        seg = compress_lzo1x(data[off:off + sector])
+        left = sector - len(out) % sector
+        if left < 4:
+            out += bytes(left)
+        out += struct.pack('<I', len(seg)) + seg
+    out[0:4] = struct.pack('<I', len(out))
+    return bytes(out)
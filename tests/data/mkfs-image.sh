@@ -0,0 +1,40 @@
+#!/bin/sh
+# Build mkfs.img.gz, a filesystem written by mkfs.btrfs and the kernel, for
+# the ignored tests of tests/mkfs.rs. Unlike the images of mkimg.py it shows
+# whether we read what btrfs itself writes. Needs root, a loop device,
+# btrfs-progs and a kernel with btrfs and zlib, lzo and zstd support.
+#
+#     sudo sh tests/data/mkfs-image.sh
+#     cargo test --test mkfs -- --ignored
+#
+# Layout: subvolume sv with zlib.txt, lzo.txt and zstd.txt, compressed with
+# compress-force, plain.bin and its reflink plain-clone.bin, a read-only
+# snapshot sv-snap of it, and quotas enabled and rescanned.
+set -eu
+
+cd "$(dirname "$0")"
+img=$PWD/mkfs.img
+mnt=$(mktemp -d)
+trap 'umount "$mnt" 2>/dev/null || true; rmdir "$mnt"' EXIT
+
+rm -f "$img" "$img.gz"
+truncate -s 128M "$img"
+mkfs.btrfs -q -f -m dup -d single "$img"
+mount -o loop "$img" "$mnt"
+
+btrfs -q quota enable "$mnt"
+btrfs -q subvolume create "$mnt/sv"
+for alg in zlib lzo zstd; do
+    mount -o remount,compress-force=$alg "$mnt"
+    seq -f "line %05g of $alg.txt" 0 1999 > "$mnt/sv/$alg.txt"
+    sync
+done
+mount -o remount,compress=no "$mnt"
+seq -f "plain %06g" 0 99999 > "$mnt/sv/plain.bin"
+cp --reflink=always "$mnt/sv/plain.bin" "$mnt/sv/plain-clone.bin"
+btrfs -q subvolume snapshot -r "$mnt/sv" "$mnt/sv-snap"
+sync
+btrfs -q quota rescan -w "$mnt"
+
+umount "$mnt"
+gzip -9n "$img"
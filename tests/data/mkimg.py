@@ -0,0 +1,695 @@
+#!/usr/bin/env python3
+"""Tiny synthetic btrfs image generator for the tests of btrfs-internals.
+
+Writes a 64MiB single device filesystem with a DUP data+metadata chunk and a
+SINGLE data chunk: subvolumes, a snapshot, reflinks, compressed files, a
+qgroup hierarchy, a UUID tree and a free space tree, or a v1 space cache with
+SPACE_CACHE=1. BG_TREE=1 moves the block group items to the block group tree.
+PAGE_SIZE=65536 writes the v1 cache as a 64K page kernel would. The default
+subvolume is 256, override with DEFAULT_SUBVOL. Some damage is deliberate,
+see the comments. Needs zstd in $PATH.
+
+    python3 mkimg.py test.img && gzip -9 test.img
+"""
+import struct, sys, os, zlib
+
+# ---------------- crc32c -----------------
+_T = []
+for i in range(256):
+    c = i
+    for _ in range(8):
+        c = (c >> 1) ^ 0x82F63B78 if c & 1 else c >> 1
+    _T.append(c)
+
+def crc32c_raw(seed, data):
+    c = seed
+    for b in data:
+        c = _T[(c ^ b) & 0xff] ^ (c >> 8)
+    return c & 0xffffffff
+
+def crc32c(data):
+    return crc32c_raw(0xffffffff, data) ^ 0xffffffff
+
+def name_hash(name):
+    return crc32c_raw(0xfffffffe, name)
+
+# -------------- constants ----------------
+NODESIZE = 4096
+SECTOR = 4096
+FSID = bytes(range(1, 17))
+CHUNK_UUID = bytes(range(17, 33))
+DEV_UUID = bytes(range(33, 49))
+IMG_SIZE = 64 << 20
+CHUNK_START = 1 << 20
+MIRROR_DELTA = 32 << 20
+MIXED_LEN = 16 << 20
+DATA_CHUNK, DATA_LEN = 17 << 20, 8 << 20
+DEV_BYTES_USED = 2 * MIXED_LEN + DATA_LEN
+
+INODE_ITEM, INODE_REF, INODE_EXTREF, XATTR_ITEM = 1, 12, 13, 24
+DIR_ITEM, DIR_INDEX, EXTENT_DATA, EXTENT_CSUM = 84, 96, 108, 128
+ROOT_ITEM, ROOT_BACKREF, ROOT_REF = 132, 144, 156
+EXTENT_ITEM, METADATA_ITEM = 168, 169
+TREE_BLOCK_REF, EXTENT_DATA_REF, SHARED_BLOCK_REF, SHARED_DATA_REF = 176, 178, 182, 184
+BLOCK_GROUP_ITEM = 192
+DEV_EXTENT, DEV_ITEM, CHUNK_ITEM = 204, 216, 228
+UUID_SUBVOL, UUID_RECEIVED = 251, 252
+FREE_SPACE_INFO, FREE_SPACE_EXTENT, FREE_SPACE_BITMAP = 198, 199, 200
+FREE_SPACE_OBJECTID = (1 << 64) - 11
+QGROUP_STATUS, QGROUP_INFO, QGROUP_LIMIT, QGROUP_RELATION = 240, 242, 244, 246
+EXTENT_CSUM_OBJECTID = (1 << 64) - 10
+
+FT = {'reg': 1, 'dir': 2, 'chr': 3, 'blk': 4, 'fifo': 5, 'sock': 6, 'lnk': 7}
+S_IF = {'reg': 0o100000, 'dir': 0o040000, 'chr': 0o020000, 'blk': 0o060000,
+        'fifo': 0o010000, 'sock': 0o140000, 'lnk': 0o120000}
+
+def key(o, t, off):
+    return struct.pack('<QBQ', o, t, off)
+
+def timespec(sec, nsec=0):
+    return struct.pack('<qI', sec, nsec)
+
+def inode_item(gen, size, nbytes, nlink, uid, gid, mode, rdev=0, flags=0, t=(1600000000, 1)):
+    return struct.pack('<QQQQQIIIIQQQ32x', gen, gen, size, nbytes, 0, nlink, uid, gid, mode, rdev, flags, 0) + \
+        timespec(*t) * 4
+
+class Alloc:
+    def __init__(self):
+        self.next = CHUNK_START
+    def get(self, size):
+        a = self.next
+        self.next += (size + SECTOR - 1) // SECTOR * SECTOR
+        return a
+
+class Img:
+    def __init__(self):
+        self.buf = bytearray(IMG_SIZE)
+        self.alloc = Alloc()
+        self.gen = 10
+        self.tree_blocks = []  # (bytenr, owner, level, keys)
+    def write(self, off, data):
+        self.buf[off:off + len(data)] = data
+        # DUP: the chunk's second stripe sits MIRROR_DELTA further on disk
+        if off >= CHUNK_START:
+            self.buf[off + MIRROR_DELTA:off + MIRROR_DELTA + len(data)] = data
+
+def header(bytenr, owner, nritems, level, gen):
+    return struct.pack('<32s16sQQ16sQQIB', b'\0' * 32, FSID, bytenr, 1, CHUNK_UUID, gen, owner, nritems, level)
+
+HDR = 101
+ITEM = 25
+KP = 33
+
+def build_tree(img, owner, items, max_items=None):
+    """items: list of (keytuple, data). Returns (bytenr, level)."""
+    items = sorted(items, key=lambda x: x[0])
+    # split into leaves by space
+    leaves = []
+    cur = []
+    used = 0
+    for k, d in items:
+        need = ITEM + len(d)
+        if cur and (used + need > NODESIZE - HDR or (max_items and len(cur) >= max_items)):
+            leaves.append(cur)
+            cur, used = [], 0
+        cur.append((k, d))
+        used += need
+    leaves.append(cur)
+    level_nodes = []
+    for leaf in leaves:
+        bytenr = img.alloc.get(NODESIZE)
+        blk = bytearray(NODESIZE)
+        data_end = NODESIZE - HDR
+        for i, (k, d) in enumerate(leaf):
+            data_end -= len(d)
+            blk[HDR + i * ITEM: HDR + (i + 1) * ITEM] = key(*k) + struct.pack('<II', data_end, len(d))
+            blk[HDR + data_end: HDR + data_end + len(d)] = d
+        blk[0:HDR] = header(bytenr, owner, len(leaf), 0, img.gen)
+        level_nodes.append((leaf[0][0] if leaf else (0, 0, 0), bytenr, blk))
+        img.tree_blocks.append((bytenr, owner, 0, [k for k, _ in leaf]))
+    level = 0
+    per_node = max_items or (NODESIZE - HDR) // KP
+    while True:
+        for first, bytenr, blk in level_nodes:
+            blk[0:HDR] = header(bytenr, owner, struct.unpack_from('<I', blk, 96)[0], level, img.gen)
+            blk[0:32] = struct.pack('<I', crc32c(bytes(blk[32:]))) + b'\0' * 28
+            img.write(bytenr, blk)
+        if len(level_nodes) == 1:
+            return level_nodes[0][1], level
+        level += 1
+        new = []
+        for i in range(0, len(level_nodes), per_node):
+            group = level_nodes[i:i + per_node]
+            bytenr = img.alloc.get(NODESIZE)
+            blk = bytearray(NODESIZE)
+            for j, (first, cb, _) in enumerate(group):
+                blk[HDR + j * KP: HDR + (j + 1) * KP] = key(*first) + struct.pack('<QQ', cb, img.gen)
+            blk[0:HDR] = header(bytenr, owner, len(group), level, img.gen)
+            new.append((group[0][0], bytenr, blk))
+            img.tree_blocks.append((bytenr, owner, level, [group[0][0]]))
+        level_nodes = new
+
+def root_item(gen, dirid, bytenr, level, uuid=b'\0' * 16, parent_uuid=b'\0' * 16, received=b'\0' * 16,
+              flags=0, ctransid=0, otransid=0, otime=(0, 0), refs=1):
+    inode = inode_item(1, 3, NODESIZE, 1, 0, 0, 0o40755)
+    return inode + struct.pack('<QQQQQQQI', gen, dirid, bytenr, 0, NODESIZE, 0, flags, refs) + \
+        key(0, 0, 0) + struct.pack('<BBQ', 0, level, gen) + uuid + parent_uuid + received + \
+        struct.pack('<QQQQ', ctransid, otransid, 0, 0) + timespec(0) + timespec(*otime) + timespec(0) + \
+        timespec(0) + b'\0' * 64
+
+def dir_entry(loc, ty, name, data=b''):
+    return key(*loc) + struct.pack('<QHHB', 10, len(data), len(name), ty) + name + data
+
+def inode_ref(index, name):
+    return struct.pack('<QH', index, len(name)) + name
+
+def root_ref(dirid, seq, name):
+    return struct.pack('<QQH', dirid, seq, len(name)) + name
+
+def file_extent_inline(data, ram=None, comp=0):
+    return struct.pack('<QQBBHB', 10, ram if ram is not None else len(data), comp, 0, 0, 0) + data
+
+def file_extent_reg(disk_bytenr, disk_num, offset, num, ram, comp=0, ty=1):
+    return struct.pack('<QQBBHB', 10, ram, comp, 0, 0, ty) + struct.pack('<QQQQ', disk_bytenr, disk_num, offset, num)
+
+class Tree:
+    """A subvolume fs tree in the making."""
+    def __init__(self, img, objectid):
+        self.img = img
+        self.id = objectid
+        self.items = []
+        self.next_ino = 257
+        self.next_index = {}
+        self.inodes = {}
+        self.mkinode(256, 'dir', 0o755)
+        self.items.append(((256, INODE_REF, 256), inode_ref(0, b'..')))
+        self.data_extents = []  # (bytenr, len, ino, offset)
+
+    def mkinode(self, ino, kind, perm, uid=0, gid=0, rdev=0, flags=0, size=0, nbytes=0):
+        self.inodes[ino] = dict(kind=kind, perm=perm, uid=uid, gid=gid, rdev=rdev, flags=flags, size=size,
+                                nbytes=nbytes, nlink=1 if kind != 'dir' else 1)
+        return ino
+
+    def link(self, parent, name, ino, kind, extref=False):
+        idx = self.next_index.get(parent, 2)
+        self.next_index[parent] = idx + 1
+        h = name_hash(name)
+        entry = dir_entry((ino, INODE_ITEM, 0), FT[kind], name)
+        # names with the same hash share one DIR_ITEM
+        for i, (k, d) in enumerate(self.items):
+            if k == (parent, DIR_ITEM, h):
+                self.items[i] = (k, d + entry)
+                break
+        else:
+            self.items.append(((parent, DIR_ITEM, h), entry))
+        self.items.append(((parent, DIR_INDEX, idx), entry))
+        if extref:
+            h2 = crc32c_raw(parent & 0xffffffff, name)
+            self.items.append(((ino, INODE_EXTREF, h2), struct.pack('<QQH', parent, idx, len(name)) + name))
+        else:
+            # merge into existing ref item
+            for i, (k, d) in enumerate(self.items):
+                if k == (ino, INODE_REF, parent):
+                    self.items[i] = (k, d + inode_ref(idx, name))
+                    break
+            else:
+                self.items.append(((ino, INODE_REF, parent), inode_ref(idx, name)))
+        self.inodes[parent]['size'] += 2 * len(name)
+        return idx
+
+    def mkdir(self, parent, name, perm=0o755):
+        ino = self.next_ino; self.next_ino += 1
+        self.mkinode(ino, 'dir', perm)
+        self.link(parent, name, ino, 'dir')
+        return ino
+
+    def mkfile(self, parent, name, data=b'', inline=True, perm=0o644, flags=0, extents=None, size=None):
+        ino = self.next_ino; self.next_ino += 1
+        self.mkinode(ino, 'reg', perm, flags=flags, size=len(data) if size is None else size)
+        self.link(parent, name, ino, 'reg')
+        if extents is not None:
+            for off, item in extents:
+                self.items.append(((ino, EXTENT_DATA, off), item))
+        elif data:
+            if inline:
+                self.items.append(((ino, EXTENT_DATA, 0), file_extent_inline(data)))
+            else:
+                sz = (len(data) + SECTOR - 1) // SECTOR * SECTOR
+                bytenr = self.img.alloc.get(sz)
+                self.img.write(bytenr, data)
+                self.data_extents.append((bytenr, sz, ino, 0))
+                self.items.append(((ino, EXTENT_DATA, 0), file_extent_reg(bytenr, sz, 0, sz, sz)))
+                self.inodes[ino]['nbytes'] = sz
+        return ino
+
+    def special(self, parent, name, kind, perm=0o644, rdev=0, target=None):
+        ino = self.next_ino; self.next_ino += 1
+        self.mkinode(ino, kind, perm, rdev=rdev, size=len(target) if target else 0)
+        self.link(parent, name, ino, kind)
+        if target is not None:
+            self.items.append(((ino, EXTENT_DATA, 0), file_extent_inline(target)))
+        return ino
+
+    def hardlink(self, parent, name, ino, extref=False):
+        self.inodes[ino]['nlink'] += 1
+        self.link(parent, name, ino, self.inodes[ino]['kind'], extref)
+
+    def xattr(self, ino, name, value):
+        h = name_hash(name)
+        d = dir_entry((0, 0, 0), 8, name, value)
+        for i, (k, old) in enumerate(self.items):
+            if k == (ino, XATTR_ITEM, h):
+                self.items[i] = (k, old + d)
+                return
+        self.items.append(((ino, XATTR_ITEM, h), d))
+
+    def subvol_link(self, parent, name, subvol_id):
+        idx = self.next_index.get(parent, 2)
+        self.next_index[parent] = idx + 1
+        h = name_hash(name)
+        self.items.append(((parent, DIR_ITEM, h), dir_entry((subvol_id, ROOT_ITEM, 0xffffffffffffffff), FT['dir'], name)))
+        self.items.append(((parent, DIR_INDEX, idx), dir_entry((subvol_id, ROOT_ITEM, 0xffffffffffffffff), FT['dir'], name)))
+        self.inodes[parent]['size'] += 2 * len(name)
+        return idx
+
+    def finish(self, max_items=None):
+        for ino, i in self.inodes.items():
+            mode = S_IF[i['kind']] | i['perm']
+            self.items.append(((ino, INODE_ITEM, 0), inode_item(10, i['size'], i['nbytes'], i['nlink'], i['uid'], i['gid'],
+                                                               mode, i['rdev'], i['flags'],
+                                                               (1600000000 + ino, 123456789))))
+        return build_tree(self.img, self.id, self.items, max_items)
+
+def chunk_item(length, nstripes=1, ty=1 | 0, stripe_off=CHUNK_START):
+    c = struct.pack('<QQQQIIIHH', length, 2, 65536, ty, 4096, 4096, 4096, nstripes, 0)
+    for i in range(nstripes):
+        c += struct.pack('<QQ', 1, stripe_off + i * MIRROR_DELTA) + DEV_UUID
+    return c
+
+def dev_item():
+    return struct.pack('<QQQIIIQQQIBB', 1, IMG_SIZE, DEV_BYTES_USED, 4096, 4096, 4096, 0, 0, 0, 0, 0, 0) + DEV_UUID + FSID
+
+EXTENT_FLAG_DATA, EXTENT_FLAG_TREE_BLOCK = 1, 2
+EXTENT_OWNER_REF = 172
+
+def extent_data_ref_hash(root, objectid, offset):
+    high = crc32c_raw(0xffffffff, struct.pack('<Q', root))
+    low = crc32c_raw(0xffffffff, struct.pack('<Q', objectid))
+    low = crc32c_raw(low, struct.pack('<Q', offset))
+    return (high << 31) ^ low
+
+def find_leaf(img, owner, k):
+    for bytenr, o, level, keys in img.tree_blocks:
+        if o == owner and level == 0 and k in keys:
+            return bytenr
+    raise KeyError(k)
+
+def free_ranges(used, start, length):
+    """used: (bytenr, size) pairs. Free ranges of [start, start + length)."""
+    free, pos = [], start
+    for bytenr, sz in sorted(u for u in used if start <= u[0] < start + length):
+        if bytenr > pos:
+            free.append((pos, bytenr - pos))
+        pos = max(pos, bytenr + sz)
+    if pos < start + length:
+        free.append((pos, start + length - pos))
+    return free
+
+def used_ranges(top, blocks, cache_extents):
+    return list({bytenr: sz for bytenr, sz, _, _ in top.data_extents}.items()) + \
+        [(b[0], NODESIZE) for b in blocks] + [(bytenr, sz) for bytenr, sz, _ in cache_extents]
+
+BITMAP_RANGE = 8 * 256 * SECTOR
+
+def free_space_tree_items(free, start, length, bitmaps):
+    items = [((start, FREE_SPACE_INFO, length), struct.pack('<II', len(free), 1 if bitmaps else 0))]
+    if not bitmaps:
+        return items + [((s, FREE_SPACE_EXTENT, l), b'') for s, l in free]
+    for at in range(start, start + length, BITMAP_RANGE):
+        n = min(BITMAP_RANGE, start + length - at)
+        bits = bytearray((n // SECTOR + 7) // 8)
+        for s, l in free:
+            for sector in range(max(s, at), min(s + l, at + n), SECTOR):
+                i = (sector - at) // SECTOR
+                bits[i // 8] |= 1 << (i % 8)
+        items.append(((at, FREE_SPACE_BITMAP, n), bytes(bits)))
+    return items
+
+def space_cache_file(gen, free, start, pages, bitmap):
+    """v1 cache: crcs of every page and the generation, entries, then bitmap
+    pages, in pages of the writing kernel"""
+    body = bytearray(pages * PAGE)
+    off = 4 * pages + 8
+    struct.pack_into('<Q', body, 4 * pages, gen)
+    if bitmap:
+        entries = [(start, sum(l for _, l in free), 2)]
+    else:
+        entries = [(s, l, 1) for s, l in free]
+    for s, l, t in entries:
+        if off + 17 > PAGE * (off // PAGE + 1):
+            off = (off // PAGE + 1) * PAGE
+        struct.pack_into('<QQB', body, off, s, l, t)
+        off += 17
+    if bitmap:
+        page = (off - 1) // PAGE + 1
+        for s, l in free:
+            for sector in range(s, s + l, SECTOR):
+                i = (sector - start) // SECTOR
+                body[page * PAGE + i // 8] |= 1 << (i % 8)
+    for i in range(pages):
+        skip = 4 * pages if i == 0 else 0
+        struct.pack_into('<I', body, 4 * i, crc32c(bytes(body[i * PAGE + skip:(i + 1) * PAGE])))
+    return bytes(body), len(entries), 1 if bitmap else 0
+
+def qgroup_items(img, top, top_root, blocks):
+    """Quota tree matching what backrefs say about the fs trees, with a stale
+    exclusive count for 0/257"""
+    snap_block = next(b for b in blocks if b[1] == 257)[0]
+    extents = []
+    for bytenr, owner, _, _ in blocks:
+        if owner == 5:
+            # the top-snap copy of the root node shares everything below it
+            roots = {5} if bytenr == top_root else {5, 258}
+        elif owner == 257 and bytenr == snap_block:
+            roots = {256, 257}
+        elif owner in (256, 257, 258):
+            roots = {owner}
+        else:
+            continue
+        extents.append((NODESIZE, roots))
+    for sz in {bytenr: sz for bytenr, sz, _, _ in top.data_extents}.values():
+        extents.append((sz, {5, 258}))
+    group = (1 << 48) | 100
+    members = {5: {5}, 256: {256}, 257: {257}, 258: {258}, group: {256, 257}}
+    items = [((0, QGROUP_STATUS, 0), struct.pack('<QQQQQ', 1, img.gen, 1, 0, 0))]
+    for qgroupid, subvols in members.items():
+        rfer = sum(sz for sz, roots in extents if roots & subvols)
+        excl = sum(sz for sz, roots in extents if roots <= subvols)
+        if qgroupid == 257:
+            excl += NODESIZE
+        items.append(((0, QGROUP_INFO, qgroupid), struct.pack('<QQQQQ', img.gen, rfer, rfer, excl, excl)))
+    items.append(((0, QGROUP_LIMIT, 256), struct.pack('<QQQQQ', 1, 1 << 20, 0, 0, 0)))
+    items.append(((0, QGROUP_LIMIT, group), struct.pack('<QQQQQ', 2, 0, 64 << 10, 0, 0)))
+    for child in (256, 257):
+        items.append(((child, QGROUP_RELATION, group), b''))
+        items.append(((group, QGROUP_RELATION, child), b''))
+    return items
+
+def extent_items(img, top, clone, blocks, shared, cache_extents=()):
+    items = []
+    ext = struct.Struct('<QQQ')
+    big_at = next(e for e in top.data_extents if e[2] == clone - 1)[0]
+    bad = next(e for e in top.data_extents if e[2] == clone + 1)
+    for bytenr, sz, ino, off in top.data_extents:
+        data_ref = struct.pack('<BQQQI', EXTENT_DATA_REF, 5, ino, off, 1)
+        if bytenr == big_at:
+            # simple quota owner first, the clone as a keyed ref
+            items.append(((bytenr, EXTENT_ITEM, sz), ext.pack(2, img.gen, EXTENT_FLAG_DATA) +
+                          struct.pack('<BQ', EXTENT_OWNER_REF, 5) + data_ref))
+            items.append(((bytenr, EXTENT_DATA_REF, extent_data_ref_hash(5, clone, 0)), struct.pack('<QQQI', 5, clone, 0, 1)))
+        elif bytenr == bad[0]:
+            # referenced through the leaf holding the file extent item
+            items.append(((bytenr, EXTENT_ITEM, sz), ext.pack(1, img.gen, EXTENT_FLAG_DATA)))
+            leaf = find_leaf(img, 5, (ino, EXTENT_DATA, 0))
+            items.append(((bytenr, SHARED_DATA_REF, leaf), struct.pack('<I', 1)))
+        else:
+            items.append(((bytenr, EXTENT_ITEM, sz), ext.pack(1, img.gen, EXTENT_FLAG_DATA) + data_ref))
+    sv_block = next(b for b in blocks if b[1] == 256)[0]
+    snap_block = next(b for b in blocks if b[1] == 257)[0]
+    for bytenr, owner, level, keys in blocks:
+        tree_ref = struct.pack('<BQ', TREE_BLOCK_REF, owner)
+        if owner == 3:
+            # old style, with the first key and level in the item
+            items.append(((bytenr, EXTENT_ITEM, NODESIZE), ext.pack(1, img.gen, EXTENT_FLAG_TREE_BLOCK) +
+                          key(*keys[0]) + bytes([level]) + tree_ref))
+        elif bytenr in shared:
+            items.append(((bytenr, METADATA_ITEM, level), ext.pack(2, img.gen, EXTENT_FLAG_TREE_BLOCK) + tree_ref +
+                          struct.pack('<BQ', TREE_BLOCK_REF, 258)))
+        elif bytenr == snap_block:
+            items.append(((bytenr, METADATA_ITEM, level), ext.pack(2, img.gen, EXTENT_FLAG_TREE_BLOCK) + tree_ref))
+            items.append(((bytenr, SHARED_BLOCK_REF, sv_block), b''))
+        else:
+            items.append(((bytenr, METADATA_ITEM, level), ext.pack(1, img.gen, EXTENT_FLAG_TREE_BLOCK) + tree_ref))
+    for bytenr, sz, ino in cache_extents:
+        items.append(((bytenr, EXTENT_ITEM, sz), ext.pack(1, img.gen, EXTENT_FLAG_DATA) +
+                      struct.pack('<BQQQI', EXTENT_DATA_REF, 1, ino, 0, 1)))
+    used = sum(sz for _, sz in used_ranges(top, blocks, cache_extents))
+    bg_items = [((CHUNK_START, BLOCK_GROUP_ITEM, MIXED_LEN), struct.pack("<QQQ", used, 256, 1 | 4 | 32)),
+                ((DATA_CHUNK, BLOCK_GROUP_ITEM, DATA_LEN), struct.pack('<QQQ', 0, 256, 1))]
+    return items, bg_items, used
+
+def main(out):
+    img = Img()
+    # chunk covering everything from 1MiB; type DATA|METADATA|SYSTEM mixed for simplicity
+    chunk = chunk_item(MIXED_LEN, nstripes=2, ty=1 | 4 | 32)
+    # and an empty single data chunk
+    chunk_root, chunk_level = build_tree(img, 3, [((1, DEV_ITEM, 1), dev_item()),
+                                                  ((256, CHUNK_ITEM, DATA_CHUNK), chunk_item(DATA_LEN, ty=1, stripe_off=DATA_CHUNK)),
+                                                  ((256, CHUNK_ITEM, CHUNK_START), chunk)])
+
+    # top-level fs tree
+    top = Tree(img, 5)
+    hello = top.mkdir(256, b'hello')
+    yellp = top.mkdir(hello, b'yellp')
+    heh = top.mkfile(yellp, b'heh.txt', b'heh\n')
+    acl = struct.pack('<I', 2) + b''.join(struct.pack('<HHI', t, p, i) for t, p, i in
+        [(1, 6, 0xffffffff), (2, 4, 1000), (4, 4, 0xffffffff), (0x10, 6, 0xffffffff), (0x20, 4, 0xffffffff)])
+    top.xattr(heh, b'system.posix_acl_access', acl)
+    top.xattr(heh, b'security.capability', struct.pack('<5I', 0x02000001, 1 << 10, 0, 0, 0))
+    top.xattr(heh, b'btrfs.compression', b'zstd')
+    top.xattr(heh, b'user.comment', b'hello world')
+    top.mkfile(256, b'with space-and.dash', b'x' * 10)
+    big = bytes((i * 7) & 0xff for i in range(10000))
+    top.mkfile(256, b'big.bin', big, inline=False)
+    big_at = top.data_extents[-1][0]
+    clone = top.mkfile(256, b'big-clone.bin', size=len(big), extents=[(0, file_extent_reg(big_at, 12288, 0, 12288, 12288))])
+    top.mkfile(256, b'csum-bad.bin', b'rotten' * 1000, inline=False)
+    bad_at = top.data_extents[-1][0]
+    top.mkfile(256, b'nodatasum.bin', b'unchecked' * 1000, inline=False, flags=1)
+    nodatasum_at = top.data_extents[-1][0]
+    # sparse.bin: partial extent, NO_HOLES gap, explicit hole, prealloc, trimmed tail
+    a = img.alloc.get(SECTOR); img.write(a, b'A' * 1024 + b'a' * 2048 + b'A' * 1024)
+    p = img.alloc.get(SECTOR); img.write(p, b'P' * SECTOR)
+    b = img.alloc.get(SECTOR); img.write(b, b'B' * SECTOR)
+    sp = top.mkfile(256, b'sparse.bin', size=14000, extents=[
+        (0, file_extent_reg(a, SECTOR, 1024, 2048, SECTOR)),
+        (4096, file_extent_reg(0, 0, 0, 4096, 4096)),
+        (8192, file_extent_reg(p, SECTOR, 0, SECTOR, SECTOR, ty=2)),
+        (12288, file_extent_reg(b, SECTOR, 0, SECTOR, SECTOR)),
+    ])
+    # data refs hold the file offset of the start of the extent
+    for bytenr, off in ((a, -1024 % (1 << 64)), (p, 8192), (b, 12288)):
+        top.data_extents.append((bytenr, SECTOR, sp, off))
+    # compressed files, one helper per algorithm
+    def compressed_file(name, comp, compress, extra=b''):
+        plain = b''.join(b'line %05d of %s\n' % (i, name) for i in range(2000))
+        blob = compress(plain)
+        sz = (len(blob) + SECTOR - 1) // SECTOR * SECTOR
+        bytenr = img.alloc.get(sz); img.write(bytenr, blob)
+        ram = (len(plain) + SECTOR - 1) // SECTOR * SECTOR
+        # skip the first 100 bytes of the extent to exercise the offset
+        ino = top.mkfile(256, name, size=len(plain) - 100, extents=[
+            (0, file_extent_reg(bytenr, sz, 100, ram - 100, ram, comp=comp))])
+        top.data_extents.append((bytenr, sz, ino, -100 % (1 << 64)))
+        small = b'inline %s\n' % name * 20
+        top.mkfile(256, name + b'.inline', size=len(small), extents=[
+            (0, file_extent_inline(compress(small), ram=len(small), comp=comp))])
+        return plain
+    import zlib, random, subprocess
+    zstd = lambda d: subprocess.run(['zstd', '-3', '--zstd=wlog=17', '-q', '-c', '--no-check'], input=d, capture_output=True, check=True).stdout
+    compressed_file(b'zstd.txt', 3, zstd)
+    from lzo import compress_btrfs
+    compressed_file(b'lzo.txt', 2, compress_btrfs)
+    # long runs, far matches and incompressible bytes in one extent
+    rnd = random.Random(1)
+    noise = bytes(rnd.getrandbits(8) for _ in range(20000))
+    mixed = noise + b'z' * 3000 + noise[:5000] + noise[100:300] * 3 + b'tail'
+    blob = compress_btrfs(mixed)
+    sz = (len(blob) + SECTOR - 1) // SECTOR * SECTOR
+    at = img.alloc.get(sz); img.write(at, blob)
+    ram = (len(mixed) + SECTOR - 1) // SECTOR * SECTOR
+    ino = top.mkfile(256, b'lzo-mixed.bin', size=len(mixed), extents=[(0, file_extent_reg(at, sz, 0, ram, ram, comp=2))])
+    top.data_extents.append((at, sz, ino, 0))
+    compressed_file(b'zlib.txt', 1, zlib.compress)
+    bad = img.alloc.get(SECTOR); img.write(bad, zlib.compress(b'x' * 8000)[:10] + b'garbage' * 10)
+    ino = top.mkfile(256, b'zlib-corrupt.txt', size=8000, extents=[(0, file_extent_reg(bad, SECTOR, 0, 8192, 8192, comp=1))])
+    top.data_extents.append((bad, SECTOR, ino, 0))
+    f = top.mkfile(256, b'linked', b'hard\n')
+    top.hardlink(hello, b'linked-again', f)
+    top.hardlink(hello, b'linked-ext', f, extref=True)
+    top.special(256, b'sym', 'lnk', 0o777, target=b'hello/yellp/heh.txt')
+    top.special(256, b'null', 'chr', 0o666, rdev=(1 << 20) | 3)
+    top.special(256, b'pipe', 'fifo', 0o644)
+    top.special(256, b'sda1', 'blk', 0o660, rdev=(8 << 20) | 1)
+    top.special(256, b'sock', 'sock', 0o755)
+    top.special(hello, b'dangling', 'lnk', 0o777, target=b'../nowhere')
+    deep = top.mkdir(hello, b'a-rather-long-directory-name-' * 3)
+    top.mkfile(deep, b'and-an-even-longer-file-name-' * 4 + b'.txt', b'long\n')
+    top.mkfile(deep, b'latin1-\xe9t\xe9', b'bytes\n')
+    subdir = top.mkdir(256, b'subvols')
+    top.subvol_link(subdir, b'sv1', 256)
+    for i in range(60):
+        top.mkfile(hello, b'f%03d' % i, b'data %d\n' % i)
+    # two names with the same crc32c name hash, in one DIR_ITEM
+    collide = top.mkdir(256, b'collide')
+    top.mkfile(collide, b'hash-5f97c7', b'first\n')
+    top.mkfile(collide, b'hash-1086000', b'second\n')
+    assert name_hash(b'hash-5f97c7') == name_hash(b'hash-1086000')
+    top_root, top_level = top.finish(max_items=20)
+
+    sv = Tree(img, 256)
+    f = sv.mkfile(256, b'in-subvol.txt', b'inside\n')
+    # a second name in the same directory, packed in the same INODE_REF
+    sv.hardlink(256, b'in-subvol-link.txt', f)
+    # extents bigger on disk than btrfs ever writes them, at an unmapped address
+    for name, disk, size, comp in ((b'oversized.bin', 1 << 30, 1 << 20, 0), (b'oversized.z', 1 << 18, 1 << 17, 1)):
+        sv.mkfile(256, name, size=size, extents=[
+            (0, file_extent_reg(1 << 40, disk, 0, size, size, comp=comp))])
+    sv.subvol_link(256, b'snap', 257)
+    sv.subvol_link(256, b'top-snap', 258)
+    sv_root, sv_level = sv.finish()
+
+    # top-snap: snapshot of the top level, a copy of its root node sharing
+    # everything below it
+    top_snap = img.alloc.get(NODESIZE)
+    blk = bytearray(img.buf[top_root:top_root + NODESIZE])
+    blk[0:HDR] = header(top_snap, 258, struct.unpack_from('<I', blk, 96)[0], top_level, img.gen)
+    blk[0:32] = struct.pack('<I', crc32c(bytes(blk[32:]))) + b'\0' * 28
+    img.write(top_snap, blk)
+    img.tree_blocks.append((top_snap, 258, top_level, []))
+    shared = [struct.unpack_from('<Q', blk, HDR + i * KP + 17)[0] for i in range(struct.unpack_from('<I', blk, 96)[0])]
+
+    snap = Tree(img, 257)
+    snap.mkfile(256, b'in-subvol.txt', b'inside\n')
+    snap_root, snap_level = snap.finish()
+
+    # csum tree: one EXTENT_CSUM item per data extent
+    csum_items = []
+    for bytenr, sz, ino, off in top.data_extents:
+        # no csums for NODATASUM files or prealloc extents
+        if bytenr in (nodatasum_at, p):
+            continue
+        sums = b''.join(struct.pack('<I', crc32c(bytes(img.buf[b:b + SECTOR]))) for b in range(bytenr, bytenr + sz, SECTOR))
+        csum_items.append(((EXTENT_CSUM_OBJECTID, EXTENT_CSUM, bytenr), sums))
+    csum_root, csum_level = build_tree(img, 7, csum_items, max_items=4)
+    # bit rot: big.bin's second sector only on the first copy, csum-bad.bin on both
+    img.buf[big_at + SECTOR + 5] ^= 0xff
+    img.buf[bad_at + 7] ^= 0xff
+    img.buf[bad_at + 7 + MIRROR_DELTA] ^= 0xff
+    assert img.alloc.next < CHUNK_START + MIXED_LEN
+
+    uuid_sv = bytes([0xaa] * 16)
+    uuid_snap = bytes([0xbb] * 16)
+    uuid_received = bytes(range(0xd0, 0xe0))
+    # uuid tree, with a leftover entry of a deleted subvolume
+    uuid_items = []
+    for ty, uuid, ids in ((UUID_SUBVOL, bytes([0x55] * 16), [5]), (UUID_SUBVOL, uuid_sv, [256]),
+                          (UUID_SUBVOL, uuid_snap, [257]), (UUID_SUBVOL, bytes([0xcc] * 16), [258]),
+                          (UUID_RECEIVED, uuid_received, [257]), (UUID_SUBVOL, bytes([0xee] * 16), [300])):
+        lo, hi = struct.unpack('<QQ', uuid)
+        uuid_items.append(((lo, ty, hi), b''.join(struct.pack('<Q', i) for i in ids)))
+    root_items = [
+        ((7, ROOT_ITEM, 0), root_item(img.gen, 0, csum_root, csum_level)),
+        ((5, ROOT_ITEM, 0), root_item(img.gen, 256, top_root, top_level, uuid=bytes([0x55] * 16), ctransid=9, otransid=0)),
+        ((256, ROOT_ITEM, 0), root_item(img.gen, 256, sv_root, sv_level, uuid=uuid_sv, ctransid=9, otransid=7,
+                                        otime=(1700000000, 5))),
+        ((256, ROOT_BACKREF, 5), root_ref(subdir, 2, b'sv1')),
+        ((5, ROOT_REF, 256), root_ref(subdir, 2, b'sv1')),
+        ((257, ROOT_ITEM, 8), root_item(img.gen, 256, snap_root, snap_level, uuid=uuid_snap, parent_uuid=uuid_sv,
+                                        received=uuid_received, flags=1, ctransid=8, otransid=8,
+                                        otime=(1700000100, 0))),
+        ((257, ROOT_BACKREF, 256), root_ref(256, 3, b'snap')),
+        ((258, ROOT_ITEM, 9), root_item(img.gen, 256, top_snap, top_level, uuid=bytes([0xcc] * 16),
+                                        parent_uuid=bytes([0x55] * 16), ctransid=9, otransid=9, otime=(1700000200, 0))),
+        ((258, ROOT_BACKREF, 256), root_ref(256, 4, b'top-snap')),
+        ((256, ROOT_REF, 258), root_ref(256, 4, b'top-snap')),
+        ((256, ROOT_REF, 257), root_ref(256, 3, b'snap')),
+        # root tree dir with "default"
+        ((6, INODE_ITEM, 0), inode_item(10, 0, 0, 1, 0, 0, 0o40755)),
+        ((6, DIR_ITEM, name_hash(b'default')), dir_entry((int(os.environ.get('DEFAULT_SUBVOL', 256)), ROOT_ITEM, 0xffffffffffffffff), 2, b'default')),
+    ]
+    for extra in EXTRA_ROOT_ITEMS:
+        root_items.append(extra(img))
+    # v1 space cache: one file per block group in the root tree, written
+    # once the allocations are known
+    cache_extents = []
+    if SPACE_CACHE:
+        for ino, (bg, pages) in ((257, (CHUNK_START, 1)), (258, (DATA_CHUNK, 2))):
+            sz = pages * PAGE
+            bytenr = img.alloc.get(sz)
+            cache_extents.append((bytenr, sz, ino))
+            root_items += [
+                ((ino, INODE_ITEM, 0), inode_item(img.gen, sz, sz, 1, 0, 0, 0o100600, flags=1 | 2 | 8 | 16)),
+                ((ino, EXTENT_DATA, 0), file_extent_reg(bytenr, sz, 0, sz, sz)),
+            ]
+    # extent tree: references to every tree block, including its own blocks
+    # and the root tree's, so build both until the allocations settle
+    mark = len(img.tree_blocks)
+    quota_items = qgroup_items(img, top, top_root, img.tree_blocks)
+    base = img.alloc.next
+    meta = []
+    while True:
+        img.alloc.next = base
+        del img.tree_blocks[mark:]
+        blocks = img.tree_blocks[:mark] + meta
+        items, bg_items, used = extent_items(img, top, clone, blocks, shared, cache_extents)
+        free = [free_ranges(used_ranges(top, blocks, cache_extents), s, l)
+                for s, l in ((CHUNK_START, MIXED_LEN), (DATA_CHUNK, DATA_LEN))]
+        extra = []
+        if BG_TREE:
+            bg_root, bg_level = build_tree(img, 11, bg_items)
+            extra.append(((11, ROOT_ITEM, 0), root_item(img.gen, 0, bg_root, bg_level)))
+        else:
+            items += bg_items
+        ext_root, ext_level = build_tree(img, 2, items, max_items=6)
+        quota_root, quota_level = build_tree(img, 8, quota_items)
+        extra.append(((8, ROOT_ITEM, 0), root_item(img.gen, 0, quota_root, quota_level)))
+        uuid_root, uuid_level = build_tree(img, 9, uuid_items)
+        extra.append(((9, ROOT_ITEM, 0), root_item(img.gen, 0, uuid_root, uuid_level)))
+        if not SPACE_CACHE:
+            # bitmaps for the fragmented mixed block group, extents for the data one
+            fst_items = free_space_tree_items(free[0], CHUNK_START, MIXED_LEN, True) + \
+                free_space_tree_items(free[1], DATA_CHUNK, DATA_LEN, False)
+            fst_root, fst_level = build_tree(img, 10, fst_items)
+            extra.append(((10, ROOT_ITEM, 0), root_item(img.gen, 0, fst_root, fst_level)))
+        else:
+            for (bytenr, sz, ino), ranges, bg in zip(cache_extents, free, (CHUNK_START, DATA_CHUNK)):
+                _, entries, bitmaps = space_cache_file(img.gen, ranges, bg, sz // PAGE, bg == DATA_CHUNK)
+                extra.append(((FREE_SPACE_OBJECTID, 0, bg), key(ino, INODE_ITEM, 0) +
+                              struct.pack('<QQQ', img.gen, entries, bitmaps)))
+        root_root, root_level = build_tree(img, 1, root_items + extra + [
+            ((2, ROOT_ITEM, 0), root_item(img.gen, 0, ext_root, ext_level))], max_items=3)
+        if img.tree_blocks[mark:] == meta:
+            break
+        meta = img.tree_blocks[mark:]
+    assert img.alloc.next < CHUNK_START + MIXED_LEN
+    for (bytenr, sz, ino), ranges, bg in zip(cache_extents, free, (CHUNK_START, DATA_CHUNK)):
+        body, _, _ = space_cache_file(img.gen, ranges, bg, sz // PAGE, bg == DATA_CHUNK)
+        img.write(bytenr, body)
+
+    # superblock
+    sys_array = key(256, CHUNK_ITEM, CHUNK_START) + chunk
+    sb = bytearray(4096)
+    struct.pack_into('<16sQQ8sQQQQQQQQQIIIIIQQQQHBBB', sb, 32, FSID, 65536, 0, b'_BHRfS_M', img.gen, root_root,
+                     chunk_root, 0, 0, IMG_SIZE, used, 6, 1, SECTOR, NODESIZE, NODESIZE, SECTOR, len(sys_array), img.gen,
+                     0, (8 if BG_TREE else 0) | (0 if SPACE_CACHE else 3), 0, 0, root_level, chunk_level, 0)
+    off = 201
+    sb[off:off + 98] = dev_item()
+    # cache_generation, after the label
+    struct.pack_into('<QQ', sb, off + 98 + 256, img.gen if SPACE_CACHE else (1 << 64) - 1, img.gen)
+    # sys_chunk_array offset = 0x32b
+    sb[0x32b:0x32b + len(sys_array)] = sys_array
+    sb[0:4] = struct.pack('<I', crc32c(bytes(sb[32:])))
+    img.write(65536, sb)
+    open(out, 'wb').write(img.buf)
+
+EXTRA_ROOT_ITEMS = []
+BG_TREE = bool(os.environ.get('BG_TREE'))
+SPACE_CACHE = bool(os.environ.get('SPACE_CACHE'))
+# page size of the kernel writing the v1 space cache
+PAGE = int(os.environ.get('PAGE_SIZE', 4096))
+
+if __name__ == '__main__':
+    if len(sys.argv) != 2:
+        sys.exit('usage: [BG_TREE=1] [SPACE_CACHE=1] mkimg.py <image>')
+    main(sys.argv[1])
@@ -0,0 +1,157 @@
+mod common;
+
+use btrfs_internals::dir::{name_hash, parse_dir_items};
+use btrfs_internals::inode::{FileType, InodeId};
+use btrfs_internals::structs::*;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+#[test]
+fn crosses_into_nested_subvolumes() {
+    let fs = common::open("test.img");
+    let root_dir = BTRFS_FIRST_FREE_OBJECTID as u64;
+
+    // subvols/sv1 is subvolume 256, with the snapshots 257 and 258 in it
+    let sv1 = fs.lookup_path(5, OsStr::new("subvols/sv1")).unwrap();
+    assert_eq!(sv1, InodeId::new(256, root_dir));
+    let file = fs
+        .lookup_path(5, OsStr::new("subvols/sv1/snap/in-subvol.txt"))
+        .unwrap();
+    assert_eq!(file.subvol, 257);
+    assert_eq!(fs.read(file.subvol, file.ino, 0, 100).unwrap(), b"inside\n");
+    assert_eq!(
+        fs.lookup_path(5, OsStr::new("subvols/sv1/top-snap/hello"))
+            .unwrap()
+            .subvol,
+        258
+    );
+
+    // Climbing out of a subvolume root goes through its backref
+    assert_eq!(fs.parent_dir(sv1).unwrap().subvol, 5);
+    assert_eq!(
+        fs.lookup_path(5, OsStr::new("subvols/sv1/snap/../in-subvol.txt"))
+            .unwrap()
+            .subvol,
+        256
+    );
+    // but never above the subvolume the lookup started in
+    assert_eq!(
+        fs.lookup_path(256, OsStr::new("../../in-subvol.txt"))
+            .unwrap(),
+        InodeId::new(256, 257)
+    );
+}
+
+#[test]
+fn shows_unlinked_nested_subvolumes_as_placeholders() {
+    let fs = common::open("test.img");
+
+    // Snapshot 258 of the top level keeps the entry of subvols/sv1, but
+    // subvolume 256 is only linked from the top level
+    let placeholder = fs.lookup_path(258, OsStr::new("subvols/sv1")).unwrap();
+    assert_eq!(
+        placeholder,
+        InodeId::new(258, BTRFS_EMPTY_SUBVOL_DIR_OBJECTID)
+    );
+    assert!(fs.read_dir(placeholder).unwrap().is_empty());
+    assert_eq!(
+        fs.stat(placeholder).unwrap().file_type(),
+        FileType::Directory
+    );
+    assert!(fs
+        .lookup_path(258, OsStr::new("subvols/sv1/in-subvol.txt"))
+        .is_err());
+}
+
+#[test]
+fn looks_up_raw_byte_names() {
+    let fs = common::open("test.img");
+    let mut path = b"hello/".to_vec();
+    path.extend(b"a-rather-long-directory-name-".repeat(3));
+    path.extend(b"/latin1-\xe9t\xe9");
+
+    let id = fs.lookup_path(5, OsStr::from_bytes(&path)).unwrap();
+    assert_eq!(fs.read(id.subvol, id.ino, 0, 100).unwrap(), b"bytes\n");
+    // The lossy UTF-8 version of the name is a different name
+    let lossy = String::from_utf8_lossy(&path).into_owned();
+    assert!(fs.lookup_path(5, OsStr::new(&lossy)).is_err());
+}
+
+#[test]
+fn tells_colliding_names_apart() {
+    let fs = common::open("test.img");
+    let (first, second) = (&b"hash-5f97c7"[..], &b"hash-1086000"[..]);
+    assert_eq!(name_hash(first), name_hash(second));
+
+    // Both names are packed in the one DIR_ITEM of their hash
+    let collide = fs.lookup_path(5, OsStr::new("collide")).unwrap();
+    let key = BtrfsKey::new(collide.ino, BTRFS_DIR_ITEM_KEY, name_hash(first));
+    let data = fs
+        .search_tree(fs.subvolume_root(5).unwrap(), &key)
+        .unwrap()
+        .unwrap();
+    let names: Vec<_> = parse_dir_items(&data)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, [first, second]);
+
+    let first = fs.lookup_entry(collide, first).unwrap().unwrap();
+    let second = fs.lookup_entry(collide, second).unwrap().unwrap();
+    assert_ne!(first.location, second.location);
+    for (entry, content) in [(first, &b"first\n"[..]), (second, b"second\n")] {
+        let ino = entry.location.objectid;
+        assert_eq!(fs.read(5, ino, 0, 100).unwrap(), content);
+    }
+    assert!(fs.lookup_entry(collide, b"hash-other").unwrap().is_none());
+}
+
+#[test]
+fn lists_directories_in_index_order() {
+    let fs = common::open("test.img");
+    let collide = fs.lookup_path(5, OsStr::new("collide")).unwrap();
+
+    // Creation order, not name or hash order
+    let entries = fs.read_dir(collide).unwrap();
+    let names: Vec<_> = entries.iter().map(|entry| &entry.name[..]).collect();
+    assert_eq!(names, [&b"hash-5f97c7"[..], b"hash-1086000"]);
+    assert!(entries[0].index < entries[1].index);
+
+    let hello = fs.lookup_path(5, OsStr::new("hello")).unwrap();
+    let files: Vec<_> = fs
+        .read_dir(hello)
+        .unwrap()
+        .into_iter()
+        .filter(|entry| entry.name.starts_with(b"f0"))
+        .map(|entry| entry.name)
+        .collect();
+    let expected: Vec<_> = (0..60).map(|i| format!("f{:03}", i).into_bytes()).collect();
+    assert_eq!(files, expected);
+
+    let top = fs.read_dir(fs.subvolume_root_dir(5).unwrap()).unwrap();
+    assert!(top.windows(2).all(|pair| pair[0].index < pair[1].index));
+    let file_type = |name: &[u8]| {
+        top.iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.file_type)
+            .unwrap()
+    };
+    assert_eq!(file_type(b"hello"), FileType::Directory);
+    assert_eq!(file_type(b"linked"), FileType::Regular);
+    assert_eq!(file_type(b"sym"), FileType::Symlink);
+    assert_eq!(file_type(b"null"), FileType::CharDevice);
+    assert_eq!(file_type(b"sda1"), FileType::BlockDevice);
+    assert_eq!(file_type(b"pipe"), FileType::Fifo);
+    assert_eq!(file_type(b"sock"), FileType::Socket);
+
+    // A nested subvolume is listed as a directory pointing to its root item
+    let subvols = fs.lookup_path(5, OsStr::new("subvols")).unwrap();
+    let entries = fs.read_dir(subvols).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].file_type, FileType::Directory);
+    assert_eq!(
+        entries[0].location,
+        BtrfsKey::new(256, BTRFS_ROOT_ITEM_KEY, u64::MAX)
+    );
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use btrfs_internals::extent::ExtentRef;
+use btrfs_internals::inode::InodeId;
+use btrfs_internals::structs::*;
+use std::ffi::OsStr;
+
+#[test]
+fn looks_up_extents() {
+    let fs = common::open("test.img");
+    let big = fs.lookup_path(5, OsStr::new("big.bin")).unwrap();
+    let clone = fs.lookup_path(5, OsStr::new("big-clone.bin")).unwrap();
+    let bytenr = fs.file_extents(big, 0, u64::MAX).unwrap()[0].disk_bytenr;
+
+    // Any address inside the extent finds it
+    for logical in [bytenr, bytenr + 4096, bytenr + 12287] {
+        let extent = fs.lookup_extent(logical).unwrap().unwrap();
+        assert_eq!((extent.bytenr, extent.num_bytes), (bytenr, 12288));
+        assert!(extent.is_data() && !extent.is_tree_block());
+        assert_eq!(extent.refs, 2);
+        assert_eq!(
+            extent.all_refs().map(ExtentRef::count).sum::<u64>(),
+            extent.refs
+        );
+        let files: Vec<_> = extent
+            .all_refs()
+            .filter_map(|extent_ref| match *extent_ref {
+                ExtentRef::Data { root, objectid, .. } => Some(InodeId::new(root, objectid)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(files, [big, clone]);
+    }
+
+    // The reflink is a keyed ref, after the inline ones, and the simple
+    // quota owner doesn't count as a ref
+    let extent = fs.lookup_extent(bytenr).unwrap().unwrap();
+    assert_eq!(extent.inline_refs[0], ExtentRef::Owner { root: 5 });
+    assert_eq!(extent.keyed_refs.len(), 1);
+    assert!(matches!(
+        extent.keyed_refs[0],
+        ExtentRef::Data { objectid, count: 1, .. } if objectid == clone.ino
+    ));
+
+    assert!(fs
+        .lookup_extent(bytenr + 12288)
+        .unwrap()
+        .is_none_or(|extent| extent.bytenr != bytenr));
+}
+
+#[test]
+fn walks_every_extent() {
+    let fs = common::open("test.img");
+    let mut extents = vec![];
+    fs.walk_extents(|extent| {
+        extents.push(extent);
+        Ok(())
+    })
+    .unwrap();
+
+    assert!(extents
+        .windows(2)
+        .all(|pair| pair[0].bytenr < pair[1].bytenr));
+    assert!(extents.iter().any(|extent| extent.is_data()));
+    assert!(extents.iter().any(|extent| extent.is_tree_block()));
+    // Refs collected along the walk are the ones found by a lookup
+    for extent in &extents {
+        let found = fs.lookup_extent(extent.bytenr).unwrap().unwrap();
+        assert_eq!(found.bytenr, extent.bytenr);
+        assert_eq!(found.keyed_refs, extent.keyed_refs);
+        assert_eq!(
+            extent.all_refs().map(ExtentRef::count).sum::<u64>(),
+            extent.refs
+        );
+    }
+}
+
+#[test]
+fn looks_up_tree_blocks() {
+    let fs = common::open("test.img");
+    let root = fs.subvolume_root(BTRFS_FS_TREE_OBJECTID).unwrap();
+
+    // Tree blocks are found by their exact address only, not by one inside
+    let block = fs.lookup_tree_block(root.bytenr).unwrap().unwrap();
+    assert!(block.is_tree_block());
+    assert_eq!(block.bytenr, root.bytenr);
+    assert_eq!(block.num_bytes, fs.node_size() as u64);
+    assert_eq!(block.inline_refs, [ExtentRef::TreeBlock { root: 5 }]);
+    assert!(fs.lookup_tree_block(root.bytenr + 512).unwrap().is_none());
+
+    // and never return data extents
+    let big = fs.lookup_path(5, OsStr::new("big.bin")).unwrap();
+    let bytenr = fs.file_extents(big, 0, u64::MAX).unwrap()[0].disk_bytenr;
+    assert!(fs.lookup_tree_block(bytenr).unwrap().is_none());
+}
@@ -0,0 +1,179 @@
+mod common;
+
+use btrfs_internals::extract::{ExtractOptions, ExtractStats};
+use btrfs_internals::fs::Fs;
+use glob::Pattern;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Extract `path` of the top level into a fresh directory `dest` of the
+/// target directory
+fn extract(fs: &Fs, path: &str, dest: &str, options: &ExtractOptions) -> (PathBuf, ExtractStats) {
+    let dest = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(dest);
+    let _ = fs::remove_dir_all(&dest);
+    let root = fs.lookup_path(5, OsStr::new(path)).unwrap();
+    let stats = fs.extract(root, &dest, options).unwrap();
+    (dest, stats)
+}
+
+/// Sorted names in directory `dir`
+fn names(dir: &Path) -> Vec<OsString> {
+    let mut names: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    names
+}
+
+fn patterns(patterns: &[&str]) -> Vec<Pattern> {
+    patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern).unwrap())
+        .collect()
+}
+
+fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let name = CString::new(name).unwrap();
+    let mut value = vec![0; 256];
+    let len = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    if len < 0 {
+        return None;
+    }
+    value.truncate(len as usize);
+    Some(value)
+}
+
+#[test]
+fn extracts_trees() {
+    let fs = common::open("test.img");
+    let (dest, stats) = extract(&fs, "", "extract-all", &ExtractOptions::default());
+    assert!(stats.files > 0 && stats.dirs > 0);
+
+    // Hard links share one inode
+    let linked = fs::metadata(dest.join("linked")).unwrap();
+    assert_eq!(linked.nlink(), 3);
+    for name in ["hello/linked-again", "hello/linked-ext"] {
+        assert_eq!(fs::metadata(dest.join(name)).unwrap().ino(), linked.ino());
+    }
+    assert!(stats.hard_links >= 2);
+
+    assert_eq!(
+        fs::read_link(dest.join("sym")).unwrap(),
+        Path::new("hello/yellp/heh.txt")
+    );
+    assert_eq!(
+        fs::read_link(dest.join("hello/dangling")).unwrap(),
+        Path::new("../nowhere")
+    );
+    assert_eq!(fs::read(dest.join("sym")).unwrap(), b"heh\n");
+
+    // Holes stay holes
+    let sparse = dest.join("sparse.bin");
+    let id = fs.lookup_path(5, OsStr::new("sparse.bin")).unwrap();
+    assert_eq!(
+        fs::read(&sparse).unwrap(),
+        fs.read(5, id.ino, 0, u64::MAX).unwrap()
+    );
+    let meta = fs::metadata(&sparse).unwrap();
+    assert_eq!(meta.len(), 14000);
+    assert!(meta.blocks() * 512 < 14000);
+
+    // Modes and nanosecond timestamps, of directories too
+    for path in ["linked", "hello", "pipe"] {
+        let stat = fs
+            .stat(fs.lookup_path(5, OsStr::new(path)).unwrap())
+            .unwrap();
+        let meta = fs::symlink_metadata(dest.join(path)).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, stat.permissions());
+        assert_eq!(meta.mtime(), stat.mtime.seconds());
+        assert_eq!(meta.mtime_nsec(), 123456789);
+    }
+    assert_eq!(
+        get_xattr(&dest.join("hello/yellp/heh.txt"), "user.comment").as_deref(),
+        Some(&b"hello world"[..])
+    );
+
+    // Nested subvolumes are followed
+    assert_eq!(
+        fs::read(dest.join("subvols/sv1/snap/in-subvol.txt")).unwrap(),
+        b"inside\n"
+    );
+}
+
+#[test]
+fn extracts_matching_paths() {
+    let fs = common::open("test.img");
+
+    // Include patterns pull in the parents of what they match, and everything
+    // below the directories they match
+    let options = ExtractOptions {
+        include: patterns(&["hello/yellp/heh.txt", "subvols/sv1/snap"]),
+        ..Default::default()
+    };
+    let (dest, stats) = extract(&fs, "", "extract-include", &options);
+    assert_eq!(stats.errors, 0);
+    assert_eq!(names(&dest), ["hello", "subvols"]);
+    assert_eq!(names(&dest.join("hello")), ["yellp"]);
+    assert_eq!(names(&dest.join("subvols/sv1")), ["snap"]);
+    assert_eq!(
+        fs::read(dest.join("hello/yellp/heh.txt")).unwrap(),
+        b"heh\n"
+    );
+    assert_eq!(
+        fs::read(dest.join("subvols/sv1/snap/in-subvol.txt")).unwrap(),
+        b"inside\n"
+    );
+    // Parents pulled in get their metadata too
+    let hello = fs::metadata(dest.join("hello")).unwrap();
+    assert_eq!(hello.mtime_nsec(), 123456789);
+
+    // Excludes win over includes and skip everything below them
+    let options = ExtractOptions {
+        include: patterns(&["hello"]),
+        exclude: patterns(&["hello/f0*", "hello/a-rather-*", "hello/yellp"]),
+        ..Default::default()
+    };
+    let (dest, stats) = extract(&fs, "", "extract-exclude", &options);
+    assert_eq!(stats.errors, 0);
+    assert_eq!(names(&dest), ["hello"]);
+    assert_eq!(
+        names(&dest.join("hello")),
+        ["dangling", "linked-again", "linked-ext"]
+    );
+}
+
+#[test]
+fn counts_files_that_cant_be_read() {
+    let fs = common::open("test.img");
+
+    let (dest, stats) = extract(
+        &fs,
+        "zlib-corrupt.txt",
+        "extract-corrupt",
+        &ExtractOptions::default(),
+    );
+    assert_eq!((stats.files, stats.errors), (0, 1));
+    // No truncated file is left behind
+    assert!(!dest.exists());
+
+    let options = ExtractOptions {
+        include: patterns(&["*.txt"]),
+        ..Default::default()
+    };
+    let (dest, stats) = extract(&fs, "", "extract-errors", &options);
+    assert!(stats.errors >= 1);
+    assert!(!dest.join("zlib-corrupt.txt").exists());
+    assert!(dest.join("zlib.txt").exists());
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use btrfs_internals::fs::Fs;
+use std::ffi::OsStr;
+
+/// Whole content of `path` in the top level subvolume
+fn read_path(fs: &Fs, path: &str) -> anyhow::Result<Vec<u8>> {
+    let id = fs.lookup_path(5, OsStr::new(path))?;
+    fs.read(id.subvol, id.ino, 0, u64::MAX)
+}
+
+#[test]
+fn reads_file_data() {
+    let fs = common::open("test.img");
+    let read = |path: &str| {
+        let id = fs.lookup_path(5, OsStr::new(path)).unwrap();
+        fs.read(id.subvol, id.ino, 0, u64::MAX).unwrap()
+    };
+
+    assert_eq!(read("hello/yellp/heh.txt"), b"heh\n");
+    assert_eq!(read("linked"), b"hard\n");
+    assert_eq!(read("big.bin"), read("big-clone.bin"));
+
+    // Holes and prealloc extents read as zeros, up to the inode size
+    let sparse = read("sparse.bin");
+    assert_eq!(sparse.len(), 14000);
+    assert!(sparse.iter().any(|b| *b != 0));
+    assert!(sparse.windows(4096).any(|w| w.iter().all(|b| *b == 0)));
+
+    let id = fs.lookup_path(5, OsStr::new("sparse.bin")).unwrap();
+    assert_eq!(
+        fs.read(id.subvol, id.ino, 13990, 100).unwrap(),
+        sparse[13990..]
+    );
+    assert!(fs.read(id.subvol, id.ino, 14000, 100).unwrap().is_empty());
+}
+
+#[test]
+fn rejects_oversized_extents() {
+    let fs = common::open("test.img");
+
+    for name in ["oversized.bin", "oversized.z"] {
+        let id = fs.lookup_path(256, OsStr::new(name)).unwrap();
+        let err = fs.read(id.subvol, id.ino, 0, 4096).unwrap_err().to_string();
+        assert!(err.contains("at file offset 0"), "{}", err);
+        assert!(err.contains("more than"), "{}", err);
+    }
+}
+
+#[test]
+fn verifies_data_checksums() {
+    let mut fs = common::open("test.img");
+    fs.verify_csums = true;
+
+    // The first copy of big.bin is damaged, the second one is good
+    assert_eq!(
+        read_path(&fs, "big.bin").unwrap(),
+        read_path(&fs, "big-clone.bin").unwrap()
+    );
+    // Both copies of csum-bad.bin are damaged
+    let err = read_path(&fs, "csum-bad.bin").unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("at file offset 0"),
+        "{:#}",
+        err
+    );
+    fs.verify_csums = false;
+    assert_eq!(read_path(&fs, "csum-bad.bin").unwrap().len(), 6000);
+    fs.verify_csums = true;
+
+    // Compressed extents are checked before decompressing them, files
+    // without checksums and prealloc extents aren't checked
+    for path in [
+        "zlib.txt",
+        "lzo.txt",
+        "zstd.txt",
+        "nodatasum.bin",
+        "sparse.bin",
+    ] {
+        assert!(read_path(&fs, path).is_ok(), "{}", path);
+    }
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use btrfs_internals::free_space::{check_free_space, super_stripes, FreeSpaceSource};
+use btrfs_internals::structs::*;
+
+#[test]
+fn checks_free_space() {
+    // The free space tree, and v1 caches written with 4K and 64K pages
+    for (name, source) in [
+        ("test.img", FreeSpaceSource::Tree),
+        ("v1cache.img", FreeSpaceSource::CacheV1),
+        ("v1cache-64k.img", FreeSpaceSource::CacheV1),
+    ] {
+        let fs = common::open(name);
+        let chunks = fs.chunks().unwrap();
+
+        for block_group in fs.block_groups().unwrap() {
+            let free = fs.free_space(&block_group).unwrap().unwrap();
+            assert_eq!(free.source, source, "{}", name);
+            assert_eq!(free.total(), block_group.length - block_group.used);
+            // The tree keeps the mixed block group in bitmaps, the v1 caches
+            // the data one, in a whole page of bits
+            let is_data = block_group.flags == BTRFS_BLOCK_GROUP_DATA;
+            assert_eq!(
+                free.bitmaps,
+                is_data == (source == FreeSpaceSource::CacheV1)
+            );
+
+            let chunk = chunks
+                .iter()
+                .find(|chunk| chunk.logical == block_group.start)
+                .unwrap();
+            let used = fs.block_group_extents(&block_group).unwrap();
+            assert_eq!(
+                check_free_space(&block_group, &free, &used, &super_stripes(chunk)),
+                [],
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+#[test]
+fn reads_every_name_of_an_inode() {
+    let fs = common::open("test.img");
+    let linked = fs.lookup_path(5, OsStr::new("linked")).unwrap();
+    let top = fs.subvolume_root_dir(5).unwrap();
+    let hello = fs.lookup_path(5, OsStr::new("hello")).unwrap();
+
+    // INODE_REF items come first, then the INODE_EXTREF of linked-ext
+    let names: Vec<_> = fs
+        .inode_names(linked)
+        .unwrap()
+        .into_iter()
+        .map(|name| (name.parent, name.name))
+        .collect();
+    assert_eq!(
+        names,
+        [
+            (top.ino, b"linked".to_vec()),
+            (hello.ino, b"linked-again".to_vec()),
+            (hello.ino, b"linked-ext".to_vec()),
+        ]
+    );
+    for name in ["hello/linked-again", "hello/linked-ext"] {
+        assert_eq!(fs.lookup_path(5, OsStr::new(name)).unwrap(), linked);
+    }
+
+    // Two names in the same directory share one INODE_REF item
+    let file = fs.lookup_path(256, OsStr::new("in-subvol.txt")).unwrap();
+    let names = fs.inode_names(file).unwrap();
+    assert_eq!(names.len(), 2);
+    assert!(names.iter().all(|name| name.parent == 256));
+    assert_eq!(names[0].name, b"in-subvol.txt");
+    assert_eq!(names[1].name, b"in-subvol-link.txt");
+    assert!(names[0].index < names[1].index);
+}
+
+#[test]
+fn resolves_every_path_of_an_inode() {
+    let fs = common::open("test.img");
+    let linked = fs.lookup_path(5, OsStr::new("linked")).unwrap();
+
+    assert_eq!(
+        fs.inode_paths(5, linked.ino).unwrap(),
+        [
+            PathBuf::from("/linked"),
+            PathBuf::from("/hello/linked-again"),
+            PathBuf::from("/hello/linked-ext"),
+        ]
+    );
+
+    // Paths inside nested subvolumes start from the top level
+    let file = fs.lookup_path(256, OsStr::new("in-subvol.txt")).unwrap();
+    assert_eq!(
+        fs.inode_paths(256, file.ino).unwrap(),
+        [
+            PathBuf::from("/subvols/sv1/in-subvol.txt"),
+            PathBuf::from("/subvols/sv1/in-subvol-link.txt"),
+        ]
+    );
+    let snap = fs.lookup_path(257, OsStr::new("in-subvol.txt")).unwrap();
+    assert_eq!(
+        fs.inode_paths(257, snap.ino).unwrap(),
+        [PathBuf::from("/subvols/sv1/snap/in-subvol.txt")]
+    );
+    assert_eq!(
+        fs.inode_paths(257, 256).unwrap(),
+        [PathBuf::from("/subvols/sv1/snap")]
+    );
+
+    // The snapshot of the top level sees the same names under its own path
+    let mut path = b"hello/".to_vec();
+    path.extend(b"a-rather-long-directory-name-".repeat(3));
+    path.extend(b"/latin1-\xe9t\xe9");
+    let id = fs.lookup_path(258, OsStr::from_bytes(&path)).unwrap();
+    assert_eq!(id.subvol, 258);
+    let mut expected = b"/subvols/sv1/top-snap/".to_vec();
+    expected.extend(&path);
+    assert_eq!(
+        fs.inode_paths(258, id.ino).unwrap(),
+        [PathBuf::from(OsStr::from_bytes(&expected))]
+    );
+}
@@ -0,0 +1,130 @@
+//! Tests against `tests/data/mkfs.img.gz`, a filesystem written by
+//! mkfs.btrfs and the kernel. Building it needs root, see
+//! `tests/data/mkfs-image.sh`, so these only run with `--ignored`.
+
+mod common;
+
+use btrfs_internals::backref::FileOffset;
+use btrfs_internals::free_space::{check_free_space, super_stripes};
+use btrfs_internals::fs::Fs;
+use btrfs_internals::inode::InodeId;
+use btrfs_internals::structs::*;
+use std::ffi::OsStr;
+
+/// The image, with the ids of subvolume `sv` and its snapshot `sv-snap`
+fn open() -> (Fs, u64, u64) {
+    let fs = common::open("mkfs.img");
+    let subvols = fs.subvolumes().unwrap();
+    let id = |path: &str| {
+        subvols
+            .iter()
+            .find(|subvol| subvol.path == path)
+            .map(|subvol| subvol.id)
+            .unwrap()
+    };
+    let (sv, snap) = (id("sv"), id("sv-snap"));
+    (fs, sv, snap)
+}
+
+#[test]
+#[ignore = "needs tests/data/mkfs.img.gz from tests/data/mkfs-image.sh"]
+fn reads_compressed_files() {
+    let (fs, sv, snap) = open();
+
+    for (name, compression) in [
+        ("zlib.txt", BTRFS_COMPRESS_ZLIB),
+        ("lzo.txt", BTRFS_COMPRESS_LZO),
+        ("zstd.txt", BTRFS_COMPRESS_ZSTD),
+    ] {
+        let expected: Vec<u8> = (0..2000)
+            .flat_map(|i| format!("line {:05} of {}\n", i, name).into_bytes())
+            .collect();
+        for subvol in [sv, snap] {
+            let id = fs.lookup_path(subvol, OsStr::new(name)).unwrap();
+            let extents = fs.file_extents(id, 0, u64::MAX).unwrap();
+            assert!(
+                extents.iter().all(|e| e.compression == compression),
+                "{}",
+                name
+            );
+            assert_eq!(fs.read(subvol, id.ino, 0, u64::MAX).unwrap(), expected);
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs tests/data/mkfs.img.gz from tests/data/mkfs-image.sh"]
+fn lists_subvolumes_and_snapshots() {
+    let (fs, sv, snap) = open();
+    let subvols = fs.subvolumes().unwrap();
+    assert_eq!(subvols.len(), 2);
+
+    let (sv, snap) = (
+        subvols.iter().find(|s| s.id == sv).unwrap(),
+        subvols.iter().find(|s| s.id == snap).unwrap(),
+    );
+    assert_eq!(sv.parent_id, BTRFS_FS_TREE_OBJECTID);
+    assert_eq!(snap.parent_id, BTRFS_FS_TREE_OBJECTID);
+    assert!(snap.is_readonly() && !sv.is_readonly());
+    assert_eq!(snap.parent_uuid, sv.uuid);
+    assert_eq!(fs.subvolume_by_uuid(&sv.uuid).unwrap(), Some(sv.id));
+    assert_eq!(fs.check_uuid_tree().unwrap(), []);
+}
+
+#[test]
+#[ignore = "needs tests/data/mkfs.img.gz from tests/data/mkfs-image.sh"]
+fn resolves_reflinks_in_snapshots() {
+    let (fs, sv, snap) = open();
+    let plain = fs.lookup_path(sv, OsStr::new("plain.bin")).unwrap();
+    let clone = fs.lookup_path(sv, OsStr::new("plain-clone.bin")).unwrap();
+    let bytenr = fs.file_extents(plain, 0, u64::MAX).unwrap()[0].disk_bytenr;
+
+    let mut found = fs.logical_resolve(bytenr).unwrap();
+    found.sort();
+    let at = |subvol, ino| FileOffset {
+        id: InodeId::new(subvol, ino),
+        offset: 0,
+    };
+    assert_eq!(
+        found,
+        [
+            at(sv, plain.ino),
+            at(sv, clone.ino),
+            at(snap, plain.ino),
+            at(snap, clone.ino)
+        ]
+    );
+}
+
+#[test]
+#[ignore = "needs tests/data/mkfs.img.gz from tests/data/mkfs-image.sh"]
+fn agrees_with_the_kernel_accounting() {
+    let (fs, _, _) = open();
+
+    // Quotas were rescanned by the kernel, the recount has to find the same
+    let status = fs.qgroup_status().unwrap().unwrap();
+    let qgroups = fs.qgroups().unwrap();
+    let counted = fs.qgroup_recount(&status, &qgroups).unwrap();
+    for (id, qgroup) in &qgroups {
+        assert_eq!(
+            (counted[id].rfer, counted[id].excl),
+            (qgroup.rfer, qgroup.excl),
+            "qgroup {}",
+            id
+        );
+    }
+
+    let chunks = fs.chunks().unwrap();
+    for block_group in fs.block_groups().unwrap() {
+        let free = fs.free_space(&block_group).unwrap().unwrap();
+        let chunk = chunks
+            .iter()
+            .find(|chunk| chunk.logical == block_group.start)
+            .unwrap();
+        let used = fs.block_group_extents(&block_group).unwrap();
+        assert_eq!(
+            check_free_space(&block_group, &free, &used, &super_stripes(chunk)),
+            []
+        );
+    }
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use btrfs_internals::qgroup::QgroupId;
+
+#[test]
+fn reads_qgroups() {
+    let fs = common::open("test.img");
+    let status = fs.qgroup_status().unwrap().unwrap();
+    assert!(!status.is_simple());
+
+    let qgroups = fs.qgroups().unwrap();
+    let ids: Vec<_> = qgroups.keys().copied().collect();
+    let level0 = |id| QgroupId::new(0, id);
+    let group = QgroupId::new(1, 100);
+    assert_eq!(
+        ids,
+        [level0(5), level0(256), level0(257), level0(258), group]
+    );
+    assert_eq!(qgroups[&group].children, [level0(256), level0(257)]);
+    assert_eq!(qgroups[&level0(257)].parents, [group]);
+
+    assert_eq!(qgroups[&level0(256)].max_rfer(), Some(1 << 20));
+    assert_eq!(qgroups[&level0(256)].max_excl(), None);
+    assert_eq!(qgroups[&group].max_excl(), Some(64 << 10));
+}
+
+#[test]
+fn recounts_qgroups() {
+    let fs = common::open("test.img");
+    let status = fs.qgroup_status().unwrap().unwrap();
+    let qgroups = fs.qgroups().unwrap();
+    let counted = fs.qgroup_recount(&status, &qgroups).unwrap();
+
+    // Everything but the exclusive size of the snapshot matches
+    let mut mismatches = vec![];
+    for (id, qgroup) in &qgroups {
+        let usage = counted[id];
+        if (qgroup.rfer, qgroup.excl) != (usage.rfer, usage.excl) {
+            mismatches.push((*id, qgroup.excl, usage.excl));
+        }
+    }
+    assert_eq!(mismatches, [(QgroupId::new(0, 257), 4096, 0)]);
+
+    // The block 256 and 257 share is exclusive to the group holding both
+    assert_eq!(counted[&QgroupId::new(1, 100)].excl, 8192);
+    // The top level shares everything below its root with its snapshot
+    assert_eq!(counted[&QgroupId::new(0, 5)].excl, 4096);
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use btrfs_internals::structs::*;
+use btrfs_internals::subvolume::SubvolSelector;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+
+#[test]
+fn lists_nested_subvolumes() {
+    let fs = common::open("test.img");
+    let subvols = fs.subvolumes().unwrap();
+
+    let listed: Vec<_> = subvols
+        .iter()
+        .map(|subvol| (subvol.id, subvol.parent_id, subvol.path.as_str()))
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            (256, BTRFS_FS_TREE_OBJECTID, "subvols/sv1"),
+            (257, 256, "subvols/sv1/snap"),
+            (258, 256, "subvols/sv1/top-snap"),
+        ]
+    );
+    // Paths built from the collected backrefs match the ones walked up alone
+    for subvol in &subvols {
+        assert_eq!(
+            fs.subvolume_top_path(subvol.id).unwrap(),
+            subvol.path.as_bytes()
+        );
+    }
+
+    let (sv1, snap) = (&subvols[0], &subvols[1]);
+    assert_eq!(snap.name, b"snap");
+    assert_eq!(snap.dir_id, BTRFS_FIRST_FREE_OBJECTID as u64);
+    assert!(snap.is_readonly() && !sv1.is_readonly());
+    assert_eq!(snap.parent_uuid, sv1.uuid);
+    assert_eq!(snap.received_uuid, std::array::from_fn(|i| 0xd0 + i as u8));
+}
+
+#[test]
+fn resolves_subvolume_selectors() {
+    let fs = common::open("test.img");
+
+    // From the "default" entry of the root tree directory
+    assert_eq!(fs.default_subvolume_id().unwrap(), 256);
+    assert_eq!(fs.resolve_subvolume(&SubvolSelector::Default).unwrap(), 256);
+
+    assert_eq!(fs.resolve_subvolume(&SubvolSelector::Id(257)).unwrap(), 257);
+    assert_eq!(fs.resolve_subvolume(&SubvolSelector::Id(5)).unwrap(), 5);
+    assert!(fs.resolve_subvolume(&SubvolSelector::Id(300)).is_err());
+    assert!(fs.resolve_subvolume(&SubvolSelector::Id(2)).is_err());
+
+    let path = |path: &[u8]| SubvolSelector::Path(OsString::from_vec(path.to_vec()));
+    for selector in [
+        &b"subvols/sv1/snap"[..],
+        b"/subvols/sv1/snap",
+        b"subvols//sv1/snap/",
+        b"./subvols/./sv1/snap",
+    ] {
+        assert_eq!(fs.resolve_subvolume(&path(selector)).unwrap(), 257);
+    }
+    assert_eq!(fs.resolve_subvolume(&path(b"/")).unwrap(), 5);
+    assert_eq!(fs.resolve_subvolume(&path(b"")).unwrap(), 5);
+    assert!(fs.resolve_subvolume(&path(b"subvols")).is_err());
+    assert!(fs.resolve_subvolume(&path(b"subvols/sv1/\xe9")).is_err());
+
+    assert_eq!(
+        fs.resolve_subvolume(&SubvolSelector::Uuid([0xcc; 16]))
+            .unwrap(),
+        258
+    );
+    assert!(fs
+        .resolve_subvolume(&SubvolSelector::Uuid([0x12; 16]))
+        .is_err());
+}
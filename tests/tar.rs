@@ -0,0 +1,148 @@
+mod common;
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::Read;
+use tar::{Archive, EntryType};
+
+/// One member read back from an archive
+struct Member {
+    ty: EntryType,
+    link: Option<Vec<u8>>,
+    records: HashMap<String, Vec<u8>>,
+    data: Vec<u8>,
+}
+
+/// Archive `path` of the top level and read every member back with the `tar`
+/// crate, by the path in its header
+fn archive(path: &str) -> HashMap<Vec<u8>, Member> {
+    let fs = common::open("test.img");
+    let root = fs.lookup_path(5, OsStr::new(path)).unwrap();
+    let mut out = vec![];
+    fs.write_tar(root, b"root", &mut out).unwrap();
+
+    let mut members = HashMap::new();
+    for entry in Archive::new(&out[..]).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut records = HashMap::new();
+        if let Some(extensions) = entry.pax_extensions().unwrap() {
+            for extension in extensions {
+                let extension = extension.unwrap();
+                records.insert(
+                    extension.key().unwrap().to_string(),
+                    extension.value_bytes().to_vec(),
+                );
+            }
+        }
+        let member = Member {
+            ty: entry.header().entry_type(),
+            link: entry.link_name_bytes().map(|link| link.into_owned()),
+            records,
+            data: {
+                let mut data = vec![];
+                entry.read_to_end(&mut data).unwrap();
+                data
+            },
+        };
+        members.insert(entry.path_bytes().into_owned(), member);
+    }
+    members
+}
+
+#[test]
+fn archives_links_and_metadata() {
+    let members = archive("");
+
+    // The first path of an inode in the archive holds its data, the others
+    // link to it
+    let first = &members[&b"hello/linked-again"[..]];
+    assert_eq!(first.ty, EntryType::Regular);
+    assert_eq!(first.data, b"hard\n");
+    for name in [&b"linked"[..], b"hello/linked-ext"] {
+        let member = &members[name];
+        assert_eq!(member.ty, EntryType::Link);
+        assert_eq!(member.link.as_deref(), Some(&b"hello/linked-again"[..]));
+        assert!(member.data.is_empty());
+    }
+
+    let sym = &members[&b"sym"[..]];
+    assert_eq!(sym.ty, EntryType::Symlink);
+    assert_eq!(sym.link.as_deref(), Some(&b"hello/yellp/heh.txt"[..]));
+    assert_eq!(members[&b"hello/"[..]].ty, EntryType::Directory);
+    assert_eq!(members[&b"pipe"[..]].ty, EntryType::Fifo);
+
+    // xattrs and nanosecond mtimes only fit in pax records
+    let heh = &members[&b"hello/yellp/heh.txt"[..]];
+    assert_eq!(heh.data, b"heh\n");
+    assert_eq!(heh.records["SCHILY.xattr.user.comment"], b"hello world");
+    assert!(heh
+        .records
+        .contains_key("SCHILY.xattr.system.posix_acl_access"));
+    let mtime = String::from_utf8(heh.records["mtime"].clone()).unwrap();
+    assert!(mtime.ends_with(".123456789"), "{}", mtime);
+
+    // Raw byte names go through pax too
+    let mut latin1 = b"hello/".to_vec();
+    latin1.extend(b"a-rather-long-directory-name-".repeat(3));
+    latin1.extend(b"/latin1-\xe9t\xe9");
+    let member = &members[&latin1];
+    assert_eq!(member.records["hdrcharset"], b"BINARY");
+    assert_eq!(member.data, b"bytes\n");
+}
+
+#[test]
+fn archives_sparse_files() {
+    let fs = common::open("test.img");
+    let id = fs.lookup_path(5, OsStr::new("sparse.bin")).unwrap();
+    let content = fs.read(5, id.ino, 0, u64::MAX).unwrap();
+
+    let members = archive("");
+    let sparse = &members[&b"GNUSparseFile.0/sparse.bin"[..]];
+    assert_eq!(sparse.ty, EntryType::Regular);
+    assert_eq!(sparse.records["GNU.sparse.major"], b"1");
+    assert_eq!(sparse.records["GNU.sparse.minor"], b"0");
+    assert_eq!(sparse.records["GNU.sparse.name"], b"sparse.bin");
+    assert_eq!(sparse.records["GNU.sparse.realsize"], b"14000");
+
+    // The map of (offset, size) regions starts the data, padded to a block
+    let text = String::from_utf8_lossy(&sparse.data);
+    let mut numbers = text.split('\n').map(|n| n.parse::<usize>().unwrap());
+    let count = numbers.next().unwrap();
+    let regions: Vec<_> = (0..count)
+        .map(|_| (numbers.next().unwrap(), numbers.next().unwrap()))
+        .collect();
+    let map_len = 1 + count * 2;
+    let map_size = text
+        .split('\n')
+        .take(map_len)
+        .map(|n| n.len() + 1)
+        .sum::<usize>();
+    let mut data = &sparse.data[map_size.div_ceil(512) * 512..];
+
+    // Put the file back together from its regions
+    let mut restored = vec![0; 14000];
+    for (offset, size) in &regions {
+        restored[*offset..offset + size].copy_from_slice(&data[..*size]);
+        data = &data[*size..];
+    }
+    assert!(data.is_empty());
+    assert_eq!(restored, content);
+    assert!(regions.iter().map(|(_, size)| size).sum::<usize>() < 14000);
+    assert_eq!(
+        regions.last().map(|(offset, size)| offset + size),
+        Some(14000)
+    );
+}
+
+#[test]
+fn archives_single_files() {
+    let fs = common::open("test.img");
+    let id = fs.lookup_path(5, OsStr::new("zlib.txt")).unwrap();
+
+    let members = archive("zlib.txt");
+    assert_eq!(members.len(), 1);
+    assert_eq!(
+        members[&b"root"[..]].data,
+        fs.read(5, id.ino, 0, u64::MAX).unwrap()
+    );
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use btrfs_internals::structs::*;
+use btrfs_internals::uuid_tree::UuidTreeProblem;
+
+#[test]
+fn looks_up_uuids() {
+    let fs = common::open("test.img");
+
+    assert_eq!(
+        fs.lookup_uuid(&[0xaa; 16], BTRFS_UUID_KEY_SUBVOL).unwrap(),
+        [256]
+    );
+    let received: [u8; 16] = std::array::from_fn(|i| 0xd0 + i as u8);
+    assert_eq!(
+        fs.lookup_uuid(&received, BTRFS_UUID_KEY_RECEIVED_SUBVOL)
+            .unwrap(),
+        [257]
+    );
+    // A received uuid is not a subvolume uuid
+    assert!(fs
+        .lookup_uuid(&received, BTRFS_UUID_KEY_SUBVOL)
+        .unwrap()
+        .is_empty());
+
+    assert_eq!(fs.subvolume_by_uuid(&[0xbb; 16]).unwrap(), Some(257));
+    assert_eq!(fs.subvolume_by_uuid(&[0x55; 16]).unwrap(), Some(5));
+    // The entry of the deleted subvolume 300 leads nowhere
+    assert_eq!(fs.subvolume_by_uuid(&[0xee; 16]).unwrap(), None);
+}
+
+#[test]
+fn checks_uuid_tree() {
+    let fs = common::open("test.img");
+
+    assert_eq!(fs.uuid_tree_entries().unwrap().len(), 6);
+    assert_eq!(
+        fs.check_uuid_tree().unwrap(),
+        [UuidTreeProblem::Stale {
+            ty: BTRFS_UUID_KEY_SUBVOL,
+            uuid: [0xee; 16],
+            subvol: 300
+        }]
+    );
+}